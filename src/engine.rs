@@ -1,7 +1,29 @@
-use rayon::{ThreadPool, ThreadPoolBuilder};
-use std::sync::Arc;
+#[cfg(feature = "prometheus")]
+mod metrics;
 
-use crate::{net::Network, queue::NetworkQueue};
+use eyre::Context as _;
+use parking_lot::{Condvar, Mutex};
+use rayon::{ThreadPool, ThreadPoolBuilder, prelude::*};
+use std::{
+    any::Any,
+    collections::{BTreeMap, BTreeSet},
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    net::{
+        Network, NetworkError, RecvProgress, SequenceCheckpoint, SequencedNetwork, SizeHistogram,
+        TestNetwork,
+    },
+    queue::NetworkQueue,
+};
 
 pub const NUM_THREADS_NET: usize = 8;
 pub const NUM_THREADS_CPU: usize = 0;
@@ -9,34 +31,448 @@ pub const NUM_THREADS_CPU: usize = 0;
 #[derive(Debug)]
 pub struct MpcEngine<N: Network> {
     id: usize,
+    /// The number of parties, 1 if the engine was built without networks.
+    num_parties: usize,
     queue: Arc<NetworkQueue<N>>,
     net_pool: ThreadPool,
     cpu_pool: ThreadPool,
+    in_flight: Arc<InFlight>,
+    round: Arc<AtomicU64>,
+    length_schedule: Arc<[Vec<Option<usize>>]>,
 }
 
-impl<N: Network + Send + 'static> MpcEngine<N> {
-    pub fn new(id: usize, num_threads_net: usize, num_threads_cpu: usize, nets: Vec<N>) -> Self {
-        let net_pool = ThreadPoolBuilder::new()
-            .num_threads(num_threads_net)
+/// Counts the tasks spawned with `spawn_*` that did not finish yet.
+#[derive(Debug)]
+struct InFlight {
+    max: Option<usize>,
+    count: Mutex<usize>,
+    cvar: Condvar,
+    #[cfg(feature = "metrics")]
+    gauge: ::metrics::Gauge,
+}
+
+impl InFlight {
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    fn new(id: usize, max: Option<usize>) -> Self {
+        Self {
+            max,
+            count: Mutex::new(0),
+            cvar: Condvar::new(),
+            #[cfg(feature = "metrics")]
+            gauge: crate::metrics::in_flight_tasks(id),
+        }
+    }
+
+    /// Updates the metrics after the number of tasks in flight changed to `count`.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    fn changed(&self, count: usize) {
+        #[cfg(feature = "metrics")]
+        self.gauge.set(count as f64);
+    }
+
+    /// Waits until another task may be spawned and counts it as in flight until the permit is dropped.
+    fn acquire(self: &Arc<Self>) -> InFlightPermit {
+        let mut count = self.count.lock();
+        if let Some(max) = self.max {
+            while *count >= max {
+                self.cvar.wait(&mut count);
+            }
+        }
+        *count += 1;
+        self.changed(*count);
+        InFlightPermit(Arc::clone(self))
+    }
+
+    /// Like [`InFlight::acquire`], but returns `None` if the limit is still reached at `deadline`.
+    fn acquire_until(self: &Arc<Self>, deadline: Instant) -> Option<InFlightPermit> {
+        let mut count = self.count.lock();
+        if let Some(max) = self.max {
+            while *count >= max {
+                if Instant::now() >= deadline {
+                    return None;
+                }
+                self.cvar.wait_until(&mut count, deadline);
+            }
+        }
+        *count += 1;
+        self.changed(*count);
+        Some(InFlightPermit(Arc::clone(self)))
+    }
+
+    /// Waits until no task is in flight.
+    fn wait_idle(&self) {
+        let mut count = self.count.lock();
+        while *count > 0 {
+            self.cvar.wait(&mut count);
+        }
+    }
+
+    /// Like [`InFlight::acquire`], but returns `None` instead of waiting if the limit is reached.
+    fn try_acquire(self: &Arc<Self>) -> Option<InFlightPermit> {
+        let mut count = self.count.lock();
+        if self.max.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        self.changed(*count);
+        Some(InFlightPermit(Arc::clone(self)))
+    }
+}
+
+#[derive(Debug)]
+struct InFlightPermit(Arc<InFlight>);
+
+impl Drop for InFlightPermit {
+    fn drop(&mut self) {
+        let mut count = self.0.count.lock();
+        *count -= 1;
+        self.0.changed(*count);
+        drop(count);
+        // a spawn waiting for the limit and a shutdown may wait at the same time
+        self.0.cvar.notify_all();
+    }
+}
+
+/// The handler for panics that escape the tasks of the thread pools.
+type PanicHandler = Arc<dyn Fn(Box<dyn Any + Send>) + Send + Sync>;
+
+/// A builder for an [`MpcEngine`] that configures its thread pools.
+///
+/// The threads of the net pool are named `mpc-net-{i}` and the ones of the cpu pool `mpc-cpu-{i}`
+/// unless other prefixes are set. The net pool includes the thread that calls
+/// [`MpcEngineBuilder::build`], which keeps its name.
+pub struct MpcEngineBuilder<N> {
+    id: usize,
+    nets: Vec<N>,
+    num_threads_net: usize,
+    num_threads_cpu: usize,
+    net_thread_name: String,
+    cpu_thread_name: String,
+    panic_handler: Option<PanicHandler>,
+    stack_size: Option<usize>,
+}
+
+impl<N> std::fmt::Debug for MpcEngineBuilder<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MpcEngineBuilder")
+            .field("id", &self.id)
+            .field("num_nets", &self.nets.len())
+            .field("num_threads_net", &self.num_threads_net)
+            .field("num_threads_cpu", &self.num_threads_cpu)
+            .field("net_thread_name", &self.net_thread_name)
+            .field("cpu_thread_name", &self.cpu_thread_name)
+            .field("stack_size", &self.stack_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<N: Network + Send + 'static> MpcEngineBuilder<N> {
+    /// Create a builder for the party `id` with [`NUM_THREADS_NET`] and [`NUM_THREADS_CPU`]
+    /// threads, see [`MpcEngine::builder`].
+    pub fn new(id: usize, nets: Vec<N>) -> Self {
+        Self {
+            id,
+            nets,
+            num_threads_net: NUM_THREADS_NET,
+            num_threads_cpu: NUM_THREADS_CPU,
+            net_thread_name: "mpc-net".to_owned(),
+            cpu_thread_name: "mpc-cpu".to_owned(),
+            panic_handler: None,
+            stack_size: None,
+        }
+    }
+
+    /// Replace the networks the engine runs its network tasks on.
+    pub fn nets(mut self, nets: Vec<N>) -> Self {
+        self.nets = nets;
+        self
+    }
+
+    /// The number of threads of the net pool, including the thread that builds the engine.
+    pub fn num_threads_net(mut self, num_threads: usize) -> Self {
+        self.num_threads_net = num_threads;
+        self
+    }
+
+    /// The number of threads of the cpu pool, where `0` picks one per logical CPU.
+    pub fn num_threads_cpu(mut self, num_threads: usize) -> Self {
+        self.num_threads_cpu = num_threads;
+        self
+    }
+
+    /// Name the threads of the net pool `{prefix}-{i}`.
+    pub fn net_thread_name(mut self, prefix: impl Into<String>) -> Self {
+        self.net_thread_name = prefix.into();
+        self
+    }
+
+    /// Name the threads of the cpu pool `{prefix}-{i}`.
+    pub fn cpu_thread_name(mut self, prefix: impl Into<String>) -> Self {
+        self.cpu_thread_name = prefix.into();
+        self
+    }
+
+    /// Call `handler` with the payload of a panic that escapes a task of either pool, instead of
+    /// aborting the process.
+    ///
+    /// Panics of tasks spawned with `spawn_*` are caught and returned by their [`Handle`] and
+    /// panics of `join*` closures are propagated to the caller, so this only sees panics that
+    /// would otherwise get lost.
+    pub fn panic_handler(
+        mut self,
+        handler: impl Fn(Box<dyn Any + Send>) + Send + Sync + 'static,
+    ) -> Self {
+        self.panic_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// The stack size in bytes of the threads spawned for either pool.
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    /// Build the thread pools and the engine.
+    pub fn build(self) -> eyre::Result<MpcEngine<N>> {
+        let net_pool = self
+            .pool_builder(self.num_threads_net, self.net_thread_name.clone())
             .use_current_thread()
             .build()
-            .unwrap();
-        let cpu_pool = ThreadPoolBuilder::new()
-            .num_threads(num_threads_cpu)
+            .context("while building the net pool")?;
+        let cpu_pool = self
+            .pool_builder(self.num_threads_cpu, self.cpu_thread_name.clone())
             .build()
-            .unwrap();
-        Self {
-            id,
-            queue: Arc::new(NetworkQueue::new(nets)),
+            .context("while building the cpu pool")?;
+        let num_parties = self.nets.first().map_or(1, Network::num_parties);
+        let queue = NetworkQueue::new(self.nets);
+        #[cfg(feature = "metrics")]
+        let queue = queue.with_available_gauge(crate::metrics::networks_available(self.id));
+        Ok(MpcEngine {
+            id: self.id,
+            num_parties,
+            queue: Arc::new(queue),
             net_pool,
             cpu_pool,
+            in_flight: Arc::new(InFlight::new(self.id, None)),
+            round: Arc::default(),
+            length_schedule: Arc::new([]),
+        })
+    }
+
+    fn pool_builder(&self, num_threads: usize, name: String) -> ThreadPoolBuilder {
+        let mut builder = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(move |i| format!("{name}-{i}"));
+        if let Some(handler) = &self.panic_handler {
+            let handler = Arc::clone(handler);
+            builder = builder.panic_handler(move |payload| handler(payload));
         }
+        if let Some(stack_size) = self.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        builder
+    }
+}
+
+/// Nests `rayon::join`s so that all closures run in parallel, the first one on `$pool`.
+///
+/// The results are nested the same way, see `nested_tuple`.
+macro_rules! pool_join {
+    ($pool:expr, $f:expr, $g:expr) => {
+        $pool.join($f, $g)
+    };
+    ($pool:expr, $f:expr, $($rest:expr),+) => {
+        $pool.join($f, || nested_join!($($rest),+))
+    };
+}
+
+macro_rules! nested_join {
+    ($f:expr, $g:expr) => {
+        rayon::join($f, $g)
+    };
+    ($f:expr, $($rest:expr),+) => {
+        rayon::join($f, || nested_join!($($rest),+))
+    };
+}
+
+/// The pattern `(r0, (r1, (r2, r3)))` matching the result of `pool_join`.
+macro_rules! nested_tuple {
+    ($r:ident, $s:ident) => {
+        ($r, $s)
+    };
+    ($r:ident, $($rest:ident),+) => {
+        ($r, nested_tuple!($($rest),+))
+    };
+}
+
+/// Generates a `join*_net` method running every closure with its own network on the net pool.
+///
+/// The networks are popped in the order of the closures and pushed back in the same order.
+macro_rules! join_net {
+    ($name:ident, $(($f:ident, $r:ident, $net:ident)),+) => {
+        #[allow(clippy::too_many_arguments)]
+        pub fn $name<$($r: Send),+>(&self, $($f: impl FnOnce(&N) -> $r + Send),+) -> ($($r),+) {
+            $(let $f = self.in_round($f);)+
+            $(let $net = self.queue.pop();)+
+            let nested_tuple!($($f),+) = pool_join!(self.net_pool, $(|| $f(&$net.1)),+);
+            $(self.queue.push($net.0, $net.1);)+
+            ($($f),+)
+        }
+    };
+}
+
+/// Generates a `join*_cpu` method running every closure on the cpu pool.
+macro_rules! join_cpu {
+    ($name:ident, $(($f:ident, $r:ident)),+) => {
+        #[allow(clippy::too_many_arguments)]
+        pub fn $name<$($r: Send),+>(&self, $($f: impl FnOnce() -> $r + Send),+) -> ($($r),+) {
+            let nested_tuple!($($f),+) = pool_join!(self.cpu_pool, $($f),+);
+            ($($f),+)
+        }
+    };
+}
+
+impl<N: Network + Send + 'static> MpcEngine<N> {
+    pub fn new(id: usize, num_threads_net: usize, num_threads_cpu: usize, nets: Vec<N>) -> Self {
+        Self::builder(id, nets)
+            .num_threads_net(num_threads_net)
+            .num_threads_cpu(num_threads_cpu)
+            .build()
+            .expect("can build thread pools")
+    }
+
+    /// Start building an engine for the party `id` that runs its network tasks on `nets`.
+    pub fn builder(id: usize, nets: Vec<N>) -> MpcEngineBuilder<N> {
+        MpcEngineBuilder::new(id, nets)
+    }
+
+    /// Limit the number of tasks spawned with [`MpcEngine::spawn_net`] and [`MpcEngine::spawn_cpu`]
+    /// that did not finish yet to `max`.
+    ///
+    /// Once the limit is reached, further spawns block until a task finishes. This keeps a producer
+    /// that spawns faster than the pools can drain from piling up pending tasks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn with_max_in_flight(mut self, max: usize) -> Self {
+        assert!(max > 0, "max in flight must be at least 1");
+        self.in_flight = Arc::new(InFlight::new(self.id, Some(max)));
+        self
+    }
+
+    /// The number of tasks spawned with [`MpcEngine::spawn_net`] and [`MpcEngine::spawn_cpu`] that
+    /// did not finish yet.
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.count.lock()
+    }
+
+    /// Blocks until all spawned work finished, e.g., at a phase boundary or in a test harness.
+    ///
+    /// Waits until the tasks spawned with [`MpcEngine::spawn_net`] and [`MpcEngine::spawn_cpu`]
+    /// finished and all networks are back in the queue, including the ones of tasks abandoned by
+    /// a timeout. A network task only stops counting as in flight after it returned its network,
+    /// so a task that finishes during the wait can't be missed. Work spawned concurrently by
+    /// other threads may still run when this returns.
+    ///
+    /// This must not be called from a spawned task, which would wait for itself.
+    pub fn wait_idle(&self) {
+        self.in_flight.wait_idle();
+        self.queue.wait_idle();
+    }
+
+    /// Shuts the engine down once all outstanding work finished.
+    ///
+    /// Waits for the tasks spawned with [`MpcEngine::spawn_net`] and [`MpcEngine::spawn_cpu`],
+    /// whether their handles were joined or not, and for the tasks abandoned by a timeout to
+    /// return their networks. Then all networks are flushed and their connections to all peers
+    /// closed, so the peers receive everything that was sent. A peer that already closed its
+    /// connections is not an error, any other error is returned after all connections were
+    /// closed. Networks taken with [`MpcEngine::get_net`] are left alone.
+    ///
+    /// The threads of the pools exit once they are idle after this returns.
+    pub fn shutdown(self) -> eyre::Result<()> {
+        // the engine is consumed, so no new work can be spawned while waiting
+        self.in_flight.wait_idle();
+        let id = self.id;
+        self.queue.with_all(|nets| {
+            let mut res = Ok(());
+            for index in 0..nets.len() {
+                let net = nets.get(index).expect("must exist");
+                for peer in (0..net.num_parties()).filter(|&peer| peer != id) {
+                    match net.flush(peer).and_then(|_| net.close(peer)) {
+                        Ok(()) | Err(NetworkError::PeerClosed(_)) => {}
+                        Err(err) if res.is_ok() => {
+                            res = Err(eyre::Report::new(err).wrap_err(format!(
+                                "while closing network {index} to party {peer}"
+                            )));
+                        }
+                        Err(err) => {
+                            tracing::warn!("closing network {index} to party {peer} failed: {err}");
+                        }
+                    }
+                }
+            }
+            res
+        })
+    }
+
+    /// The number of networks of the engine, including the ones that are in use.
+    pub fn num_networks(&self) -> usize {
+        self.queue.num()
+    }
+
+    /// The number of networks that are currently not in use by a task.
+    ///
+    /// This is only a snapshot, e.g., to log the contention of the networks. A network taken with
+    /// [`MpcEngine::get_net`] counts neither as available nor towards [`MpcEngine::num_networks`].
+    pub fn available_networks(&self) -> usize {
+        self.queue.available()
+    }
+
+    /// Check the length of every message received with [`RoundCtx::recv`] against `schedule`.
+    ///
+    /// `schedule[round][from]` is the expected length of the messages from the party `from` in the
+    /// round [`MpcEngine::current_round`]. Entries that are `None` or missing, e.g., for rounds past
+    /// the end of the schedule, are not checked. A mismatch is returned as an error, which turns a
+    /// desync of a protocol with fixed message sizes into a failure in the round it happens.
+    pub fn with_length_schedule(mut self, schedule: Vec<Vec<Option<usize>>>) -> Self {
+        self.length_schedule = schedule.into();
+        self
     }
 
     pub fn id(&self) -> usize {
         self.id
     }
 
+    /// The number of parties, including this one.
+    pub fn num_parties(&self) -> usize {
+        self.num_parties
+    }
+
+    /// The current round number, starting at 0.
+    ///
+    /// The round is recorded in a `round` span around every closure that runs on a network, so the
+    /// trace events of the transports can be lined up across parties.
+    pub fn current_round(&self) -> u64 {
+        self.round.load(Ordering::Relaxed)
+    }
+
+    /// Increments the round number, see [`MpcEngine::current_round`].
+    pub fn advance_round(&self) {
+        self.round.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Wraps `f` to run in a span recording the current round.
+    fn in_round<F: FnOnce(&N) -> R, R>(&self, f: F) -> impl FnOnce(&N) -> R + use<F, R, N> {
+        let span = tracing::debug_span!("round", round = self.current_round());
+        move |net| span.in_scope(|| f(net))
+    }
+
+    /// Take the network with the highest index out of the engine until the guard is dropped.
+    ///
+    /// Waits until that network is not in use, the other networks keep serving spawned tasks.
+    /// Returns `None` if all networks were taken.
     pub fn get_net(&self) -> Option<NetworkGuard<N>> {
         let net = self.queue.remove()?;
         let queue = Arc::clone(&self.queue);
@@ -51,30 +487,157 @@ impl<N: Network + Send + 'static> MpcEngine<N> {
         &self,
         f: impl FnOnce(&N) -> T + Send + 'static,
     ) -> Handle<T> {
+        self.spawn_net_with_hint(None, f)
+    }
+
+    /// Like [`MpcEngine::spawn_net`], but runs `f` on the network at index `hint` if given.
+    ///
+    /// This lets the same logical task reuse the same connection, e.g., to benefit from a warmed
+    /// up TCP window. The hint is taken modulo the number of networks and does not advance the
+    /// round-robin order used by the other methods. If the hinted network is in use or poisoned,
+    /// this falls back to the next network in round-robin order.
+    ///
+    /// Whether the hinted network is in use depends on the local timing, so one party may fall back
+    /// while its peer runs on the hinted network, and the parties deadlock on different
+    /// connections. The hint is only safe if the hinted network of every party is guaranteed to
+    /// be free, e.g., because all tasks on it were joined before.
+    pub fn spawn_net_with_hint<T: Send + 'static>(
+        &self,
+        hint: Option<usize>,
+        f: impl FnOnce(&N) -> T + Send + 'static,
+    ) -> Handle<T> {
+        let permit = self.in_flight.acquire();
+        let (id, net) = match hint {
+            Some(index) => self
+                .queue
+                .try_pop_index(index)
+                .unwrap_or_else(|| self.queue.pop()),
+            None => self.queue.pop(),
+        };
+        self.spawn_on(permit, id, net, f)
+    }
+
+    /// Like [`MpcEngine::spawn_net`], but returns `None` instead of blocking if the next network
+    /// is in use or the limit set with [`MpcEngine::with_max_in_flight`] is reached.
+    ///
+    /// Only the network [`MpcEngine::spawn_net`] would use is considered, because all parties must
+    /// use the networks in the same order. The caller can do other work, e.g., on the cpu pool,
+    /// and try again later.
+    pub fn try_spawn_net<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&N) -> T + Send + 'static,
+    ) -> Option<Handle<T>> {
+        let permit = self.in_flight.try_acquire()?;
+        let (id, net) = self.queue.try_pop()?;
+        Some(self.spawn_on(permit, id, net, f))
+    }
+
+    /// Like [`MpcEngine::spawn_net`], but fails if no network became available within `timeout`.
+    ///
+    /// The timeout covers waiting for the next network in round-robin order and for the limit set
+    /// with [`MpcEngine::with_max_in_flight`], but not running `f`. The error names the networks
+    /// that are still in use, e.g., held by a hanging task or a [`NetworkGuard`].
+    pub fn spawn_net_timeout<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&N) -> T + Send + 'static,
+        timeout: Duration,
+    ) -> eyre::Result<Handle<T>> {
+        let deadline = Instant::now() + timeout;
+        let Some(permit) = self.in_flight.acquire_until(deadline) else {
+            eyre::bail!(
+                "no task finished within {timeout:?}, {} tasks are in flight",
+                self.in_flight()
+            );
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let Some((id, net)) = self.queue.pop_timeout(remaining) else {
+            eyre::bail!(
+                "no network became available within {timeout:?}, networks {:?} are in use",
+                self.queue.in_use()
+            );
+        };
+        Ok(self.spawn_on(permit, id, net, f))
+    }
+
+    /// Spawns `f` on the net pool with the network `net` popped from index `id`.
+    fn spawn_on<T: Send + 'static>(
+        &self,
+        permit: InFlightPermit,
+        id: usize,
+        net: N,
+        f: impl FnOnce(&N) -> T + Send + 'static,
+    ) -> Handle<T> {
+        let f = self.in_round(f);
+        let queue = Arc::clone(&self.queue);
+        let (tx, rx) = oneshot::channel();
+        self.net_pool.spawn(move || {
+            // a panic is returned by the handle, the network goes back to the queue either way
+            let res = std::panic::catch_unwind(AssertUnwindSafe(|| f(&net)));
+            // the handle may have been dropped, the result is discarded then
+            let _ = tx.send(res);
+            queue.push(id, net);
+            drop(permit);
+        });
+
+        Handle::new(rx)
+    }
+
+    /// Spawns `f` on the net pool and returns the index of the used network alongside the receiver.
+    ///
+    /// In contrast to [`MpcEngine::spawn_net`], the receiver may be dropped before `f` finishes.
+    /// A panic of `f` is caught and sent to the receiver, the network goes back to the queue
+    /// either way.
+    fn spawn_net_abandonable<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&N) -> T + Send + 'static,
+    ) -> (usize, oneshot::Receiver<std::thread::Result<T>>) {
+        let f = self.in_round(f);
         let (id, net) = self.queue.pop();
         let queue = Arc::clone(&self.queue);
         let (tx, rx) = oneshot::channel();
         self.net_pool.spawn(move || {
-            tx.send(f(&net)).unwrap();
+            let res = std::panic::catch_unwind(AssertUnwindSafe(|| f(&net)));
+            // the receiver is gone if the caller timed out, the result is discarded then
+            let _ = tx.send(res);
             queue.push(id, net);
         });
+        (id, rx)
+    }
 
-        Handle { sender: rx }
+    /// Waits for `rx` until `deadline`, poisoning the network `id` if the result did not arrive in time.
+    fn recv_or_poison<T>(
+        &self,
+        id: usize,
+        rx: oneshot::Receiver<std::thread::Result<T>>,
+        deadline: Instant,
+    ) -> Option<std::thread::Result<T>> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok(res) => Some(res),
+            Err(_) => {
+                tracing::warn!("network {id} did not finish in time, marking it as poisoned");
+                self.queue.poison(id);
+                None
+            }
+        }
     }
 
     pub fn spawn_cpu<T: Send + 'static>(
         &self,
         f: impl FnOnce() -> T + Send + 'static,
     ) -> Handle<T> {
+        let permit = self.in_flight.acquire();
         let (tx, rx) = oneshot::channel();
         self.cpu_pool.spawn(move || {
-            tx.send(f()).unwrap();
+            let _ = tx.send(std::panic::catch_unwind(AssertUnwindSafe(f)));
+            drop(permit);
         });
 
-        Handle { sender: rx }
+        Handle::new(rx)
     }
 
     pub fn install_net<T: Send>(&self, f: impl FnOnce(&N) -> T + Send) -> T {
+        let f = self.in_round(f);
         let (id, net) = self.queue.pop();
         self.net_pool.install(|| {
             let res = f(&net);
@@ -83,215 +646,783 @@ impl<N: Network + Send + 'static> MpcEngine<N> {
         })
     }
 
-    pub fn install_cpu<T: Send>(&self, f: impl FnOnce() -> T + Send) -> T {
-        self.cpu_pool.install(f)
+    /// Runs one protocol round `f` on the net pool with a network lent from the pool.
+    ///
+    /// The network is returned to the pool when the round ends, even if `f` panics, so a
+    /// failed round can't leak a connection. All parties must run their rounds in the same order,
+    /// as the network is picked in the same round-robin order as for [`MpcEngine::install_net`].
+    pub fn round<R: Send>(&self, f: impl FnOnce(&RoundCtx<N>) -> R + Send) -> R {
+        let (index, net) = self.queue.pop();
+        let round = self.current_round();
+        let ctx = RoundCtx {
+            index,
+            net: Some(net),
+            queue: &self.queue,
+            round,
+            length_schedule: &self.length_schedule,
+        };
+        let span = tracing::debug_span!("round", round);
+        self.net_pool.install(|| span.in_scope(|| f(&ctx)))
     }
 
-    pub fn join_net<R0: Send, R1: Send>(
+    /// Like [`MpcEngine::install_net`], but returns `None` if `f` did not complete within `timeout`.
+    ///
+    /// On timeout `f` keeps running on the net pool and its network is only returned to the queue
+    /// once `f` finishes. Because the abandoned closure may still consume or produce messages that
+    /// belong to a later round, the network is marked as poisoned, see
+    /// [`MpcEngine::poisoned_networks`]. Poisoned networks are skipped by all later operations
+    /// until [`MpcEngine::clear_poisoned`] is called, so the parties only stay in sync if they
+    /// poison the same networks. It is up to the protocol to decide whether the engine can still
+    /// be used afterwards.
+    ///
+    /// In contrast to [`MpcEngine::install_net`], `f` has to be `'static`, because it may outlive
+    /// this call. If `f` panics in time, the panic is resumed on the caller, a panic after the
+    /// timeout is discarded.
+    pub fn install_net_timeout<T: Send + 'static>(
         &self,
-        f0: impl FnOnce(&N) -> R0 + Send,
-        f1: impl FnOnce(&N) -> R1 + Send,
-    ) -> (R0, R1) {
-        let (id0, net0) = self.queue.pop();
-        let (id1, net1) = self.queue.pop();
-        let res = self.net_pool.join(|| f0(&net0), || f1(&net1));
-        self.queue.push(id0, net0);
-        self.queue.push(id1, net1);
-        res
+        f: impl FnOnce(&N) -> T + Send + 'static,
+        timeout: Duration,
+    ) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        let (id, rx) = self.spawn_net_abandonable(f);
+        self.recv_or_poison(id, rx, deadline).map(resume_panic)
     }
 
-    pub fn join3_net<R0: Send, R1: Send, R2: Send>(
+    /// Like [`MpcEngine::join_net`], but returns `None` if `f0` and `f1` did not both complete within `timeout`.
+    ///
+    /// See [`MpcEngine::install_net_timeout`] for the semantics on timeout and the `'static` bounds.
+    pub fn join_net_timeout<R0: Send + 'static, R1: Send + 'static>(
         &self,
-        f0: impl FnOnce(&N) -> R0 + Send,
-        f1: impl FnOnce(&N) -> R1 + Send,
-        f2: impl FnOnce(&N) -> R2 + Send,
-    ) -> (R0, R1, R2) {
-        let (id0, net0) = self.queue.pop();
-        let (id1, net1) = self.queue.pop();
-        let (id2, net2) = self.queue.pop();
-        let (r0, (r1, r2)) = self
-            .net_pool
-            .join(|| f0(&net0), || rayon::join(|| f1(&net1), || f2(&net2)));
-        self.queue.push(id0, net0);
-        self.queue.push(id1, net1);
-        self.queue.push(id2, net2);
-        (r0, r1, r2)
-    }
-
-    pub fn join4_net<R0: Send, R1: Send, R2: Send, R3: Send>(
-        &self,
-        f0: impl FnOnce(&N) -> R0 + Send,
-        f1: impl FnOnce(&N) -> R1 + Send,
-        f2: impl FnOnce(&N) -> R2 + Send,
-        f3: impl FnOnce(&N) -> R3 + Send,
-    ) -> (R0, R1, R2, R3) {
-        let (id0, net0) = self.queue.pop();
-        let (id1, net1) = self.queue.pop();
-        let (id2, net2) = self.queue.pop();
-        let (id3, net3) = self.queue.pop();
-        let (r0, (r1, (r2, r3))) = self.net_pool.join(
-            || f0(&net0),
-            || rayon::join(|| f1(&net1), || rayon::join(|| f2(&net2), || f3(&net3))),
-        );
-        self.queue.push(id0, net0);
-        self.queue.push(id1, net1);
-        self.queue.push(id2, net2);
-        self.queue.push(id3, net3);
-        (r0, r1, r2, r3)
-    }
-
-    pub fn join5_net<R0: Send, R1: Send, R2: Send, R3: Send, R4: Send>(
-        &self,
-        f0: impl FnOnce(&N) -> R0 + Send,
-        f1: impl FnOnce(&N) -> R1 + Send,
-        f2: impl FnOnce(&N) -> R2 + Send,
-        f3: impl FnOnce(&N) -> R3 + Send,
-        f4: impl FnOnce(&N) -> R4 + Send,
-    ) -> (R0, R1, R2, R3, R4) {
-        let (id0, net0) = self.queue.pop();
-        let (id1, net1) = self.queue.pop();
-        let (id2, net2) = self.queue.pop();
-        let (id3, net3) = self.queue.pop();
-        let (id4, net4) = self.queue.pop();
-        let (r0, (r1, (r2, (r3, r4)))) = self.net_pool.join(
-            || f0(&net0),
-            || {
-                rayon::join(
-                    || f1(&net1),
-                    || rayon::join(|| f2(&net2), || rayon::join(|| f3(&net3), || f4(&net4))),
-                )
-            },
-        );
-        self.queue.push(id0, net0);
-        self.queue.push(id1, net1);
-        self.queue.push(id2, net2);
-        self.queue.push(id3, net3);
-        self.queue.push(id4, net4);
-        (r0, r1, r2, r3, r4)
-    }
-
-    pub fn join8_net<
-        R0: Send,
-        R1: Send,
-        R2: Send,
-        R3: Send,
-        R4: Send,
-        R5: Send,
-        R6: Send,
-        R7: Send,
-    >(
-        &self,
-        f0: impl FnOnce(&N) -> R0 + Send,
-        f1: impl FnOnce(&N) -> R1 + Send,
-        f2: impl FnOnce(&N) -> R2 + Send,
-        f3: impl FnOnce(&N) -> R3 + Send,
-        f4: impl FnOnce(&N) -> R4 + Send,
-        f5: impl FnOnce(&N) -> R5 + Send,
-        f6: impl FnOnce(&N) -> R6 + Send,
-        f7: impl FnOnce(&N) -> R7 + Send,
-    ) -> (R0, R1, R2, R3, R4, R5, R6, R7) {
-        let (id0, net0) = self.queue.pop();
-        let (id1, net1) = self.queue.pop();
-        let (id2, net2) = self.queue.pop();
-        let (id3, net3) = self.queue.pop();
-        let (id4, net4) = self.queue.pop();
-        let (id5, net5) = self.queue.pop();
-        let (id6, net6) = self.queue.pop();
-        let (id7, net7) = self.queue.pop();
-        let (r0, (r1, (r2, (r3, (r4, (r5, (r6, r7))))))) = self.net_pool.join(
-            || f0(&net0),
-            || {
-                rayon::join(
-                    || f1(&net1),
-                    || {
-                        rayon::join(
-                            || f2(&net2),
-                            || {
-                                rayon::join(
-                                    || f3(&net3),
-                                    || {
-                                        rayon::join(
-                                            || f4(&net4),
-                                            || {
-                                                rayon::join(
-                                                    || f5(&net5),
-                                                    || rayon::join(|| f6(&net6), || f7(&net7)),
-                                                )
-                                            },
-                                        )
-                                    },
-                                )
-                            },
-                        )
-                    },
-                )
-            },
-        );
-        self.queue.push(id0, net0);
-        self.queue.push(id1, net1);
-        self.queue.push(id2, net2);
-        self.queue.push(id3, net3);
-        self.queue.push(id4, net4);
-        self.queue.push(id5, net5);
-        self.queue.push(id6, net6);
-        self.queue.push(id7, net7);
-        (r0, r1, r2, r3, r4, r5, r6, r7)
-    }
-
-    pub fn join_cpu<R0: Send, R1: Send>(
-        &self,
-        f0: impl FnOnce() -> R0 + Send,
-        f1: impl FnOnce() -> R1 + Send,
-    ) -> (R0, R1) {
-        self.cpu_pool.join(f0, f1)
+        f0: impl FnOnce(&N) -> R0 + Send + 'static,
+        f1: impl FnOnce(&N) -> R1 + Send + 'static,
+        timeout: Duration,
+    ) -> Option<(R0, R1)> {
+        let deadline = Instant::now() + timeout;
+        let (id0, rx0) = self.spawn_net_abandonable(f0);
+        let (id1, rx1) = self.spawn_net_abandonable(f1);
+        let r0 = self.recv_or_poison(id0, rx0, deadline);
+        let r1 = self.recv_or_poison(id1, rx1, deadline);
+        // both networks are poisoned if necessary before a panic is resumed
+        let (r0, r1) = (r0.map(resume_panic), r1.map(resume_panic));
+        Some((r0?, r1?))
+    }
+
+    /// The indices of all networks that were abandoned by a timed out operation.
+    pub fn poisoned_networks(&self) -> Vec<usize> {
+        self.queue.poisoned()
+    }
+
+    /// Clear the poisoned flag of the network at `index`, e.g., after the protocol resynchronized it.
+    pub fn clear_poisoned(&self, index: usize) {
+        self.queue.clear_poison(index);
+    }
+
+    /// Runs `f(peer, net)` for every other party concurrently on the net pool.
+    ///
+    /// Returns the results indexed by party id, with `None` at the own id. Every peer `j` of
+    /// party `i` uses the network at index `(i + j) % num`, so both ends of a link agree on the
+    /// connection. With at least as many networks as parties, every peer gets its own network,
+    /// otherwise some peers share one. This does not advance the round-robin order of the other
+    /// methods. With a single party, `f` is never called and the result is `[None]`.
+    pub fn for_each_peer<R: Send>(&self, f: impl Fn(usize, &N) -> R + Sync) -> Vec<Option<R>> {
+        let span = tracing::debug_span!("round", round = self.current_round());
+        // without networks, wait for one to be inserted
+        let num = self.queue.num().max(1);
+        let indices = (0..self.num_parties)
+            .map(|peer| (peer != self.id).then(|| (self.id + peer) % num))
+            .collect::<Vec<_>>();
+        // pop in ascending order, so concurrent calls can't wait for each other's networks
+        let nets = indices
+            .iter()
+            .flatten()
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|index| self.queue.pop_index(index))
+            .collect::<BTreeMap<_, _>>();
+        let res = self.net_pool.install(|| {
+            indices
+                .par_iter()
+                .enumerate()
+                .map(|(peer, index)| {
+                    let net = &nets[index.as_ref()?];
+                    Some(span.in_scope(|| f(peer, net)))
+                })
+                .collect()
+        });
+        for (index, net) in nets {
+            self.queue.push(index, net);
+        }
+        res
     }
 
-    pub fn join3_cpu<R0: Send, R1: Send, R2: Send>(
+    /// Sends `data` of every party to the party `king`.
+    ///
+    /// Returns `Some(messages)` on the king, where `messages[i]` is the data of party `i`
+    /// including its own, and `None` on all other parties. All parties must call this with the
+    /// same `king`, which receives the messages concurrently on the net pool.
+    pub fn gather_to(&self, king: usize, data: &[u8]) -> eyre::Result<Option<Vec<Vec<u8>>>> {
+        self.install_net(|net| {
+            let (me, num_parties) = (net.id(), net.num_parties());
+            if king >= num_parties {
+                eyre::bail!("king {king} is not one of the {num_parties} parties");
+            }
+            if me != king {
+                net.send(king, data)?;
+                return Ok(None);
+            }
+            let messages = (0..num_parties)
+                .into_par_iter()
+                .map(|from| {
+                    if from == me {
+                        Ok(data.to_vec())
+                    } else {
+                        Ok(net.recv(from)?)
+                    }
+                })
+                .collect::<eyre::Result<_>>()?;
+            Ok(Some(messages))
+        })
+    }
+
+    /// Sends `per_party[i]` from the party `king` to the party `i` and returns the message for this
+    /// party.
+    ///
+    /// The king passes one message for every party, including its own, all other parties pass
+    /// `None`. All parties must call this with the same `king`.
+    pub fn scatter_from(
         &self,
-        f0: impl FnOnce() -> R0 + Send,
-        f1: impl FnOnce() -> R1 + Send,
-        f2: impl FnOnce() -> R2 + Send,
-    ) -> (R0, R1, R2) {
-        let (r0, (r1, r2)) = self.cpu_pool.join(f0, || rayon::join(f1, f2));
-        (r0, r1, r2)
+        king: usize,
+        per_party: Option<Vec<Vec<u8>>>,
+    ) -> eyre::Result<Vec<u8>> {
+        self.install_net(|net| {
+            let (me, num_parties) = (net.id(), net.num_parties());
+            if king >= num_parties {
+                eyre::bail!("king {king} is not one of the {num_parties} parties");
+            }
+            match per_party {
+                Some(mut messages) if me == king => {
+                    if messages.len() != num_parties {
+                        eyre::bail!(
+                            "expected a message for each of the {num_parties} parties, got {}",
+                            messages.len()
+                        );
+                    }
+                    (0..num_parties)
+                        .into_par_iter()
+                        .filter(|&to| to != me)
+                        .try_for_each(|to| net.send(to, &messages[to]))?;
+                    Ok(std::mem::take(&mut messages[me]))
+                }
+                None if me != king => Ok(net.recv(king)?),
+                Some(_) => eyre::bail!("only the king {king} passes messages to scatter"),
+                None => eyre::bail!("the king {king} has to pass the messages to scatter"),
+            }
+        })
     }
 
-    pub fn join4_cpu<R0: Send, R1: Send, R2: Send, R3: Send>(
+    /// Sends `data` from the party `root` to all parties along a binary tree and returns it.
+    ///
+    /// The root passes `Some(data)`, all other parties pass `None`. Every party receives the data
+    /// from its parent and forwards it to at most two children, so no party sends more than two
+    /// copies, at the cost of `log2(num_parties)` hops. With up to three parties, the root sends to
+    /// everyone directly. All parties must call this with the same `root`, the tree only depends
+    /// on the party ids: the party `root + r` forwards to the parties `root + 2r + 1` and
+    /// `root + 2r + 2`, modulo the number of parties.
+    pub fn broadcast_tree(&self, root: usize, data: Option<&[u8]>) -> eyre::Result<Vec<u8>> {
+        self.install_net(|net| {
+            let links = TreeLinks::new(net.id(), root, net.num_parties())?;
+            let data = match (links.parent, data) {
+                (None, Some(data)) => data.to_vec(),
+                (Some(parent), None) => net.recv(parent)?,
+                (None, None) => eyre::bail!("the root {root} has to pass the data to broadcast"),
+                (Some(_), Some(_)) => eyre::bail!("only the root {root} passes data to broadcast"),
+            };
+            links
+                .children
+                .par_iter()
+                .try_for_each(|&child| net.send(child, &data))?;
+            Ok(data)
+        })
+    }
+
+    /// Combines the `local` values of all parties with `combine` and returns the result on every
+    /// party.
+    ///
+    /// The values are reduced up the binary tree of [`MpcEngine::broadcast_tree`] rooted at party 0,
+    /// each party combining its own value with the ones of its children in ascending order of
+    /// their rank, and the result is sent back down the tree. This takes `2 * (num_parties - 1)`
+    /// messages in total, at most three sent by a single party, and `2 * log2(num_parties)` hops.
+    /// As the result is computed once, all parties get the same bytes, but `combine` should be
+    /// associative and commutative for it to not depend on the tree.
+    pub fn all_reduce(
         &self,
-        f0: impl FnOnce() -> R0 + Send,
-        f1: impl FnOnce() -> R1 + Send,
-        f2: impl FnOnce() -> R2 + Send,
-        f3: impl FnOnce() -> R3 + Send,
-    ) -> (R0, R1, R2, R3) {
-        let (r0, (r1, (r2, r3))) = self
-            .cpu_pool
-            .join(f0, || rayon::join(f1, || rayon::join(f2, f3)));
-        (r0, r1, r2, r3)
-    }
-
-    pub fn join5_cpu<R0: Send, R1: Send, R2: Send, R3: Send, R4: Send>(
+        local: Vec<u8>,
+        combine: impl Fn(&[u8], &[u8]) -> Vec<u8> + Sync,
+    ) -> eyre::Result<Vec<u8>> {
+        self.install_net(|net| {
+            let links = TreeLinks::new(net.id(), 0, net.num_parties())?;
+            let values = links
+                .children
+                .par_iter()
+                .map(|&child| net.recv(child))
+                .collect::<Result<Vec<_>, _>>()?;
+            let reduced = values.iter().fold(local, |acc, value| combine(&acc, value));
+            let result = match links.parent {
+                Some(parent) => {
+                    net.send(parent, &reduced)?;
+                    net.recv(parent)?
+                }
+                None => reduced,
+            };
+            links
+                .children
+                .par_iter()
+                .try_for_each(|&child| net.send(child, &result))?;
+            Ok(result)
+        })
+    }
+
+    pub fn install_cpu<T: Send>(&self, f: impl FnOnce() -> T + Send) -> T {
+        self.cpu_pool.install(f)
+    }
+
+    join_net!(join_net, (f0, R0, net0), (f1, R1, net1));
+    join_net!(join3_net, (f0, R0, net0), (f1, R1, net1), (f2, R2, net2));
+    join_net!(
+        join4_net,
+        (f0, R0, net0),
+        (f1, R1, net1),
+        (f2, R2, net2),
+        (f3, R3, net3)
+    );
+    join_net!(
+        join5_net,
+        (f0, R0, net0),
+        (f1, R1, net1),
+        (f2, R2, net2),
+        (f3, R3, net3),
+        (f4, R4, net4)
+    );
+    join_net!(
+        join6_net,
+        (f0, R0, net0),
+        (f1, R1, net1),
+        (f2, R2, net2),
+        (f3, R3, net3),
+        (f4, R4, net4),
+        (f5, R5, net5)
+    );
+    join_net!(
+        join7_net,
+        (f0, R0, net0),
+        (f1, R1, net1),
+        (f2, R2, net2),
+        (f3, R3, net3),
+        (f4, R4, net4),
+        (f5, R5, net5),
+        (f6, R6, net6)
+    );
+    join_net!(
+        join8_net,
+        (f0, R0, net0),
+        (f1, R1, net1),
+        (f2, R2, net2),
+        (f3, R3, net3),
+        (f4, R4, net4),
+        (f5, R5, net5),
+        (f6, R6, net6),
+        (f7, R7, net7)
+    );
+
+    /// Runs every closure of `fs` with its own network on the net pool and returns the results in order.
+    ///
+    /// The networks are popped in the order of `fs`, like for the fixed arity `join*_net` methods.
+    /// With more closures than networks, a closure waits until an earlier one returned its network.
+    /// A network is returned to the queue even if its closure panics, the panic is propagated once
+    /// all closures finished.
+    #[allow(clippy::type_complexity)]
+    pub fn join_many_net<'a, R: Send>(
         &self,
-        f0: impl FnOnce() -> R0 + Send,
-        f1: impl FnOnce() -> R1 + Send,
-        f2: impl FnOnce() -> R2 + Send,
-        f3: impl FnOnce() -> R3 + Send,
-        f4: impl FnOnce() -> R4 + Send,
-    ) -> (R0, R1, R2, R3, R4) {
-        let (r0, (r1, (r2, (r3, r4)))) = self.cpu_pool.join(f0, || {
-            rayon::join(f1, || rayon::join(f2, || rayon::join(f3, f4)))
+        fs: Vec<Box<dyn FnOnce(&N) -> R + Send + 'a>>,
+    ) -> Vec<R> {
+        let mut results = fs.iter().map(|_| None).collect::<Vec<_>>();
+        self.net_pool.in_place_scope(|scope| {
+            for (f, res) in fs.into_iter().zip(results.iter_mut()) {
+                let f = self.in_round(f);
+                let (index, net) = self.queue.pop();
+                let lent = LentNet {
+                    index,
+                    net: Some(net),
+                    queue: &self.queue,
+                };
+                scope.spawn(move |_| *res = Some(f(lent.net())));
+            }
         });
-        (r0, r1, r2, r3, r4)
+        results
+            .into_iter()
+            .map(|res| res.expect("scope joined all closures"))
+            .collect()
+    }
+
+    /// Creates a scope to spawn network tasks that may borrow from the stack, like [`rayon::scope`].
+    ///
+    /// Every [`NetScope::spawn`] runs its closure with its own network on the net pool, all spawned
+    /// closures finished once `scope_net` returns. A panic in a spawned closure is propagated after
+    /// that.
+    pub fn scope_net<'scope, R>(&'scope self, f: impl FnOnce(&NetScope<'_, 'scope, N>) -> R) -> R {
+        self.net_pool
+            .in_place_scope(|scope: &rayon::Scope<'scope>| {
+                f(&NetScope {
+                    scope,
+                    engine: self,
+                })
+            })
+    }
+
+    join_cpu!(join_cpu, (f0, R0), (f1, R1));
+    join_cpu!(join3_cpu, (f0, R0), (f1, R1), (f2, R2));
+    join_cpu!(join4_cpu, (f0, R0), (f1, R1), (f2, R2), (f3, R3));
+    join_cpu!(join5_cpu, (f0, R0), (f1, R1), (f2, R2), (f3, R3), (f4, R4));
+    join_cpu!(
+        join6_cpu,
+        (f0, R0),
+        (f1, R1),
+        (f2, R2),
+        (f3, R3),
+        (f4, R4),
+        (f5, R5)
+    );
+    join_cpu!(
+        join7_cpu,
+        (f0, R0),
+        (f1, R1),
+        (f2, R2),
+        (f3, R3),
+        (f4, R4),
+        (f5, R5),
+        (f6, R6)
+    );
+    join_cpu!(
+        join8_cpu,
+        (f0, R0),
+        (f1, R1),
+        (f2, R2),
+        (f3, R3),
+        (f4, R4),
+        (f5, R5),
+        (f6, R6),
+        (f7, R7)
+    );
+}
+
+impl<N: Network + Send + 'static> MpcEngine<SequencedNetwork<N>> {
+    /// Snapshot the sequence state of all networks, ordered by their index.
+    ///
+    /// Waits until no network is in use, so the snapshot is consistent with the completed tasks.
+    pub fn checkpoint_sequences(&self) -> Vec<SequenceCheckpoint> {
+        self.queue.with_all(|nets| {
+            (0..nets.len())
+                .map(|index| nets.get(index).expect("must exist").checkpoint())
+                .collect()
+        })
+    }
+
+    /// Restore the sequence state of all networks from the `checkpoints` taken with
+    /// [`MpcEngine::checkpoint_sequences`].
+    pub fn restore_sequences(&self, checkpoints: &[SequenceCheckpoint]) -> eyre::Result<()> {
+        self.queue.with_all(|nets| {
+            if checkpoints.len() != nets.len() {
+                eyre::bail!(
+                    "expected {} checkpoints, got {}",
+                    nets.len(),
+                    checkpoints.len()
+                );
+            }
+            for (index, checkpoint) in checkpoints.iter().enumerate() {
+                nets.get(index).expect("must exist").restore(checkpoint)?;
+            }
+            Ok(())
+        })
     }
 }
 
+/// Runs `per_party` for each of `num_parties` parties on its own thread, each with an engine over a
+/// [`TestNetwork`] mesh, and returns the results ordered by party id.
+///
+/// A panicking party drops its networks, so the other parties fail to receive from it instead of
+/// hanging. The panic is returned as an error.
+pub fn simulate<R: Send>(
+    num_parties: usize,
+    per_party: impl Fn(MpcEngine<TestNetwork>) -> R + Send + Sync,
+) -> eyre::Result<Vec<R>> {
+    let nets = TestNetwork::networks(num_parties, NUM_THREADS_NET);
+    let per_party = &per_party;
+    std::thread::scope(|scope| {
+        let handles = nets
+            .into_iter()
+            .enumerate()
+            .map(|(id, nets)| {
+                scope.spawn(move || {
+                    per_party(MpcEngine::new(id, NUM_THREADS_NET, NUM_THREADS_CPU, nets))
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut results = Vec::with_capacity(num_parties);
+        let mut panicked = None;
+        for (id, handle) in handles.into_iter().enumerate() {
+            match handle.join() {
+                Ok(result) => results.push(result),
+                Err(payload) => {
+                    // report the first party that panicked, the others may have panicked because of it
+                    panicked.get_or_insert((id, panic_message(payload)));
+                }
+            }
+        }
+        match panicked {
+            Some((id, msg)) => Err(eyre::eyre!("party {id} panicked: {msg}")),
+            None => Ok(results),
+        }
+    })
+}
+
+/// The neighbors of a party in a binary tree over all parties, e.g., for [`MpcEngine::broadcast_tree`].
+///
+/// The parties are ranked by their distance `(id - root) mod num_parties` from the root, the party
+/// of rank `r` is the parent of the ranks `2r + 1` and `2r + 2`. This only depends on the ids, so
+/// all parties agree on the tree.
 #[derive(Debug)]
+struct TreeLinks {
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+impl TreeLinks {
+    fn new(id: usize, root: usize, num_parties: usize) -> eyre::Result<Self> {
+        if root >= num_parties {
+            eyre::bail!("root {root} is not one of the {num_parties} parties");
+        }
+        let rank = (id + num_parties - root) % num_parties;
+        let party = |rank: usize| (rank + root) % num_parties;
+        let parent = rank.checked_sub(1).map(|rank| party(rank / 2));
+        let children = [2 * rank + 1, 2 * rank + 2]
+            .into_iter()
+            .filter(|&child| child < num_parties)
+            .map(party)
+            .collect();
+        Ok(Self { parent, children })
+    }
+}
+
+/// The message of a panic payload, as passed to `panic!`.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .copied()
+            .unwrap_or("unknown panic")
+            .to_owned(),
+    }
+}
+
+/// The error of [`Handle::join`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task panicked with the given message
+    Panicked(String),
+    /// The task was dropped without running, e.g., because its pool shut down
+    Cancelled,
+}
+
+impl std::error::Error for JoinError {}
+
+impl std::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::Panicked(msg) => write!(f, "spawned task panicked: {msg}"),
+            JoinError::Cancelled => write!(f, "spawned task was cancelled"),
+        }
+    }
+}
+
+/// The receiving end of a spawned task, see [`Handle`].
+trait Pending<T>: Send {
+    /// Waits up to `timeout` for the result, forever if `None`, and returns `None` on timeout.
+    fn recv(&mut self, timeout: Option<Duration>) -> Option<Result<T, JoinError>>;
+
+    /// Returns the result if it arrived, `None` otherwise.
+    fn try_recv(&mut self) -> Option<Result<T, JoinError>>;
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, JoinError>>;
+}
+
+impl<T: Send> Pending<T> for oneshot::Receiver<std::thread::Result<T>> {
+    fn recv(&mut self, timeout: Option<Duration>) -> Option<Result<T, JoinError>> {
+        let res = match timeout {
+            Some(timeout) => match self.recv_timeout(timeout) {
+                Ok(res) => Ok(res),
+                Err(oneshot::RecvTimeoutError::Timeout) => return None,
+                Err(oneshot::RecvTimeoutError::Disconnected) => Err(JoinError::Cancelled),
+            },
+            None => self.recv_ref().map_err(|_| JoinError::Cancelled),
+        };
+        Some(res.and_then(task_result))
+    }
+
+    fn try_recv(&mut self) -> Option<Result<T, JoinError>> {
+        match oneshot::Receiver::try_recv(self) {
+            Ok(res) => Some(task_result(res)),
+            Err(oneshot::TryRecvError::Empty) => None,
+            Err(oneshot::TryRecvError::Disconnected) => Some(Err(JoinError::Cancelled)),
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, JoinError>> {
+        Pin::new(self).poll(cx).map(|res| match res {
+            Ok(res) => task_result(res),
+            Err(_) => Err(JoinError::Cancelled),
+        })
+    }
+}
+
+/// A [`Pending`] result that is transformed once it arrived, see [`Handle::map`].
+struct Map<T, F> {
+    inner: Box<dyn Pending<T>>,
+    f: Option<F>,
+}
+
+impl<T, F> Map<T, F> {
+    fn apply<U>(&mut self, res: Result<T, JoinError>) -> Result<U, JoinError>
+    where
+        F: FnOnce(T) -> U,
+    {
+        let f = self.f.take().expect("result is only taken once");
+        res.map(f)
+    }
+}
+
+impl<T, U, F: FnOnce(T) -> U + Send> Pending<U> for Map<T, F> {
+    fn recv(&mut self, timeout: Option<Duration>) -> Option<Result<U, JoinError>> {
+        let res = self.inner.recv(timeout)?;
+        Some(self.apply(res))
+    }
+
+    fn try_recv(&mut self) -> Option<Result<U, JoinError>> {
+        let res = self.inner.try_recv()?;
+        Some(self.apply(res))
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<U, JoinError>> {
+        self.inner.poll(cx).map(|res| self.apply(res))
+    }
+}
+
+/// Returns the result of a task or resumes its panic on the current thread.
+fn resume_panic<T>(res: std::thread::Result<T>) -> T {
+    res.unwrap_or_else(|payload| std::panic::resume_unwind(payload))
+}
+
+/// Converts the result of a task that may have panicked.
+fn task_result<T>(res: std::thread::Result<T>) -> Result<T, JoinError> {
+    res.map_err(|payload| JoinError::Panicked(panic_message(payload)))
+}
+
 pub struct Handle<T> {
-    sender: oneshot::Receiver<T>,
+    pending: Box<dyn Pending<T>>,
+}
+
+impl<T> std::fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Handle").finish_non_exhaustive()
+    }
+}
+
+impl<T: Send + 'static> Handle<T> {
+    fn new(rx: oneshot::Receiver<std::thread::Result<T>>) -> Self {
+        Self {
+            pending: Box::new(rx),
+        }
+    }
 }
 
 impl<T> Handle<T> {
-    pub fn join(self) -> T {
-        self.sender.recv().unwrap()
+    /// Waits for the task to finish and returns its result.
+    ///
+    /// A panic of the task is caught on the pool and returned as [`JoinError::Panicked`], so the
+    /// caller can abort the protocol cleanly instead of panicking as well.
+    pub fn join(mut self) -> Result<T, JoinError> {
+        self.pending.recv(None).expect("waits without timeout")
+    }
+
+    /// Returns the result of [`Handle::join`] if the task finished, or the handle otherwise.
+    ///
+    /// This does not block, so a caller can poll a batch of handles and do other work in between.
+    pub fn try_join(mut self) -> Result<Result<T, JoinError>, Self> {
+        self.pending.try_recv().ok_or(self)
+    }
+
+    /// Like [`Handle::try_join`], but waits up to `timeout` for the task to finish.
+    ///
+    /// On timeout the task keeps running and the handle is returned, so the caller can decide to
+    /// wait again or abort, e.g., when the deadline of a round passed.
+    pub fn join_timeout(mut self, timeout: Duration) -> Result<Result<T, JoinError>, Self> {
+        self.pending.recv(Some(timeout)).ok_or(self)
+    }
+
+    /// Returns a handle to the result of the task transformed by `f`.
+    ///
+    /// `f` runs on the thread that joins or awaits the returned handle, once the result arrived.
+    /// It is not called if the task panicked.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U + Send + 'static) -> Handle<U>
+    where
+        T: 'static,
+    {
+        Handle {
+            pending: Box::new(Map {
+                inner: self.pending,
+                f: Some(f),
+            }),
+        }
+    }
+}
+
+/// Awaiting a handle yields the result of [`Handle::join`] without blocking the executor thread.
+impl<T> Future for Handle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.pending.poll(cx)
+    }
+}
+
+/// Joins all `handles` in order and returns their results.
+///
+/// Every handle is joined even if a task panicked, so all tasks finished once this returns. The
+/// first error is returned in that case.
+pub fn join_all<T>(handles: Vec<Handle<T>>) -> Result<Vec<T>, JoinError> {
+    let mut results = Vec::with_capacity(handles.len());
+    let mut error = None;
+    for handle in handles {
+        match handle.join() {
+            Ok(res) => results.push(res),
+            Err(err) => {
+                error.get_or_insert(err);
+            }
+        }
+    }
+    match error {
+        Some(err) => Err(err),
+        None => Ok(results),
+    }
+}
+
+/// A network popped from the queue, pushed back when dropped, even during a panic.
+struct LentNet<'a, N> {
+    index: usize,
+    net: Option<N>,
+    queue: &'a NetworkQueue<N>,
+}
+
+impl<N> LentNet<'_, N> {
+    fn net(&self) -> &N {
+        self.net.as_ref().expect("must be some")
+    }
+}
+
+impl<N> Drop for LentNet<'_, N> {
+    fn drop(&mut self) {
+        self.queue
+            .push(self.index, self.net.take().expect("must be some"));
+    }
+}
+
+/// A scope to spawn network tasks that borrow from the stack, see [`MpcEngine::scope_net`].
+#[derive(Debug)]
+pub struct NetScope<'a, 'scope, N: Network> {
+    scope: &'a rayon::Scope<'scope>,
+    engine: &'scope MpcEngine<N>,
+}
+
+impl<'scope, N: Network + Send + 'static> NetScope<'_, 'scope, N> {
+    /// Runs `f` with its own network on the net pool.
+    ///
+    /// Waits until a network is available, the network is returned to the queue once `f` returned.
+    pub fn spawn(&self, f: impl FnOnce(&N) + Send + 'scope) {
+        let f = self.engine.in_round(f);
+        let (index, net) = self.engine.queue.pop();
+        let lent = LentNet {
+            index,
+            net: Some(net),
+            queue: &self.engine.queue,
+        };
+        self.scope.spawn(move |_| f(lent.net()));
+    }
+}
+
+/// The connections lent to a round started with [`MpcEngine::round`].
+#[derive(Debug)]
+pub struct RoundCtx<'a, N> {
+    index: usize,
+    net: Option<N>,
+    queue: &'a NetworkQueue<N>,
+    round: u64,
+    length_schedule: &'a [Vec<Option<usize>>],
+}
+
+impl<N: Network> RoundCtx<'_, N> {
+    /// The lent network, e.g., for helpers that take a [`Network`].
+    pub fn net(&self) -> &N {
+        self.net.as_ref().expect("must be some")
+    }
+
+    /// The id of this party.
+    pub fn id(&self) -> usize {
+        self.net().id()
+    }
+
+    /// The number of parties.
+    pub fn num_parties(&self) -> usize {
+        self.net().num_parties()
+    }
+
+    /// Send `data` to the party `to`.
+    pub fn send(&self, to: usize, data: &[u8]) -> eyre::Result<()> {
+        Ok(self.net().send(to, data)?)
+    }
+
+    /// Receive a message from the party `from`.
+    ///
+    /// Fails if the length does not match the schedule set with
+    /// [`MpcEngine::with_length_schedule`].
+    pub fn recv(&self, from: usize) -> eyre::Result<Vec<u8>> {
+        let data = self.net().recv(from)?;
+        let expected = usize::try_from(self.round)
+            .ok()
+            .and_then(|round| self.length_schedule.get(round))
+            .and_then(|peers| peers.get(from).copied().flatten());
+        if let Some(expected) = expected
+            && data.len() != expected
+        {
+            eyre::bail!(
+                "expected {expected} bytes from party {from} in round {}, got {}",
+                self.round,
+                data.len()
+            );
+        }
+        Ok(data)
+    }
+
+    /// Send `data` to all other parties, does nothing with a single party.
+    pub fn broadcast(&self, data: &[u8]) -> eyre::Result<()> {
+        Ok(self.net().broadcast(data)?)
+    }
+}
+
+impl<N> Drop for RoundCtx<'_, N> {
+    fn drop(&mut self) {
+        self.queue
+            .push(self.index, self.net.take().expect("must be some"));
     }
 }
 
@@ -313,11 +1444,60 @@ impl<T: Network> Network for NetworkGuard<T> {
         self.id
     }
 
-    fn send(&self, to: usize, data: &[u8]) -> eyre::Result<()> {
+    fn num_parties(&self) -> usize {
+        self.net.as_ref().expect("must be some").num_parties()
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
         self.net.as_ref().expect("must be some").send(to, data)
     }
 
-    fn recv(&self, from: usize) -> eyre::Result<Vec<u8>> {
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
         self.net.as_ref().expect("must be some").recv(from)
     }
+
+    fn recv_into(&self, from: usize, buf: &mut Vec<u8>) -> Result<usize, NetworkError> {
+        self.net
+            .as_ref()
+            .expect("must be some")
+            .recv_into(from, buf)
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        self.net
+            .as_ref()
+            .expect("must be some")
+            .recv_timeout(from, timeout)
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        self.net.as_ref().expect("must be some").resync_peer(id)
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        self.net.as_ref().expect("must be some").drain(from)
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        self.net.as_ref().expect("must be some").flush(to)
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        self.net.as_ref().expect("must be some").close(to)
+    }
+
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        self.net.as_ref().expect("must be some").ping(peer, timeout)
+    }
+
+    fn exchange(&self, with: usize, data: &[u8]) -> Result<Vec<u8>, NetworkError> {
+        self.net
+            .as_ref()
+            .expect("must be some")
+            .exchange(with, data)
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        self.net.as_ref().expect("must be some").size_histogram()
+    }
 }
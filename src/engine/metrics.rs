@@ -0,0 +1,82 @@
+//! Export the state of an [`MpcEngine`] as Prometheus metrics.
+
+use prometheus::{
+    IntGauge, Opts, Registry,
+    core::{Collector, Desc},
+    proto::MetricFamily,
+};
+use std::sync::{Arc, atomic::Ordering};
+
+use super::{InFlight, MpcEngine};
+use crate::{net::Network, queue::NetworkQueue};
+
+/// Reads the engine state whenever the registry is scraped.
+struct EngineCollector<N> {
+    queue: Arc<NetworkQueue<N>>,
+    in_flight: Arc<InFlight>,
+    round: Arc<std::sync::atomic::AtomicU64>,
+    round_gauge: IntGauge,
+    in_flight_gauge: IntGauge,
+    networks: IntGauge,
+    available: IntGauge,
+    poisoned: IntGauge,
+}
+
+impl<N> EngineCollector<N> {
+    fn gauges(&self) -> [&IntGauge; 5] {
+        [
+            &self.round_gauge,
+            &self.in_flight_gauge,
+            &self.networks,
+            &self.available,
+            &self.poisoned,
+        ]
+    }
+}
+
+impl<N: Send + 'static> Collector for EngineCollector<N> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauges().into_iter().flat_map(|g| g.desc()).collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.round_gauge
+            .set(self.round.load(Ordering::Relaxed) as i64);
+        self.in_flight_gauge
+            .set(*self.in_flight.count.lock() as i64);
+        self.networks.set(self.queue.num() as i64);
+        self.available.set(self.queue.available() as i64);
+        self.poisoned.set(self.queue.poisoned().len() as i64);
+        self.gauges()
+            .into_iter()
+            .flat_map(|g| g.collect())
+            .collect()
+    }
+}
+
+impl<N: Network + Send + 'static> MpcEngine<N> {
+    /// Register the metrics of this engine in `registry`, labeled with the party id.
+    ///
+    /// The exported gauges are the current round, the number of in-flight spawned tasks, and the
+    /// number of networks in total, currently available in the pool, and poisoned. They are read
+    /// from the engine whenever the registry is gathered.
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        let gauge = |name: &str, help: &str| {
+            IntGauge::with_opts(
+                Opts::new(name, help)
+                    .namespace("mpc_engine")
+                    .const_label("party", self.id.to_string()),
+            )
+        };
+        registry.register(Box::new(EngineCollector {
+            queue: Arc::clone(&self.queue),
+            in_flight: Arc::clone(&self.in_flight),
+            round: Arc::clone(&self.round),
+            round_gauge: gauge("round", "The current round number")?,
+            in_flight_gauge: gauge("in_flight_tasks", "The number of unfinished spawned tasks")?,
+            networks: gauge("networks", "The number of networks")?,
+            available: gauge("networks_available", "The number of networks not in use")?,
+            poisoned: gauge("networks_poisoned", "The number of poisoned networks")?,
+        }))
+    }
+}
@@ -1,6 +1,28 @@
 mod engine;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod net;
 mod queue;
 
-pub use engine::{Handle, MpcEngine, NUM_THREADS_CPU, NUM_THREADS_NET, NetworkGuard};
-pub use net::{Address, DummyNetwork, Network, TcpNetwork, TestNetwork, TlsNetwork};
+pub use engine::{
+    Handle, JoinError, MpcEngine, MpcEngineBuilder, NUM_THREADS_CPU, NUM_THREADS_NET, NetScope,
+    NetworkGuard, RoundCtx, join_all, simulate,
+};
+#[cfg(feature = "metrics")]
+pub use metrics::describe_metrics;
+#[cfg(feature = "compression")]
+pub use net::CompressedNetwork;
+#[cfg(feature = "quic")]
+pub use net::QuicNetwork;
+#[cfg(feature = "shmem")]
+pub use net::ShmemNetwork;
+#[cfg(unix)]
+pub use net::UnixNetwork;
+pub use net::{
+    Address, AuthNetwork, BitFlip, ByzantineNetwork, ConnectProgress, Corruption, DummyNetwork,
+    LinkMatrix, LinkProps, Network, NetworkConfig, NetworkError, NetworkExt, PausableNetwork,
+    PeerIdentity, PriorityNetwork, RandomBytes, RecordingNetwork, RecvProgress, ReplayNetwork,
+    STREAM_CHUNK_SIZE, SendQueue, SequenceCheckpoint, SequencedNetwork, SizeHistogram, Socks5Proxy,
+    TcpKeepalive, TcpNetwork, TeeNetwork, TestNetwork, TlsNetwork, TlsSessionCache,
+    TranscriptNetwork, Truncate,
+};
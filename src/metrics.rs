@@ -0,0 +1,109 @@
+//! Emit the state of the engine and the networks through the [`metrics`] facade.
+//!
+//! The following metrics are emitted to the recorder installed by the application, e.g., a
+//! Prometheus exporter. All of them are labeled with the `party` id, the ones of a link also
+//! with the `peer`:
+//!
+//! - `mpc_engine_bytes_sent_total` and `mpc_engine_bytes_received_total`, counters of the bytes
+//!   on the wire per peer, including the framing,
+//! - `mpc_engine_messages_sent_total` and `mpc_engine_messages_received_total`, counters of the
+//!   messages per peer,
+//! - `mpc_engine_connect_retries_total`, a counter of the failed attempts to connect or
+//!   reconnect to a peer that were retried,
+//! - `mpc_engine_networks_available`, a gauge of the networks of an engine not in use, i.e.,
+//!   the depth of its network queue,
+//! - `mpc_engine_in_flight_tasks`, a gauge of the tasks spawned on an engine that did not finish
+//!   yet.
+//!
+//! The handles of the networks and the engine are bound to the recorder when they are created,
+//! so the recorder has to be installed before. The counters are not affected by resetting the
+//! statistics of a network.
+
+use ::metrics::{Counter, Gauge, Unit, counter, describe_counter, describe_gauge, gauge};
+
+const BYTES_SENT: &str = "mpc_engine_bytes_sent_total";
+const BYTES_RECEIVED: &str = "mpc_engine_bytes_received_total";
+const MESSAGES_SENT: &str = "mpc_engine_messages_sent_total";
+const MESSAGES_RECEIVED: &str = "mpc_engine_messages_received_total";
+const CONNECT_RETRIES: &str = "mpc_engine_connect_retries_total";
+const NETWORKS_AVAILABLE: &str = "mpc_engine_networks_available";
+const IN_FLIGHT_TASKS: &str = "mpc_engine_in_flight_tasks";
+
+/// Describe the metrics of this crate to the installed recorder.
+///
+/// This is optional, it adds the units and help texts to exporters that support them.
+pub fn describe_metrics() {
+    describe_counter!(BYTES_SENT, Unit::Bytes, "The bytes sent to a peer");
+    describe_counter!(
+        BYTES_RECEIVED,
+        Unit::Bytes,
+        "The bytes received from a peer"
+    );
+    describe_counter!(MESSAGES_SENT, Unit::Count, "The messages sent to a peer");
+    describe_counter!(
+        MESSAGES_RECEIVED,
+        Unit::Count,
+        "The messages received from a peer"
+    );
+    describe_counter!(
+        CONNECT_RETRIES,
+        Unit::Count,
+        "The retried attempts to connect to a peer"
+    );
+    describe_gauge!(
+        NETWORKS_AVAILABLE,
+        Unit::Count,
+        "The number of networks not in use"
+    );
+    describe_gauge!(
+        IN_FLIGHT_TASKS,
+        Unit::Count,
+        "The number of unfinished spawned tasks"
+    );
+}
+
+/// The counters of the link of party `id` to a single peer.
+#[derive(Debug)]
+pub(crate) struct PeerMetrics {
+    bytes_sent: Counter,
+    bytes_recv: Counter,
+    messages_sent: Counter,
+    messages_recv: Counter,
+}
+
+impl PeerMetrics {
+    pub(crate) fn new(id: usize, peer: usize) -> Self {
+        let labels = [("party", id.to_string()), ("peer", peer.to_string())];
+        Self {
+            bytes_sent: counter!(BYTES_SENT, &labels),
+            bytes_recv: counter!(BYTES_RECEIVED, &labels),
+            messages_sent: counter!(MESSAGES_SENT, &labels),
+            messages_recv: counter!(MESSAGES_RECEIVED, &labels),
+        }
+    }
+
+    pub(crate) fn record_send(&self, frame_len: u64) {
+        self.bytes_sent.increment(frame_len);
+        self.messages_sent.increment(1);
+    }
+
+    pub(crate) fn record_recv(&self, frame_len: u64) {
+        self.bytes_recv.increment(frame_len);
+        self.messages_recv.increment(1);
+    }
+}
+
+/// Count a failed attempt of party `id` to connect to `peer` that is retried.
+pub(crate) fn connect_retry(id: usize, peer: usize) {
+    counter!(CONNECT_RETRIES, "party" => id.to_string(), "peer" => peer.to_string()).increment(1);
+}
+
+/// The gauge of the available networks of the engine of party `id`.
+pub(crate) fn networks_available(id: usize) -> Gauge {
+    gauge!(NETWORKS_AVAILABLE, "party" => id.to_string())
+}
+
+/// The gauge of the in-flight tasks of the engine of party `id`.
+pub(crate) fn in_flight_tasks(id: usize) -> Gauge {
+    gauge!(IN_FLIGHT_TASKS, "party" => id.to_string())
+}
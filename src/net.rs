@@ -1,26 +1,99 @@
+mod auth;
+mod byzantine;
+mod codec;
+#[cfg(feature = "compression")]
+mod compress;
+mod pause;
+mod priority;
+#[cfg(feature = "quic")]
+mod quic;
+mod seq;
+#[cfg(feature = "shmem")]
+mod shmem;
+mod stats;
+mod tee;
+mod transcript;
+#[cfg(unix)]
+mod unix;
+
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use codec::{Codec, FrameReader, FrameWriter, TranscriptHasher};
 use eyre::ContextCompat;
 use intmap::IntMap;
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex, RwLock};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use rustls::{
-    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
-    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    ClientConfig, ClientConnection, ConfigBuilder, DigitallySignedStruct, RootCertStore,
+    ServerConfig, ServerConnection, SignatureScheme, StreamOwned, SupportedProtocolVersion,
+    WantsVerifier,
+    client::{
+        ClientSessionMemoryCache, Resumption,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        verify_server_cert_signed_by_trust_anchor, verify_server_name,
+    },
+    crypto::{
+        CryptoProvider, WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature,
+    },
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+    server::{ParsedCertificate, ServerSessionMemoryCache, WebPkiClientVerifier},
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use socket2::{Domain, SockRef, Socket, Type};
+use stats::PeerStats;
+// the channels between test networks, crossbeam's are faster under high message rates
+#[cfg(feature = "crossbeam")]
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, unbounded as channel};
+#[cfg(not(feature = "crossbeam"))]
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
 use std::{
     cmp::Ordering,
+    collections::{BTreeMap, HashMap, VecDeque},
     fmt::Formatter,
-    io::{Read, Write},
-    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    io::{ErrorKind, IoSlice, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
     num::ParseIntError,
+    ops::{Index, IndexMut, Range},
+    path::Path,
     str::FromStr,
-    sync::{Arc, mpsc},
-    time::Duration,
+    sync::{Arc, atomic::AtomicBool},
+    time::{Duration, Instant},
 };
 
+pub use auth::AuthNetwork;
+pub use byzantine::{BitFlip, ByzantineNetwork, Corruption, RandomBytes, Truncate};
+#[cfg(feature = "compression")]
+pub use compress::CompressedNetwork;
+pub use pause::PausableNetwork;
+pub use priority::PriorityNetwork;
+#[cfg(feature = "quic")]
+pub use quic::QuicNetwork;
+pub use seq::{SequenceCheckpoint, SequencedNetwork};
+#[cfg(feature = "shmem")]
+pub use shmem::ShmemNetwork;
+pub use stats::SizeHistogram;
+pub use tee::TeeNetwork;
+pub use transcript::{ReplayNetwork, TranscriptNetwork};
+#[cfg(unix)]
+pub use unix::UnixNetwork;
+
 const TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_MESSAGE_SIZE: usize = 1 << 30;
+const CONNECT_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(2);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The size of the chunks of [`NetworkExt::send_stream`].
+pub const STREAM_CHUNK_SIZE: usize = 1 << 20;
+
+/// The maximum length of the [`TlsNetwork`] handshake preamble.
+const MAX_PREAMBLE_LEN: usize = 18;
+
+/// The acknowledgement sent by [`Network::recv_acked`].
+const ACK: u8 = 0x06;
 
 /// A network address wrapper.
+///
+/// IPv6 literals are stored without brackets and written as `[hostname]:port`.
 #[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord, Hash)]
 pub struct Address {
     /// The hostname of the address, will be DNS resolved.
@@ -34,18 +107,44 @@ impl Address {
     pub fn new(hostname: String, port: u16) -> Self {
         Self { hostname, port }
     }
+
+    /// Construct a new [`Address`] from an IP address, no DNS lookup is needed to resolve it.
+    pub fn from_ip(ip: IpAddr, port: u16) -> Self {
+        Self {
+            hostname: ip.to_string(),
+            port,
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(addr) => Self::from_ip(IpAddr::V4(*addr.ip()), addr.port()),
+            // keep the scope id of link-local addresses, which `IpAddr` can't express
+            SocketAddr::V6(addr) if addr.scope_id() != 0 => Self {
+                hostname: format!("{}%{}", addr.ip(), addr.scope_id()),
+                port: addr.port(),
+            },
+            SocketAddr::V6(addr) => Self::from_ip(IpAddr::V6(*addr.ip()), addr.port()),
+        }
+    }
 }
 
 impl std::fmt::Display for Address {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}", self.hostname, self.port)
+        if self.hostname.contains(':') {
+            write!(f, "[{}]:{}", self.hostname, self.port)
+        } else {
+            write!(f, "{}:{}", self.hostname, self.port)
+        }
     }
 }
 
 /// An error for parsing [`Address`]es.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ParseAddressError {
-    /// Must be hostname:port
+    /// Must be hostname:port or [hostname]:port
     InvalidFormat,
     /// Invalid port
     InvalidPort(ParseIntError),
@@ -57,7 +156,10 @@ impl std::fmt::Display for ParseAddressError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             ParseAddressError::InvalidFormat => {
-                write!(f, "invalid format, expected hostname:port")
+                write!(
+                    f,
+                    "invalid format, expected hostname:port or [hostname]:port"
+                )
             }
             ParseAddressError::InvalidPort(e) => write!(f, "cannot parse port: {e}"),
         }
@@ -67,12 +169,24 @@ impl std::fmt::Display for ParseAddressError {
 impl FromStr for Address {
     type Err = ParseAddressError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.split(':').collect();
-        if parts.len() != 2 {
-            return Err(ParseAddressError::InvalidFormat);
-        }
-        let hostname = parts[0].to_string();
-        let port = parts[1].parse().map_err(ParseAddressError::InvalidPort)?;
+        let (hostname, port) = if let Some(rest) = s.strip_prefix('[') {
+            // an IPv6 literal, whose colons would be ambiguous without the brackets
+            let (hostname, port) = rest
+                .split_once("]:")
+                .ok_or(ParseAddressError::InvalidFormat)?;
+            if hostname.is_empty() || hostname.contains(']') {
+                return Err(ParseAddressError::InvalidFormat);
+            }
+            (hostname, port)
+        } else {
+            let parts: Vec<&str> = s.split(':').collect();
+            if parts.len() != 2 {
+                return Err(ParseAddressError::InvalidFormat);
+            }
+            (parts[0], parts[1])
+        };
+        let hostname = hostname.to_string();
+        let port = port.parse().map_err(ParseAddressError::InvalidPort)?;
         Ok(Address { hostname, port })
     }
 }
@@ -80,13 +194,13 @@ impl FromStr for Address {
 impl ToSocketAddrs for Address {
     type Iter = std::vec::IntoIter<SocketAddr>;
     fn to_socket_addrs(&self) -> std::io::Result<Self::Iter> {
-        format!("{}:{}", self.hostname, self.port).to_socket_addrs()
+        self.to_string().to_socket_addrs()
     }
 }
 
 impl Serialize for Address {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_str(&format!("{}:{}", self.hostname, self.port))
+        serializer.collect_str(self)
     }
 }
 
@@ -97,420 +211,3742 @@ impl<'de> Deserialize<'de> for Address {
     }
 }
 
-pub trait Network: Send + Sync {
-    fn id(&self) -> usize;
-    fn send(&self, to: usize, data: &[u8]) -> eyre::Result<()>;
-    fn recv(&self, from: usize) -> eyre::Result<Vec<u8>>;
-}
-
-#[derive(Debug)]
-pub struct TcpNetwork {
-    id: usize,
-    send: IntMap<usize, Mutex<TcpStream>>,
-    recv: IntMap<usize, Mutex<mpsc::Receiver<Vec<u8>>>>,
+/// The result of [`Network::recv_timeout`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecvProgress {
+    /// The message was received completely
+    Complete(Vec<u8>),
+    /// The message did not arrive in time
+    Partial {
+        /// The number of payload bytes buffered so far
+        received: usize,
+        /// The announced length of the message, `None` if not even the length prefix arrived
+        len: Option<usize>,
+    },
 }
 
-impl TcpNetwork {
-    pub fn networks<A: ToSocketAddrs>(
-        id: usize,
-        bind_addr: A,
-        addrs: &[Address],
-        num: usize,
-    ) -> eyre::Result<Vec<Self>> {
-        tracing::debug!("creating new network");
-        let listener = TcpListener::bind(bind_addr)?;
-
-        let mut nets = Vec::with_capacity(num);
-        for _ in 0..num {
-            nets.push(Self {
-                id,
-                send: IntMap::default(),
-                recv: IntMap::default(),
-            });
-        }
-
-        for i in 0..num {
-            for (other_id, addr) in addrs.iter().enumerate() {
-                match id.cmp(&other_id) {
-                    Ordering::Less => {
-                        let mut stream = loop {
-                            if let Ok(stream) = TcpStream::connect(addr) {
-                                break stream;
-                            }
-                            std::thread::sleep(Duration::from_millis(50));
-                        };
-                        stream.set_write_timeout(Some(TIMEOUT))?;
-                        stream.set_nodelay(true)?;
-                        stream.write_u64::<BigEndian>(i as u64)?;
-                        stream.write_u64::<BigEndian>(id as u64)?;
-                        nets[i]
-                            .send
-                            .insert(other_id, Mutex::new(stream.try_clone().unwrap()));
-                        let (tx, rx) = mpsc::channel();
-                        std::thread::spawn(move || {
-                            loop {
-                                let len = stream.read_u32::<BigEndian>()? as usize;
-                                let mut data = vec![0; len];
-                                stream.read_exact(&mut data)?;
-                                tx.send(data).unwrap();
-                            }
-                            #[allow(unreachable_code)]
-                            eyre::Ok(())
-                        });
-                        nets[i].recv.insert(other_id, Mutex::new(rx));
-                    }
-                    Ordering::Greater => {
-                        let (mut stream, _) = listener.accept()?;
-                        stream.set_write_timeout(Some(TIMEOUT))?;
-                        stream.set_nodelay(true)?;
-                        let i = stream.read_u64::<BigEndian>()? as usize;
-                        let other_id = stream.read_u64::<BigEndian>()? as usize;
-                        nets[i]
-                            .send
-                            .insert(other_id, Mutex::new(stream.try_clone().unwrap()));
-                        let (tx, rx) = mpsc::channel();
-                        std::thread::spawn(move || {
-                            loop {
-                                let len = stream.read_u32::<BigEndian>()? as usize;
-                                let mut data = vec![0; len];
-                                stream.read_exact(&mut data)?;
-                                tx.send(data).unwrap();
-                            }
-                            #[allow(unreachable_code)]
-                            eyre::Ok(())
-                        });
-                        nets[i].recv.insert(other_id, Mutex::new(rx));
-                    }
-                    Ordering::Equal => continue,
-                }
-            }
-        }
+pub trait Network: Send + Sync {
+    fn id(&self) -> usize;
+    /// The number of parties, including this one.
+    fn num_parties(&self) -> usize;
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError>;
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError>;
 
-        Ok(nets)
+    /// The ids of all other parties in ascending order, empty with a single party.
+    fn peers(&self) -> Vec<usize> {
+        let id = self.id();
+        (0..self.num_parties())
+            .filter(|&other| other != id)
+            .collect()
     }
-}
 
-impl Network for TcpNetwork {
-    fn id(&self) -> usize {
-        self.id
+    /// Receive a message from `from` into `buf`, replacing its contents, and return its length.
+    ///
+    /// Keeping one scratch buffer per peer avoids an allocation per message in transports that
+    /// override this. The TCP, TLS, Unix and QUIC transports hand the previous buffer back to
+    /// their reader thread, which reuses it for a later frame.
+    ///
+    /// The default implementation replaces `buf` with the result of [`Network::recv`].
+    fn recv_into(&self, from: usize, buf: &mut Vec<u8>) -> Result<usize, NetworkError> {
+        *buf = self.recv(from)?;
+        Ok(buf.len())
     }
 
-    fn send(&self, to: usize, data: &[u8]) -> eyre::Result<()> {
-        let mut stream = self
-            .send
-            .get(to)
-            .context("while get stream in send")?
-            .lock();
-        stream.write_u32::<BigEndian>(data.len() as u32)?;
-        stream.write_all(data)?;
-        Ok(())
+    /// Receive a message from `from`, waiting at most `timeout` for it to arrive.
+    ///
+    /// If the message did not fully arrive in time, the progress of the partially received
+    /// message is returned instead. The transport retains the partial message, so a subsequent
+    /// call resumes where this one left off without re-reading any bytes.
+    ///
+    /// The default implementation returns an error, a transport that can wait with a deadline has
+    /// to override this.
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        let _ = timeout;
+        Err(eyre::eyre!(
+            "receiving from party {from} with a timeout is not supported by this transport"
+        )
+        .into())
     }
 
-    fn recv(&self, from: usize) -> eyre::Result<Vec<u8>> {
-        let queue = self
-            .recv
-            .get(from)
-            .context("while get stream in recv")?
-            .lock();
-        Ok(queue.recv_timeout(TIMEOUT)?)
+    /// Realign the framed stream with the party `id`, e.g., after an aborted round or a transport glitch.
+    ///
+    /// Both parties must call this at the same logical point of the protocol. Each side sends a
+    /// resync marker (a reserved length prefix followed by a magic value) and then discards all
+    /// incoming frames until the marker of the peer arrives, so afterwards both directions start
+    /// at a frame boundary without stale messages. If the peer's marker is consumed by a regular
+    /// receive, that receive fails and the next resync does not wait for another marker.
+    ///
+    /// With CRC framing enabled, a reader that lost the frame alignment scans the byte stream
+    /// for the marker, so this also recovers from byte-level desyncs.
+    ///
+    /// The default implementation returns an error.
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        Err(eyre::eyre!("resync with party {id} is not supported by this transport").into())
     }
-}
-
-/// A wrapper type for client and server TLS streams
-#[derive(Debug)]
-pub enum TlsStream {
-    /// A Stream with a client connection
-    Client(StreamOwned<ClientConnection, TcpStream>),
-    /// A Stream with a sever connection
-    Server(StreamOwned<ServerConnection, TcpStream>),
-}
 
-impl From<StreamOwned<ClientConnection, TcpStream>> for TlsStream {
-    fn from(value: StreamOwned<ClientConnection, TcpStream>) -> Self {
-        Self::Client(value)
+    /// Discard all complete messages from `from` that arrived but were not received yet.
+    ///
+    /// This does not block and returns the number of discarded messages, e.g., to get rid of
+    /// stale messages after an aborted round. Only whole frames are discarded: a frame that is
+    /// still being read stays with the transport and is returned by the next receive. If the
+    /// resync marker of the peer is reached, draining stops there and the next
+    /// [`Network::resync_peer`] does not wait for another marker.
+    ///
+    /// The default implementation returns an error.
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        Err(eyre::eyre!("draining party {from} is not supported by this transport").into())
     }
-}
 
-impl From<StreamOwned<ServerConnection, TcpStream>> for TlsStream {
-    fn from(value: StreamOwned<ServerConnection, TcpStream>) -> Self {
-        Self::Server(value)
+    /// Force the delivery of all data sent to `to` that the transport still buffers.
+    ///
+    /// Call this at the end of a round after a batch of sends. The TCP, TLS, Unix and QUIC
+    /// transports flush the underlying stream, wrappers flush their inner network.
+    ///
+    /// The default implementation does nothing, for transports that deliver every message on send.
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        let _ = to;
+        Ok(())
     }
-}
 
-impl Read for TlsStream {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        match self {
-            TlsStream::Client(stream) => stream.read(buf),
-            TlsStream::Server(stream) => stream.read(buf),
+    /// Call [`Network::flush`] for all other parties.
+    fn flush_all(&self) -> Result<(), NetworkError> {
+        let id = self.id();
+        for other in (0..self.num_parties()).filter(|&other| other != id) {
+            self.flush(other)?;
         }
+        Ok(())
     }
-}
 
-impl Write for TlsStream {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        match self {
-            TlsStream::Client(stream) => stream.write(buf),
-            TlsStream::Server(stream) => stream.write(buf),
-        }
+    /// Flush the data sent to `to` and signal the end of the stream to it.
+    ///
+    /// Once the peer received all messages sent before, its receives fail with
+    /// [`NetworkError::PeerClosed`] instead of running into a timeout, so it can tell a party that
+    /// is done from one that crashed. This only closes the sending direction, messages from `to`
+    /// can still be received. The TCP and Unix transports shut down the writing half of the
+    /// socket, TLS sends a close notification first and QUIC finishes the stream.
+    ///
+    /// The default implementation only flushes, the peer notices the end once the network is dropped.
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        self.flush(to)
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        match self {
-            TlsStream::Client(stream) => stream.flush(),
-            TlsStream::Server(stream) => stream.flush(),
+    /// Call [`Network::close`] for all other parties, e.g., before recycling the network.
+    ///
+    /// All connections are closed even if closing one of them fails, the first error is returned.
+    fn shutdown(&self) -> Result<(), NetworkError> {
+        let id = self.id();
+        let mut result = Ok(());
+        for other in (0..self.num_parties()).filter(|&other| other != id) {
+            result = result.and(self.close(other));
         }
+        result
     }
-}
-
-#[derive(Debug)]
-pub struct TlsNetwork {
-    id: usize,
-    send: IntMap<usize, Mutex<TlsStream>>,
-    recv: IntMap<usize, Mutex<mpsc::Receiver<Vec<u8>>>>,
-}
 
-impl TlsNetwork {
-    pub fn networks<A: ToSocketAddrs>(
-        id: usize,
-        bind_addr: A,
-        addrs: &[Address],
-        certs: Vec<CertificateDer<'static>>,
-        key: PrivateKeyDer<'static>,
-        num: usize,
-    ) -> eyre::Result<Vec<Self>> {
-        tracing::debug!("creating new network");
+    /// Check that `peer` is alive by sending it a ping and waiting up to `timeout` for its answer.
+    ///
+    /// Returns the round-trip time, or fails with [`NetworkError::Timeout`] if the answer did not
+    /// arrive in time, e.g., because the connection is half-open. The ping is a control frame that
+    /// the reader thread of the peer answers in the background, so the peer does not have to
+    /// take part, and it is neither received as a message nor counted in the statistics or
+    /// transcripts. Pings between rounds detect a broken connection before a round depends on it.
+    ///
+    /// The default implementation returns an error.
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        let _ = timeout;
+        Err(eyre::eyre!("pinging party {peer} is not supported by this transport").into())
+    }
 
-        let mut root_store = RootCertStore::empty();
-        for cert in &certs {
-            root_store.add(cert.clone())?;
+    /// Send `data` to all other parties, does nothing with a single party.
+    fn broadcast(&self, data: &[u8]) -> Result<(), NetworkError> {
+        let id = self.id();
+        for other in (0..self.num_parties()).filter(|&other| other != id) {
+            self.send(other, data)?;
         }
-        let client_config = ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-
-        let server_config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(vec![certs[id].clone()], key)?;
+        Ok(())
+    }
 
-        let client_config = Arc::new(client_config);
-        let server_config = Arc::new(server_config);
+    /// Send `data` to the party `with` and receive its message, e.g., to swap shares.
+    ///
+    /// Both parties call this with each other's id. The default implementation sends before it
+    /// receives. This is fine for the TCP, TLS and test transports, which read incoming messages in
+    /// the background, but would deadlock if both sends blocked until the peer receives, e.g.,
+    /// because the messages exceed the socket buffers of a transport without background reads.
+    /// Such transports override this to overlap the send and the receive.
+    fn exchange(&self, with: usize, data: &[u8]) -> Result<Vec<u8>, NetworkError> {
+        self.send(with, data)?;
+        self.recv(with)
+    }
 
-        let listener = TcpListener::bind(bind_addr)?;
+    /// Receive one message from each other party in the order of their ids.
+    ///
+    /// The own id is skipped, so the message of party `j` is at index `j` if `j < id` and at
+    /// index `j - 1` otherwise. With a single party, this returns an empty vector.
+    fn recv_from_all(&self) -> Result<Vec<Vec<u8>>, NetworkError> {
+        let id = self.id();
+        (0..self.num_parties())
+            .filter(|&other| other != id)
+            .map(|other| self.recv(other))
+            .collect()
+    }
 
-        let mut nets = Vec::with_capacity(num);
-        for _ in 0..num {
-            nets.push(Self {
-                id,
-                send: IntMap::default(),
-                recv: IntMap::default(),
-            });
+    /// Send `data` to all other parties and wait until each of them acknowledged it.
+    ///
+    /// The other parties must receive the message with [`Network::recv_acked`]. The message is
+    /// sent to all parties before waiting for any acknowledgement, so a slow peer can't delay the
+    /// delivery to the others. Fails if a peer does not acknowledge within the receive timeout.
+    /// With a single party, this does nothing.
+    fn broadcast_acked(&self, data: &[u8]) -> Result<(), NetworkError> {
+        self.broadcast(data)?;
+        let id = self.id();
+        for other in (0..self.num_parties()).filter(|&other| other != id) {
+            if self.recv(other)? != [ACK] {
+                return Err(eyre::eyre!("party {other} sent an invalid acknowledgement").into());
+            }
         }
+        Ok(())
+    }
 
-        const STREAM_0: u8 = 0;
-        const STREAM_1: u8 = 1;
+    /// Receive a message sent with [`Network::broadcast_acked`] by `from` and acknowledge it.
+    fn recv_acked(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        let data = self.recv(from)?;
+        self.send(from, &[ACK])?;
+        Ok(data)
+    }
 
-        for i in 0..num {
-            for s in [STREAM_0, STREAM_1] {
-                for (other_id, addr) in addrs.iter().enumerate() {
-                    match id.cmp(&other_id) {
-                        Ordering::Less => {
-                            let stream = loop {
-                                if let Ok(stream) = TcpStream::connect(addr) {
-                                    break stream;
-                                }
-                                std::thread::sleep(Duration::from_millis(50));
-                            };
-                            stream.set_write_timeout(Some(TIMEOUT))?;
-                            stream.set_nodelay(true)?;
+    /// Send `data` to all other parties and check that every party received the same values.
+    ///
+    /// All parties must call this at the same time. After receiving the values of all other
+    /// parties, each party echoes them to everyone else and compares the echoes with its own view.
+    /// If a sender sent different values to different parties, the honest parties that received
+    /// different values detect this and return an error. This is weaker than a reliable broadcast,
+    /// as a party that detects equivocation aborts instead of agreeing on a value.
+    ///
+    /// Returns the value of each party indexed by party id, the entry for the own id is `None`.
+    /// With a single party, this returns `[None]` without any communication.
+    fn consistent_broadcast(&self, data: &[u8]) -> Result<Vec<Option<Vec<u8>>>, NetworkError> {
+        let id = self.id();
+        let num_parties = self.num_parties();
+        for other in (0..num_parties).filter(|&other| other != id) {
+            self.send(other, data)?;
+        }
+        let mut values = vec![None; num_parties];
+        for other in (0..num_parties).filter(|&other| other != id) {
+            values[other] = Some(self.recv(other)?);
+        }
 
-                            let name = ServerName::try_from(addr.hostname.clone())?.to_owned();
-                            let conn = ClientConnection::new(client_config.clone(), name.clone())?;
-                            let mut stream = StreamOwned::new(conn, stream);
+        // echo the values of all parties, including the own and the one of the receiver
+        let echo = encode_echo(&values, data)?;
+        for other in (0..num_parties).filter(|&other| other != id) {
+            self.send(other, &echo)?;
+        }
+        for other in (0..num_parties).filter(|&other| other != id) {
+            check_echo(id, other, &values, data, &self.recv(other)?)?;
+        }
+        Ok(values)
+    }
 
-                            stream.write_u64::<BigEndian>(i as u64)?;
-                            stream.write_u64::<BigEndian>(id as u64)?;
-                            stream.write_u8(s)?;
+    /// The histograms of the sizes of the messages sent to each peer, keyed by the peer id.
+    ///
+    /// Returns `None` if the transport does not record histograms, e.g., because they were not
+    /// enabled in its [`NetworkConfig`].
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        None
+    }
 
-                            if s == STREAM_0 {
-                                nets[i]
-                                    .send
-                                    .insert(other_id, Mutex::new(TlsStream::Client(stream)));
-                            } else {
-                                let (tx, rx) = mpsc::channel();
-                                std::thread::spawn(move || {
-                                    loop {
-                                        let len = stream.read_u32::<BigEndian>()? as usize;
-                                        let mut data = vec![0; len];
-                                        stream.read_exact(&mut data)?;
-                                        tx.send(data).unwrap();
-                                    }
-                                    #[allow(unreachable_code)]
-                                    eyre::Ok(())
-                                });
-                                nets[i].recv.insert(other_id, Mutex::new(rx));
-                            }
-                        }
-                        Ordering::Greater => {
-                            let (stream, _) = listener.accept()?;
-                            stream.set_write_timeout(Some(TIMEOUT))?;
-                            stream.set_nodelay(true)?;
+    /// The id of the next party in the ring, i.e., `(id + 1) % num_parties`.
+    ///
+    /// Messages travel "forward" from a party to its next party. With two parties, the next and
+    /// the previous party are the same peer. With a single party, it is the party itself and the
+    /// ring helpers fail, as there is no connection to oneself.
+    fn next_id(&self) -> usize {
+        (self.id() + 1) % self.num_parties()
+    }
 
-                            let conn = ServerConnection::new(server_config.clone())?;
-                            let mut stream = StreamOwned::new(conn, stream);
+    /// The id of the previous party in the ring, i.e., `(id + num_parties - 1) % num_parties`.
+    fn prev_id(&self) -> usize {
+        (self.id() + self.num_parties() - 1) % self.num_parties()
+    }
 
-                            let i = stream.read_u64::<BigEndian>()? as usize;
-                            let other_id = stream.read_u64::<BigEndian>()? as usize;
-                            let s_ = stream.read_u8()?;
-
-                            if s_ == STREAM_0 {
-                                let (tx, rx) = mpsc::channel();
-                                std::thread::spawn(move || {
-                                    loop {
-                                        let len = stream.read_u32::<BigEndian>()? as usize;
-                                        let mut data = vec![0; len];
-                                        stream.read_exact(&mut data)?;
-                                        tx.send(data).unwrap();
-                                    }
-                                    #[allow(unreachable_code)]
-                                    eyre::Ok(())
-                                });
-                                nets[i].recv.insert(other_id, Mutex::new(rx));
-                            } else {
-                                nets[i]
-                                    .send
-                                    .insert(other_id, Mutex::new(TlsStream::Server(stream)));
-                            }
-                        }
-                        Ordering::Equal => continue,
-                    }
-                }
-            }
-        }
+    /// Send data forward to the next party in the ring.
+    fn send_next(&self, data: &[u8]) -> Result<(), NetworkError> {
+        self.send(self.next_id(), data)
+    }
 
-        Ok(nets)
+    /// Receive data sent forward by the previous party in the ring.
+    ///
+    /// With two parties this reads from the same stream as [`Network::recv_next`], so messages
+    /// must be received in the order the peer sent them.
+    fn recv_prev(&self) -> Result<Vec<u8>, NetworkError> {
+        self.recv(self.prev_id())
     }
-}
 
-impl Network for TlsNetwork {
-    fn id(&self) -> usize {
-        self.id
+    /// Send data backward to the previous party in the ring.
+    fn send_prev(&self, data: &[u8]) -> Result<(), NetworkError> {
+        self.send(self.prev_id(), data)
     }
 
-    fn send(&self, to: usize, data: &[u8]) -> eyre::Result<()> {
-        let mut stream = self
-            .send
-            .get(to)
-            .context("while get stream in send")?
-            .lock();
-        stream.write_u32::<BigEndian>(data.len() as u32)?;
-        stream.write_all(data)?;
-        Ok(())
+    /// Receive data sent backward by the next party in the ring.
+    ///
+    /// With two parties this reads from the same stream as [`Network::recv_prev`], so messages
+    /// must be received in the order the peer sent them.
+    fn recv_next(&self) -> Result<Vec<u8>, NetworkError> {
+        self.recv(self.next_id())
     }
 
-    fn recv(&self, from: usize) -> eyre::Result<Vec<u8>> {
-        let queue = self
-            .recv
-            .get(from)
-            .context("while get stream in recv")?
-            .lock();
-        Ok(queue.recv_timeout(TIMEOUT)?)
+    /// Erase the concrete transport, e.g., to select it from a config at runtime.
+    ///
+    /// An `MpcEngine<Box<dyn Network>>` can then be used with any transport.
+    fn boxed(self) -> Box<dyn Network>
+    where
+        Self: Sized + 'static,
+    {
+        Box::new(self)
     }
 }
 
-#[derive(Debug)]
-pub struct TestNetwork {
-    id: usize,
-    send: IntMap<usize, mpsc::Sender<Vec<u8>>>,
-    recv: IntMap<usize, Mutex<mpsc::Receiver<Vec<u8>>>>,
-}
+/// Typed messages and chunked streams on top of the byte-oriented [`Network`], implemented for
+/// all networks.
+///
+/// Values are serialized with serde using the standard configuration of bincode 2, i.e.,
+/// little-endian with variable-length integers, so both parties agree on the format as long as
+/// they use the same types. Any other format can be used by serializing to bytes manually.
+pub trait NetworkExt: Network {
+    /// Serialize `value` and send it to the party `to`.
+    fn send_obj<T: Serialize>(&self, to: usize, value: &T) -> eyre::Result<()> {
+        let data = bincode::serde::encode_to_vec(value, bincode::config::standard())?;
+        Ok(self.send(to, &data)?)
+    }
 
-impl TestNetwork {
-    pub fn party_networks(num_parties: usize) -> Vec<Self> {
-        let mut networks = Vec::with_capacity(num_parties);
-        let mut senders = Vec::new();
-        let mut receivers = Vec::new();
+    /// Receive a message from the party `from` and deserialize it, failing on trailing bytes.
+    fn recv_obj<T: DeserializeOwned>(&self, from: usize) -> eyre::Result<T> {
+        let data = self.recv(from)?;
+        let (value, len) = bincode::serde::decode_from_slice(&data, bincode::config::standard())
+            .map_err(|err| {
+                eyre::Report::new(err)
+                    .wrap_err(format!("could not deserialize message from party {from}"))
+            })?;
+        if len != data.len() {
+            eyre::bail!(
+                "message from party {from} has {} trailing bytes",
+                data.len() - len
+            );
+        }
+        Ok(value)
+    }
 
-        for _ in 0..num_parties {
-            senders.push(IntMap::new());
-            receivers.push(IntMap::new());
+    /// Send `data` to the party `to` in chunks of [`STREAM_CHUNK_SIZE`] bytes, to be received
+    /// with [`NetworkExt::recv_stream`].
+    ///
+    /// The total length is sent first as big-endian `u64`, followed by one message per chunk.
+    /// `on_progress` is called with the number of bytes sent so far after every chunk. Every
+    /// chunk is a separate send, so a large buffer does not block other sends to the same peer
+    /// for the whole transfer and does not have to fit the maximum message size.
+    fn send_stream(
+        &self,
+        to: usize,
+        data: &[u8],
+        mut on_progress: impl FnMut(u64),
+    ) -> eyre::Result<()> {
+        self.send(to, &(data.len() as u64).to_be_bytes())?;
+        let mut sent = 0;
+        for chunk in data.chunks(STREAM_CHUNK_SIZE) {
+            self.send(to, chunk)?;
+            sent += chunk.len() as u64;
+            on_progress(sent);
         }
+        Ok(())
+    }
 
-        #[allow(clippy::needless_range_loop)]
-        for i in 0..num_parties {
-            for j in 0..num_parties {
-                if i != j {
-                    let (tx, rx) = mpsc::channel();
-                    senders[i].insert(j, tx);
-                    receivers[j].insert(i, Mutex::new(rx));
-                }
+    /// Receive the data sent with [`NetworkExt::send_stream`] by the party `from`.
+    ///
+    /// `on_progress` is called with the number of bytes received so far after every chunk. The
+    /// buffer grows with the received chunks instead of trusting the announced length, and a
+    /// chunk of the wrong length fails the receive.
+    fn recv_stream(&self, from: usize, mut on_progress: impl FnMut(u64)) -> eyre::Result<Vec<u8>> {
+        let header = self.recv(from)?;
+        let len = u64::from_be_bytes(header.as_slice().try_into().map_err(|_| {
+            eyre::eyre!(
+                "expected the 8 byte stream length from party {from}, got {} bytes",
+                header.len()
+            )
+        })?);
+        let mut data = Vec::new();
+        while (data.len() as u64) < len {
+            let chunk = self.recv(from)?;
+            let expected = (len - data.len() as u64).min(STREAM_CHUNK_SIZE as u64);
+            if chunk.len() as u64 != expected {
+                eyre::bail!(
+                    "expected a chunk of {expected} bytes from party {from}, got {}",
+                    chunk.len()
+                );
             }
+            data.extend_from_slice(&chunk);
+            on_progress(data.len() as u64);
         }
+        Ok(data)
+    }
+}
 
-        for (id, (send, recv)) in senders.into_iter().zip(receivers).enumerate() {
-            networks.push(TestNetwork { id, send, recv });
-        }
+impl<N: Network + ?Sized> NetworkExt for N {}
 
-        networks
+/// Encodes the values of all parties for [`Network::consistent_broadcast`], using `data` for the own.
+fn encode_echo(values: &[Option<Vec<u8>>], data: &[u8]) -> eyre::Result<Vec<u8>> {
+    let mut echo = Vec::new();
+    for value in values {
+        let value = value.as_deref().unwrap_or(data);
+        echo.write_u32::<BigEndian>(u32::try_from(value.len())?)?;
+        echo.extend_from_slice(value);
     }
+    Ok(echo)
+}
 
-    pub fn networks(num_parties: usize, num: usize) -> Vec<Vec<Self>> {
-        let mut nets = (0..num_parties)
-            .map(|_| Vec::with_capacity(num))
-            .collect::<Vec<Vec<_>>>();
-        for _ in 0..num {
-            for (party_id, net) in TestNetwork::party_networks(num_parties)
-                .into_iter()
-                .enumerate()
-            {
-                nets[party_id].push(net);
+/// Compares the echo of `other` with our view of the values of all parties.
+fn check_echo(
+    id: usize,
+    other: usize,
+    values: &[Option<Vec<u8>>],
+    data: &[u8],
+    theirs: &[u8],
+) -> Result<(), NetworkError> {
+    let mut rest = theirs;
+    for (j, value) in values.iter().enumerate() {
+        let len = rest
+            .read_u32::<BigEndian>()
+            .map_err(|_| NetworkError::TruncatedMessage(other))? as usize;
+        if rest.len() < len {
+            return Err(NetworkError::TruncatedMessage(other));
+        }
+        let echoed;
+        (echoed, rest) = rest.split_at(len);
+        if echoed != value.as_deref().unwrap_or(data) {
+            if j == id {
+                return Err(
+                    eyre::eyre!("party {other} received a different value than we sent").into(),
+                );
             }
+            return Err(eyre::eyre!(
+                "party {j} equivocated, party {other} received a different value"
+            )
+            .into());
         }
-        nets
     }
+    if !rest.is_empty() {
+        return Err(eyre::eyre!("party {other} sent an echo with trailing data").into());
+    }
+    Ok(())
 }
 
-impl Network for TestNetwork {
+impl<N: Network + ?Sized> Network for Box<N> {
     fn id(&self) -> usize {
-        self.id
+        (**self).id()
     }
 
-    fn send(&self, to: usize, data: &[u8]) -> eyre::Result<()> {
-        self.send
-            .get(to)
-            .context("while get stream in send")?
-            .send(data.to_owned())?;
-        Ok(())
+    fn num_parties(&self) -> usize {
+        (**self).num_parties()
     }
 
-    fn recv(&self, from: usize) -> eyre::Result<Vec<u8>> {
-        Ok(self
-            .recv
-            .get(from)
-            .context("while get stream in recv")?
-            .lock()
-            .recv_timeout(TIMEOUT)?)
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        (**self).send(to, data)
     }
-}
 
-#[derive(Clone, Copy)]
-pub struct DummyNetwork;
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        (**self).recv(from)
+    }
 
-impl DummyNetwork {
-    pub fn networks(num: usize) -> Vec<Self> {
-        vec![DummyNetwork; num]
+    fn peers(&self) -> Vec<usize> {
+        (**self).peers()
     }
-}
 
-impl Network for DummyNetwork {
-    fn id(&self) -> usize {
-        0
+    fn recv_into(&self, from: usize, buf: &mut Vec<u8>) -> Result<usize, NetworkError> {
+        (**self).recv_into(from, buf)
     }
 
-    fn send(&self, _to: usize, _data: &[u8]) -> eyre::Result<()> {
-        Ok(())
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        (**self).recv_timeout(from, timeout)
     }
 
-    fn recv(&self, _from: usize) -> eyre::Result<Vec<u8>> {
-        Ok(vec![])
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        (**self).resync_peer(id)
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        (**self).drain(from)
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        (**self).flush(to)
+    }
+
+    fn flush_all(&self) -> Result<(), NetworkError> {
+        (**self).flush_all()
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        (**self).close(to)
+    }
+
+    fn shutdown(&self) -> Result<(), NetworkError> {
+        (**self).shutdown()
+    }
+
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        (**self).ping(peer, timeout)
+    }
+
+    fn broadcast(&self, data: &[u8]) -> Result<(), NetworkError> {
+        (**self).broadcast(data)
+    }
+
+    fn exchange(&self, with: usize, data: &[u8]) -> Result<Vec<u8>, NetworkError> {
+        (**self).exchange(with, data)
+    }
+
+    fn recv_from_all(&self) -> Result<Vec<Vec<u8>>, NetworkError> {
+        (**self).recv_from_all()
+    }
+
+    fn broadcast_acked(&self, data: &[u8]) -> Result<(), NetworkError> {
+        (**self).broadcast_acked(data)
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        (**self).size_histogram()
+    }
+
+    fn recv_acked(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        (**self).recv_acked(from)
+    }
+
+    fn consistent_broadcast(&self, data: &[u8]) -> Result<Vec<Option<Vec<u8>>>, NetworkError> {
+        (**self).consistent_broadcast(data)
+    }
+
+    fn next_id(&self) -> usize {
+        (**self).next_id()
+    }
+
+    fn prev_id(&self) -> usize {
+        (**self).prev_id()
+    }
+
+    fn send_next(&self, data: &[u8]) -> Result<(), NetworkError> {
+        (**self).send_next(data)
+    }
+
+    fn recv_prev(&self) -> Result<Vec<u8>, NetworkError> {
+        (**self).recv_prev()
+    }
+
+    fn send_prev(&self, data: &[u8]) -> Result<(), NetworkError> {
+        (**self).send_prev(data)
+    }
+
+    fn recv_next(&self) -> Result<Vec<u8>, NetworkError> {
+        (**self).recv_next()
+    }
+}
+
+/// Configuration for the stream based transports.
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    /// Append a CRC32 checksum to every frame, all parties must use the same setting.
+    pub crc: bool,
+    /// Record a histogram of the sizes of the messages sent to each peer, see [`Network::size_histogram`].
+    pub histograms: bool,
+    /// Hash the frames exchanged with each peer, see [`TcpNetwork::transcript_hash`].
+    pub transcript_hash: bool,
+    /// Derive the id of a connecting [`TlsNetwork`] party from its client certificate.
+    ///
+    /// All parties must use the same setting. This is ignored by [`TcpNetwork`].
+    pub mutual_tls: bool,
+    /// Send the [`TlsNetwork`] handshake preamble as 0-RTT early data when resuming a session.
+    ///
+    /// Only the preamble is sent as early data and it is only used after the handshake completed,
+    /// so a replay never results in a connection. All parties must use the same setting.
+    pub early_data: Option<TlsSessionCache>,
+    /// Carry both directions of a [`TlsNetwork`] link over a single connection.
+    ///
+    /// All parties must use the same setting.
+    pub tls_multiplex: bool,
+    /// Disable Nagle's algorithm on [`TcpNetwork`] and [`TlsNetwork`] connections, on by default.
+    pub nodelay: bool,
+    /// Write the messages sent on [`TcpNetwork`] connections on a background thread per peer.
+    ///
+    /// An error writing a queued message is returned by the next operation on that peer, the
+    /// messages queued after it are lost.
+    pub send_queue: Option<SendQueue>,
+    /// The number of connections a [`TcpNetwork`] opens per peer and network, 1 by default.
+    ///
+    /// All parties must use the same setting. This is ignored by [`TlsNetwork`].
+    pub streams_per_peer: usize,
+    /// Send TCP keepalive probes on idle [`TcpNetwork`] and [`TlsNetwork`] connections.
+    pub keepalive: Option<TcpKeepalive>,
+    /// The maximum number of pending connections on the listener, the OS default if `None`.
+    pub accept_backlog: Option<i32>,
+    /// Set `SO_REUSEADDR` on the listener, on by default, ignored on Windows.
+    pub reuse_address: bool,
+    /// Set `SO_REUSEPORT` on the listener, the setup fails on platforms without it.
+    pub reuse_port: bool,
+    /// Drop accepted connections that do not complete the handshake within this time.
+    ///
+    /// Without a deadline, such a connection fails the setup or blocks it.
+    pub handshake_timeout: Option<Duration>,
+    /// How long a receive waits for a message before failing, 30 seconds by default.
+    pub read_timeout: Duration,
+    /// How long a send may block on a full socket buffer before failing, 30 seconds by default.
+    pub write_timeout: Duration,
+    /// The maximum length of a received message, 1 GiB by default.
+    pub max_message_size: usize,
+    /// The maximum number of attempts to connect to a peer, retry until it is reachable if `None`.
+    pub connect_attempts: Option<u32>,
+    /// The maximum delay between two attempts to connect to a peer, 2 seconds by default.
+    pub max_connect_backoff: Duration,
+    /// How long a single attempt to connect to a peer may take, 10 seconds by default.
+    pub connect_timeout: Duration,
+    /// Re-establish a broken [`TcpNetwork`] connection with up to this many attempts.
+    ///
+    /// Messages in flight when the connection broke are lost, see [`SequencedNetwork`].
+    pub reconnect_attempts: Option<u32>,
+    /// Connect to the parties with a higher id through a SOCKS5 proxy.
+    pub socks5_proxy: Option<Socks5Proxy>,
+    /// Report each connection established during the setup of a [`TcpNetwork`] or [`TlsNetwork`].
+    pub connect_progress: Option<ConnectProgress>,
+    /// The identity the [`TlsNetwork`] certificate of each party must have, indexed by party id.
+    ///
+    /// All parties must set this. This is ignored by [`TcpNetwork`].
+    pub peer_identities: Option<Vec<PeerIdentity>>,
+    /// The TLS protocol versions [`TlsNetwork`] connections may use, the rustls defaults if `None`.
+    pub tls_versions: Option<Vec<&'static SupportedProtocolVersion>>,
+    /// The cipher suites and key exchange groups of [`TlsNetwork`] connections, the process
+    /// default provider if `None`.
+    pub tls_provider: Option<Arc<CryptoProvider>>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            crc: false,
+            histograms: false,
+            transcript_hash: false,
+            mutual_tls: false,
+            early_data: None,
+            tls_multiplex: false,
+            nodelay: true,
+            send_queue: None,
+            streams_per_peer: 1,
+            keepalive: None,
+            accept_backlog: None,
+            reuse_address: true,
+            reuse_port: false,
+            handshake_timeout: None,
+            read_timeout: TIMEOUT,
+            write_timeout: TIMEOUT,
+            max_message_size: MAX_MESSAGE_SIZE,
+            connect_attempts: None,
+            max_connect_backoff: MAX_CONNECT_BACKOFF,
+            connect_timeout: CONNECT_TIMEOUT,
+            reconnect_attempts: None,
+            socks5_proxy: None,
+            connect_progress: None,
+            peer_identities: None,
+            tls_versions: None,
+            tls_provider: None,
+        }
+    }
+}
+
+/// The TCP keepalive probes of a connection, see [`NetworkConfig::keepalive`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// How long a connection has to be idle before the first probe is sent.
+    pub time: Duration,
+    /// The time between two unanswered probes, the OS default if `None`.
+    ///
+    /// This is only supported on Linux, Android, macOS, iOS, FreeBSD, NetBSD and Windows.
+    pub interval: Option<Duration>,
+    /// The number of unanswered probes after which the connection fails, the OS default if
+    /// `None`.
+    ///
+    /// This is only supported on Linux, Android, macOS, iOS, FreeBSD and NetBSD.
+    pub retries: Option<u32>,
+}
+
+impl TcpKeepalive {
+    fn to_socket2(self) -> socket2::TcpKeepalive {
+        let keepalive = socket2::TcpKeepalive::new().with_time(self.time);
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            windows,
+        ))]
+        let keepalive = match self.interval {
+            Some(interval) => keepalive.with_interval(interval),
+            None => keepalive,
+        };
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+        ))]
+        let keepalive = match self.retries {
+            Some(retries) => keepalive.with_retries(retries),
+            None => keepalive,
+        };
+        keepalive
+    }
+}
+
+/// The queue of the background writer of a connection, see [`NetworkConfig::send_queue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendQueue {
+    /// Queue any number of messages.
+    Unbounded,
+    /// Block a send while this many messages are queued.
+    Bounded(usize),
+}
+
+/// The TLS sessions that [`TlsNetwork`] connections can resume to send early data.
+///
+/// Cloning is cheap and all clones share the same sessions.
+#[derive(Debug, Clone)]
+pub struct TlsSessionCache {
+    client: Arc<ClientSessionMemoryCache>,
+    server: Arc<ServerSessionMemoryCache>,
+}
+
+impl TlsSessionCache {
+    /// Create a cache holding up to `size` sessions each for outgoing and incoming connections.
+    pub fn new(size: usize) -> Self {
+        Self {
+            client: Arc::new(ClientSessionMemoryCache::new(size)),
+            server: ServerSessionMemoryCache::new(size),
+        }
+    }
+}
+
+impl Default for TlsSessionCache {
+    fn default() -> Self {
+        Self::new(256)
+    }
+}
+
+/// A callback reporting the connections established during a setup, see
+/// [`NetworkConfig::connect_progress`].
+///
+/// It is called with the id of the peer, the index of the connection among the ones to the
+/// peer, the number of connections established so far, and the total number of connections of
+/// the setup. The calls are serialized, so the counts are increasing, but the callback is called
+/// from the threads of the setup and should return quickly. Cloning is cheap and all clones
+/// share the same callback.
+#[derive(Clone)]
+pub struct ConnectProgress(Arc<dyn Fn(usize, usize, usize, usize) + Send + Sync>);
+
+impl ConnectProgress {
+    /// Call `callback` with `(peer_id, stream_index, established_count, total)` for each
+    /// established connection.
+    pub fn new(callback: impl Fn(usize, usize, usize, usize) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+}
+
+impl std::fmt::Debug for ConnectProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectProgress").finish_non_exhaustive()
+    }
+}
+
+/// Counts the connections of a setup for the [`NetworkConfig::connect_progress`] callback.
+struct SetupProgress<'a> {
+    callback: Option<&'a ConnectProgress>,
+    total: usize,
+    established: Mutex<usize>,
+}
+
+impl<'a> SetupProgress<'a> {
+    fn new(config: &'a NetworkConfig, total: usize) -> Self {
+        Self {
+            callback: config.connect_progress.as_ref(),
+            total,
+            established: Mutex::new(0),
+        }
+    }
+
+    /// Reports that the connection `stream` to `peer` is established.
+    fn established(&self, peer: usize, stream: usize) {
+        if let Some(callback) = self.callback {
+            let mut established = self.established.lock();
+            *established += 1;
+            (callback.0)(peer, stream, *established, self.total);
+        }
+    }
+}
+
+/// A SOCKS5 proxy for outgoing connections, see [`NetworkConfig::socks5_proxy`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    /// The address of the proxy.
+    pub address: Address,
+    /// The username and password to authenticate with (RFC 1929), no authentication if `None`.
+    pub credentials: Option<(String, String)>,
+}
+
+impl std::fmt::Debug for Socks5Proxy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // never log the password
+        f.debug_struct("Socks5Proxy")
+            .field("address", &self.address)
+            .field(
+                "username",
+                &self.credentials.as_ref().map(|(username, _)| username),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Socks5Proxy {
+    /// Connects to `target` through the proxy, waiting up to `timeout` for the proxy to connect
+    /// and for each of its replies.
+    fn connect(&self, target: &Address, timeout: Duration) -> std::io::Result<TcpStream> {
+        const VERSION: u8 = 5;
+        const NO_AUTH: u8 = 0;
+        const USERNAME_PASSWORD: u8 = 2;
+        const CONNECT: u8 = 1;
+
+        let mut stream = connect_timeout(&self.address, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        let method = match self.credentials {
+            Some(_) => USERNAME_PASSWORD,
+            None => NO_AUTH,
+        };
+        stream.write_all(&[VERSION, 1, method])?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a SOCKS5 proxy", self.address),
+            ));
+        }
+        if reply[1] != method {
+            return Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "SOCKS5 proxy does not accept the authentication method",
+            ));
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            let mut request = vec![1];
+            for field in [username, password] {
+                let len = u8::try_from(field.len()).map_err(|_| {
+                    std::io::Error::new(ErrorKind::InvalidInput, "SOCKS5 credentials are too long")
+                })?;
+                request.push(len);
+                request.extend_from_slice(field.as_bytes());
+            }
+            stream.write_all(&request)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "SOCKS5 proxy rejected the credentials",
+                ));
+            }
+        }
+
+        let mut request = vec![VERSION, CONNECT, 0];
+        match target.hostname.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            // let the proxy resolve the hostname
+            Err(_) => {
+                let len = u8::try_from(target.hostname.len()).map_err(|_| {
+                    std::io::Error::new(ErrorKind::InvalidInput, "hostname is too long for SOCKS5")
+                })?;
+                request.push(3);
+                request.push(len);
+                request.extend_from_slice(target.hostname.as_bytes());
+            }
+        }
+        request.write_u16::<BigEndian>(target.port)?;
+        stream.write_all(&request)?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        match reply[1] {
+            0 => {}
+            5 => return Err(ErrorKind::ConnectionRefused.into()),
+            code => {
+                let reason = match code {
+                    1 => "general failure",
+                    2 => "connection not allowed by ruleset",
+                    3 => "network unreachable",
+                    4 => "host unreachable",
+                    6 => "TTL expired",
+                    7 => "command not supported",
+                    8 => "address type not supported",
+                    _ => "unknown error",
+                };
+                return Err(std::io::Error::other(format!(
+                    "SOCKS5 proxy could not connect to {target}: {reason}"
+                )));
+            }
+        }
+        // skip the address the proxy bound for the connection
+        let len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8()? as usize,
+            atyp => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("SOCKS5 proxy replied with unknown address type {atyp}"),
+                ));
+            }
+        };
+        std::io::copy(
+            &mut (&mut stream).take(len as u64 + 2),
+            &mut std::io::sink(),
+        )?;
+        stream.set_read_timeout(None)?;
+        Ok(stream)
+    }
+}
+
+/// Connects to `addr` like [`TcpStream::connect`], trying every address the hostname resolves to
+/// in order, but gives up on each after `timeout`.
+fn connect_timeout(addr: &Address, timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "address did not resolve")))
+}
+
+/// The identity the certificate of a party must have, see [`NetworkConfig::peer_identities`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerIdentity {
+    /// The certificate must be valid for this DNS name or IP address.
+    Name(String),
+    /// The SHA-256 hash of the DER encoded certificate.
+    Fingerprint([u8; 32]),
+}
+
+impl PeerIdentity {
+    /// The identity matching exactly the certificate `cert`.
+    pub fn fingerprint(cert: &CertificateDer<'_>) -> Self {
+        Self::Fingerprint(sha256(cert))
+    }
+
+    /// Checks that the certificate chain `chain` the party `id` presented has this identity.
+    fn check(&self, chain: Option<&[CertificateDer<'_>]>, id: usize) -> eyre::Result<()> {
+        let cert = chain
+            .and_then(|chain| chain.first())
+            .with_context(|| format!("party {id} did not present a certificate"))?;
+        let matches = match self {
+            PeerIdentity::Name(name) => {
+                let name = ServerName::try_from(name.as_str())?;
+                verify_server_name(&ParsedCertificate::try_from(cert)?, &name).is_ok()
+            }
+            PeerIdentity::Fingerprint(fingerprint) => sha256(cert) == *fingerprint,
+        };
+        if !matches {
+            eyre::bail!("the certificate of party {id} does not match its identity {self}");
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for PeerIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerIdentity::Name(name) => write!(f, "name {name}"),
+            PeerIdentity::Fingerprint(fingerprint) => {
+                write!(f, "fingerprint ")?;
+                fingerprint.iter().try_for_each(|b| write!(f, "{b:02x}"))
+            }
+        }
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let suite = rustls::crypto::aws_lc_rs::cipher_suite::TLS13_AES_128_GCM_SHA256
+        .tls13()
+        .expect("is a TLS 1.3 suite");
+    let hash = suite.common.hash_provider.hash(data);
+    hash.as_ref().try_into().expect("is a SHA-256 hash")
+}
+
+impl NetworkConfig {
+    fn codec(&self) -> Codec {
+        Codec {
+            crc: self.crc,
+            max_message_size: self.max_message_size,
+        }
+    }
+
+    fn tls_crypto_provider(&self) -> Arc<CryptoProvider> {
+        self.tls_provider
+            .clone()
+            .or_else(|| CryptoProvider::get_default().cloned())
+            .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+    }
+
+    fn tls_versions(&self) -> &[&'static SupportedProtocolVersion] {
+        self.tls_versions
+            .as_deref()
+            .unwrap_or(rustls::DEFAULT_VERSIONS)
+    }
+
+    fn tls_client_builder(&self) -> eyre::Result<ConfigBuilder<ClientConfig, WantsVerifier>> {
+        Ok(match &self.tls_provider {
+            Some(provider) => ClientConfig::builder_with_provider(provider.clone())
+                .with_protocol_versions(self.tls_versions())?,
+            None => ClientConfig::builder_with_protocol_versions(self.tls_versions()),
+        })
+    }
+
+    fn tls_server_builder(&self) -> eyre::Result<ConfigBuilder<ServerConfig, WantsVerifier>> {
+        Ok(match &self.tls_provider {
+            Some(provider) => ServerConfig::builder_with_provider(provider.clone())
+                .with_protocol_versions(self.tls_versions())?,
+            None => ServerConfig::builder_with_protocol_versions(self.tls_versions()),
+        })
+    }
+
+    /// The rustls configurations for the party `id`, trusting exactly the party certificates.
+    fn tls_configs(
+        &self,
+        id: usize,
+        num_parties: usize,
+        certs: &[CertificateDer<'static>],
+        key: PrivateKeyDer<'static>,
+    ) -> eyre::Result<(ClientConfig, ServerConfig, Arc<RootCertStore>)> {
+        if id >= num_parties {
+            eyre::bail!("party id {id} is out of range for {num_parties} parties");
+        }
+        if certs.len() < num_parties {
+            eyre::bail!(
+                "expected a certificate for each of the {num_parties} parties, but got {}",
+                certs.len()
+            );
+        }
+
+        let mut root_store = RootCertStore::empty();
+        for cert in certs {
+            root_store.add(cert.clone())?;
+        }
+        if let Some(identities) = &self.peer_identities
+            && identities.len() != num_parties
+        {
+            eyre::bail!(
+                "expected an identity for each of the {num_parties} parties, but got {}",
+                identities.len()
+            );
+        }
+        let root_store = Arc::new(root_store);
+        let client_auth = self.mutual_tls || self.peer_identities.is_some();
+        let (client_config, server_config) = if client_auth {
+            let verifier = WebPkiClientVerifier::builder(root_store.clone()).build()?;
+            let client_config = self
+                .tls_client_builder()?
+                .with_root_certificates(root_store.clone())
+                .with_client_auth_cert(vec![certs[id].clone()], key.clone_key())?;
+            let server_config = self
+                .tls_server_builder()?
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(vec![certs[id].clone()], key)?;
+            (client_config, server_config)
+        } else {
+            let client_config = self
+                .tls_client_builder()?
+                .with_root_certificates(root_store.clone())
+                .with_no_client_auth();
+            let server_config = self
+                .tls_server_builder()?
+                .with_no_client_auth()
+                .with_single_cert(vec![certs[id].clone()], key)?;
+            (client_config, server_config)
+        };
+        Ok((client_config, server_config, root_store))
+    }
+
+    fn bind<A: ToSocketAddrs>(&self, bind_addr: A) -> eyre::Result<TcpListener> {
+        if self.accept_backlog.is_none() && self.reuse_address && !self.reuse_port {
+            return Ok(TcpListener::bind(bind_addr)?);
+        }
+        let addr = bind_addr
+            .to_socket_addrs()?
+            .next()
+            .context("bind address did not resolve")?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        #[cfg(not(windows))]
+        socket.set_reuse_address(self.reuse_address)?;
+        #[cfg(all(
+            unix,
+            not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+        ))]
+        socket.set_reuse_port(self.reuse_port)?;
+        #[cfg(not(all(
+            unix,
+            not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+        )))]
+        if self.reuse_port {
+            eyre::bail!("SO_REUSEPORT is not supported on this platform");
+        }
+        socket.bind(&addr.into())?;
+        // the backlog of TcpListener::bind
+        socket.listen(self.accept_backlog.unwrap_or(128))?;
+        Ok(socket.into())
+    }
+
+    /// Sets the socket options of a [`TcpNetwork`] or [`TlsNetwork`] connection.
+    fn configure_stream(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_write_timeout(Some(self.write_timeout))?;
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&keepalive.to_socket2())?;
+        }
+        Ok(())
+    }
+
+    /// Connects party `id` to the party `peer` at `addr`, retrying with an exponential backoff.
+    fn connect(&self, id: usize, peer: usize, addr: &Address) -> eyre::Result<TcpStream> {
+        self.connect_with(id, peer, addr, || self.connect_once(addr))
+    }
+
+    /// Makes a single attempt to connect to `addr`, through the proxy if there is one.
+    fn connect_once(&self, addr: &Address) -> std::io::Result<TcpStream> {
+        match &self.socks5_proxy {
+            Some(proxy) => proxy.connect(addr, self.connect_timeout),
+            None => connect_timeout(addr, self.connect_timeout),
+        }
+    }
+
+    /// Calls `connect` of party `id` to `peer` until it succeeds, with an exponential backoff
+    /// between the attempts.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    fn connect_with<S>(
+        &self,
+        id: usize,
+        peer: usize,
+        addr: impl std::fmt::Display,
+        connect: impl Fn() -> std::io::Result<S>,
+    ) -> eyre::Result<S> {
+        let mut backoff = CONNECT_BACKOFF;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let err = match connect() {
+                Ok(stream) => return Ok(stream),
+                Err(err) => err,
+            };
+            if self.connect_attempts.is_some_and(|max| attempts >= max) {
+                return Err(eyre::Report::new(err).wrap_err(format!(
+                    "could not connect to party {peer} at {addr} after {attempts} attempts"
+                )));
+            }
+            tracing::trace!("connecting to party {peer} at {addr} failed, retrying: {err}");
+            #[cfg(feature = "metrics")]
+            crate::metrics::connect_retry(id, peer);
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(self.max_connect_backoff);
+        }
+    }
+
+    /// Accepts connections until `handshake` succeeds on one of them.
+    ///
+    /// The handshake runs with the configured deadline as read timeout and is responsible for
+    /// clearing it once done.
+    fn accept<T>(
+        &self,
+        listener: &TcpListener,
+        mut handshake: impl FnMut(TcpStream) -> eyre::Result<T>,
+    ) -> eyre::Result<T> {
+        loop {
+            let (stream, addr) = listener.accept()?;
+            stream.set_read_timeout(self.handshake_timeout)?;
+            match handshake(stream) {
+                Ok(res) => return Ok(res),
+                Err(err) if self.handshake_timeout.is_some() => {
+                    tracing::warn!("dropping connection from {addr}: {err:?}");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn peer_stats(&self, id: usize, num_parties: usize) -> IntMap<usize, PeerStats> {
+        (0..num_parties)
+            .filter(|&other_id| other_id != id)
+            .map(|other_id| (other_id, PeerStats::new(id, other_id, self.histograms)))
+            .collect()
+    }
+
+    fn peer_transcripts(&self, id: usize, num_parties: usize) -> IntMap<usize, TranscriptHasher> {
+        if !self.transcript_hash {
+            return IntMap::default();
+        }
+        (0..num_parties)
+            .filter(|&other_id| other_id != id)
+            .map(|other_id| (other_id, TranscriptHasher::new(id, other_id)))
+            .collect()
+    }
+}
+
+/// An error that can occur while sending or receiving.
+///
+/// Errors of other crates, e.g., from a closure passed to the engine, convert into an
+/// [`eyre::Report`] with `?`, and a [`NetworkError`] inside a report can be recovered with
+/// [`eyre::Report::downcast_ref`].
+#[derive(Debug)]
+pub enum NetworkError {
+    /// A frame from the given party failed its integrity check
+    Corrupt(usize),
+    /// A message from the given party exceeds the maximum message size
+    MessageTooLarge { peer: usize, len: usize, max: usize },
+    /// A send to or receive from the given party did not complete in time
+    Timeout(usize),
+    /// The connection to the given party was closed
+    PeerClosed(usize),
+    /// The connection to the given party was closed in the middle of a message
+    TruncatedMessage(usize),
+    /// There is no connection to the given party id
+    UnknownPeer(usize),
+    /// The given party started a resync, see [`Network::resync_peer`]
+    Resync(usize),
+    /// Any other I/O error
+    Io(std::io::Error),
+    /// Any other error, e.g., a protocol violation detected by a wrapper
+    Other(eyre::Report),
+}
+
+impl NetworkError {
+    /// Classifies an I/O error on the connection to `peer`.
+    pub(crate) fn from_io(peer: usize, err: std::io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => NetworkError::Timeout(peer),
+            ErrorKind::UnexpectedEof
+            | ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted => NetworkError::PeerClosed(peer),
+            _ => NetworkError::Io(err),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NetworkError::Io(err) => Some(err),
+            NetworkError::Other(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkError::Corrupt(peer) => write!(f, "received corrupt frame from party {peer}"),
+            NetworkError::MessageTooLarge { peer, len, max } => write!(
+                f,
+                "message of {len} bytes from party {peer} exceeds the maximum size of {max} bytes"
+            ),
+            NetworkError::Timeout(peer) => write!(f, "timed out on the connection to party {peer}"),
+            NetworkError::PeerClosed(peer) => write!(f, "party {peer} closed the connection"),
+            NetworkError::TruncatedMessage(peer) => {
+                write!(
+                    f,
+                    "party {peer} closed the connection in the middle of a message"
+                )
+            }
+            NetworkError::UnknownPeer(peer) => write!(f, "no connection to party {peer}"),
+            NetworkError::Resync(peer) => write!(f, "party {peer} started a resync"),
+            NetworkError::Io(err) => write!(f, "{err}"),
+            NetworkError::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for NetworkError {
+    fn from(err: std::io::Error) -> Self {
+        NetworkError::Io(err)
+    }
+}
+
+impl From<eyre::Report> for NetworkError {
+    fn from(err: eyre::Report) -> Self {
+        // unwrap a network error that was converted into a report, e.g., by a wrapper
+        match err.downcast::<NetworkError>() {
+            Ok(err) => err,
+            Err(err) => NetworkError::Other(err),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TcpNetwork {
+    id: usize,
+    num_parties: usize,
+    /// The index of this network among the networks of the party, sent in the id handshake.
+    index: usize,
+    codec: Codec,
+    read_timeout: Duration,
+    peers: IntMap<usize, TcpPeer>,
+    stats: IntMap<usize, PeerStats>,
+    /// Whether the stats record histograms, see [`NetworkConfig::histograms`].
+    histograms: bool,
+    transcripts: IntMap<usize, TranscriptHasher>,
+    reconnector: Option<Arc<Reconnector>>,
+}
+
+/// The length of the sequence number appended to messages sent over multiple streams.
+const STRIPE_SEQ_LEN: usize = 8;
+
+/// The sending half of a [`TcpNetwork`] connection, see [`NetworkConfig::send_queue`].
+#[derive(Debug)]
+enum TcpSender {
+    Direct(Mutex<TcpStream>),
+    Queued(FrameWriter),
+}
+
+impl TcpSender {
+    fn new(config: &NetworkConfig, codec: Codec, stream: TcpStream) -> Self {
+        match config.send_queue {
+            Some(queue) => {
+                let capacity = match queue {
+                    SendQueue::Unbounded => None,
+                    SendQueue::Bounded(capacity) => Some(capacity),
+                };
+                Self::Queued(
+                    codec.spawn_writer(stream, capacity, |stream| stream.shutdown(Shutdown::Write)),
+                )
+            }
+            None => Self::Direct(Mutex::new(stream)),
+        }
+    }
+
+    fn ping(&self, codec: &Codec, nonce: u64) -> std::io::Result<()> {
+        match self {
+            TcpSender::Direct(stream) => codec.write_ping(&mut *stream.lock(), nonce),
+            TcpSender::Queued(writer) => writer.ping(nonce),
+        }
+    }
+
+    fn pong(&self, codec: &Codec, nonce: u64) -> std::io::Result<()> {
+        match self {
+            TcpSender::Direct(stream) => codec.write_pong(&mut *stream.lock(), nonce),
+            TcpSender::Queued(writer) => writer.pong(nonce),
+        }
+    }
+
+    fn send(&self, codec: &Codec, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            TcpSender::Direct(stream) => codec.write_frame(&mut *stream.lock(), data),
+            TcpSender::Queued(writer) => writer.send(data.to_vec()),
+        }
+    }
+
+    fn resync(&self, codec: &Codec) -> std::io::Result<()> {
+        match self {
+            TcpSender::Direct(stream) => codec.write_resync(&mut *stream.lock()),
+            TcpSender::Queued(writer) => writer.resync(),
+        }
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        match self {
+            TcpSender::Direct(stream) => stream.lock().flush(),
+            TcpSender::Queued(writer) => writer.flush(),
+        }
+    }
+
+    fn close(&self) -> std::io::Result<()> {
+        match self {
+            TcpSender::Direct(stream) => {
+                let mut stream = stream.lock();
+                stream
+                    .flush()
+                    .and_then(|_| stream.shutdown(Shutdown::Write))
+            }
+            TcpSender::Queued(writer) => writer.close(),
+        }
+    }
+}
+
+/// The first bytes on a [`TcpNetwork`] connection, sent by the connecting party.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct TcpPreamble {
+    /// The index of the network among the networks of the party.
+    index: usize,
+    /// The id of the connecting party.
+    id: usize,
+    /// The index of the connection among the streams to the peer.
+    stream: usize,
+}
+
+impl TcpPreamble {
+    /// Writes the preamble of a connection to `peer` and checks the codec flags it echoes.
+    fn write(
+        &self,
+        stream: &mut TcpStream,
+        peer: usize,
+        config: &NetworkConfig,
+    ) -> eyre::Result<()> {
+        stream.write_u64::<BigEndian>(self.index as u64)?;
+        stream.write_u64::<BigEndian>(self.id as u64)?;
+        stream.write_u8(config.codec().flags())?;
+        stream.write_u32::<BigEndian>(self.stream as u32)?;
+        stream.write_u32::<BigEndian>(config.streams_per_peer as u32)?;
+        config.codec().check_flags(peer, stream.read_u8()?)
+    }
+
+    /// Reads the preamble of a connection accepted by party `id` and checks that the connecting
+    /// party uses the same settings.
+    fn read(stream: &mut TcpStream, id: usize, config: &NetworkConfig) -> eyre::Result<Self> {
+        let index = stream.read_u64::<BigEndian>()? as usize;
+        let other_id = stream.read_u64::<BigEndian>()? as usize;
+        let flags = stream.read_u8()?;
+        let s = stream.read_u32::<BigEndian>()? as usize;
+        let streams = stream.read_u32::<BigEndian>()? as usize;
+        // echo our flags, so a connecting party with other flags fails as well
+        stream.write_u8(config.codec().flags())?;
+        // only parties with a lower id connect to us
+        if other_id >= id {
+            eyre::bail!("party {other_id} must not connect to party {id}");
+        }
+        config.codec().check_flags(other_id, flags)?;
+        if streams != config.streams_per_peer {
+            eyre::bail!(
+                "party {other_id} uses {streams} streams per peer, but we use {}",
+                config.streams_per_peer
+            );
+        }
+        if s >= streams {
+            eyre::bail!("party {other_id} requested stream {s} of {streams}");
+        }
+        Ok(Self {
+            index,
+            id: other_id,
+            stream: s,
+        })
+    }
+}
+
+/// The connections of a [`TcpNetwork`] to a single peer, see [`NetworkConfig::streams_per_peer`].
+#[derive(Debug)]
+struct TcpPeer {
+    conns: Vec<RwLock<TcpConn>>,
+    /// The sequence numbers of the next message sent and received, only used with multiple
+    /// streams.
+    send_seq: Mutex<u64>,
+    recv_seq: Mutex<u64>,
+}
+
+impl TcpPeer {
+    fn striped(&self) -> bool {
+        self.conns.len() > 1
+    }
+
+    /// The stream the message with the sequence number `seq` is sent on.
+    fn stream(&self, seq: u64) -> usize {
+        (seq % self.conns.len() as u64) as usize
+    }
+}
+
+/// Checks the sequence number at the end of a message `frame` from `from` received over
+/// multiple streams.
+///
+/// Returns the length of the message without the sequence number if it is the `next` one, and
+/// `None` for a message that was sent before the last [`Network::drain`].
+fn check_stripe_seq(
+    from: usize,
+    next: &mut u64,
+    frame: &[u8],
+) -> Result<Option<usize>, NetworkError> {
+    let Some(len) = frame.len().checked_sub(STRIPE_SEQ_LEN) else {
+        return Err(NetworkError::Corrupt(from));
+    };
+    let seq = u64::from_be_bytes(frame[len..].try_into().expect("8 bytes"));
+    if seq < *next {
+        return Ok(None);
+    }
+    if seq > *next {
+        return Err(eyre::eyre!(
+            "message {seq} from party {from} is out of order, expected message {next}"
+        )
+        .into());
+    }
+    *next += 1;
+    Ok(Some(len))
+}
+
+/// A single connection of a [`TcpNetwork`] to a peer.
+#[derive(Debug)]
+struct TcpConn {
+    /// Counts the reconnects, so concurrent operations that failed only reconnect once.
+    generation: u64,
+    /// The socket, to shut it down before reconnecting.
+    stream: TcpStream,
+    /// Shared with the thread answering the pings of the peer.
+    sender: Arc<TcpSender>,
+    reader: FrameReader,
+}
+
+impl TcpConn {
+    fn new(
+        config: &NetworkConfig,
+        codec: Codec,
+        stream: TcpStream,
+        peer: usize,
+        generation: u64,
+    ) -> std::io::Result<Self> {
+        let sender = Arc::new(TcpSender::new(config, codec, stream.try_clone()?));
+        let reader = codec.spawn_reader(stream.try_clone()?, peer);
+        let pong_sender = Arc::downgrade(&sender);
+        reader.answer_pings(move |nonce| {
+            let sender = pong_sender.upgrade().ok_or(ErrorKind::BrokenPipe)?;
+            sender.pong(&codec, nonce)
+        });
+        Ok(Self {
+            generation,
+            stream,
+            sender,
+            reader,
+        })
+    }
+}
+
+/// What the networks of a party need to re-establish failed connections, see
+/// [`NetworkConfig::reconnect_attempts`].
+#[derive(Debug)]
+struct Reconnector {
+    config: NetworkConfig,
+    attempts: u32,
+    addrs: Vec<Address>,
+    /// The listener of the setup, non-blocking so a reconnect can give up waiting.
+    listener: TcpListener,
+    /// Accepted connections of other networks or streams of the party.
+    pending: Mutex<HashMap<TcpPreamble, TcpStream>>,
+}
+
+impl Reconnector {
+    /// Re-establishes the connection `stream` of the network `index` of party `id` to `peer`.
+    ///
+    /// Like during the setup, the party with the lower id connects and the other one accepts.
+    fn reconnect(
+        &self,
+        id: usize,
+        index: usize,
+        peer: usize,
+        stream: usize,
+    ) -> Result<TcpStream, NetworkError> {
+        let mut backoff = CONNECT_BACKOFF;
+        for attempt in 1..=self.attempts {
+            let res = if id < peer {
+                self.connect(TcpPreamble { index, id, stream }, peer)
+            } else {
+                self.accept(
+                    id,
+                    TcpPreamble {
+                        index,
+                        id: peer,
+                        stream,
+                    },
+                )
+            };
+            match res {
+                Ok(stream) => {
+                    tracing::info!("reconnected to party {peer} after {attempt} attempts");
+                    return Ok(stream);
+                }
+                Err(err) => tracing::debug!("reconnecting to party {peer} failed: {err:?}"),
+            }
+            if attempt == self.attempts {
+                break;
+            }
+            #[cfg(feature = "metrics")]
+            crate::metrics::connect_retry(id, peer);
+            if id < peer {
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(self.config.max_connect_backoff);
+            }
+        }
+        Err(NetworkError::PeerClosed(peer))
+    }
+
+    fn connect(&self, preamble: TcpPreamble, peer: usize) -> eyre::Result<TcpStream> {
+        let mut stream = self.config.connect_once(&self.addrs[peer])?;
+        self.config.configure_stream(&stream)?;
+        preamble.write(&mut stream, peer, &self.config)?;
+        Ok(stream)
+    }
+
+    /// Waits up to the connect timeout for the peer to connect with `expected`, keeping the
+    /// other connections of the peer for the other networks or streams.
+    fn accept(&self, id: usize, expected: TcpPreamble) -> eyre::Result<TcpStream> {
+        let deadline = Instant::now() + self.config.connect_timeout;
+        loop {
+            if let Some(stream) = self.pending.lock().remove(&expected) {
+                return Ok(stream);
+            }
+            match self.listener.accept() {
+                Ok((stream, addr)) => match self.handshake(id, stream) {
+                    Ok((stream, preamble)) if preamble == expected => return Ok(stream),
+                    Ok((stream, preamble)) => {
+                        self.pending.lock().insert(preamble, stream);
+                    }
+                    Err(err) => tracing::warn!("dropping connection from {addr}: {err:?}"),
+                },
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        eyre::bail!("party {} did not reconnect in time", expected.id);
+                    }
+                    std::thread::sleep(CONNECT_BACKOFF);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn handshake(
+        &self,
+        id: usize,
+        mut stream: TcpStream,
+    ) -> eyre::Result<(TcpStream, TcpPreamble)> {
+        // the accepted socket may inherit the non-blocking mode of the listener
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(
+            self.config
+                .handshake_timeout
+                .unwrap_or(self.config.connect_timeout),
+        ))?;
+        self.config.configure_stream(&stream)?;
+        let preamble = TcpPreamble::read(&mut stream, id, &self.config)?;
+        stream.set_read_timeout(None)?;
+        Ok((stream, preamble))
+    }
+}
+
+impl TcpNetwork {
+    pub fn networks<A: ToSocketAddrs>(
+        id: usize,
+        bind_addr: A,
+        addrs: &[Address],
+        num: usize,
+    ) -> eyre::Result<Vec<Self>> {
+        Self::networks_with_config(id, bind_addr, addrs, num, NetworkConfig::default())
+    }
+
+    pub fn networks_with_config<A: ToSocketAddrs>(
+        id: usize,
+        bind_addr: A,
+        addrs: &[Address],
+        num: usize,
+        config: NetworkConfig,
+    ) -> eyre::Result<Vec<Self>> {
+        tracing::debug!("creating new network");
+        if id >= addrs.len() {
+            eyre::bail!("party id {id} is out of range for {} parties", addrs.len());
+        }
+        let streams_per_peer = config.streams_per_peer;
+        if streams_per_peer == 0 || streams_per_peer > u32::MAX as usize {
+            eyre::bail!("invalid number of streams per peer: {streams_per_peer}");
+        }
+        let codec = config.codec();
+        let listener = config.bind(bind_addr)?;
+
+        let mut nets = Vec::with_capacity(num);
+        for index in 0..num {
+            nets.push(Self {
+                id,
+                num_parties: addrs.len(),
+                index,
+                codec,
+                read_timeout: config.read_timeout,
+                peers: IntMap::default(),
+                stats: config.peer_stats(id, addrs.len()),
+                histograms: config.histograms,
+                transcripts: config.peer_transcripts(id, addrs.len()),
+                reconnector: None,
+            });
+        }
+
+        let progress = SetupProgress::new(&config, (addrs.len() - 1) * num * streams_per_peer);
+        let mut streams = HashMap::new();
+        std::thread::scope(|scope| {
+            // connect to the parties with a higher id, one thread per party, while accepting the
+            // connections of the parties with a lower id
+            let connecting = addrs
+                .iter()
+                .enumerate()
+                .skip(id + 1)
+                .map(|(other_id, addr)| {
+                    let (config, progress) = (&config, &progress);
+                    scope.spawn(move || {
+                        let mut streams = Vec::with_capacity(num * streams_per_peer);
+                        for index in 0..num {
+                            for s in 0..streams_per_peer {
+                                let mut stream = config.connect(id, other_id, addr)?;
+                                config.configure_stream(&stream)?;
+                                let preamble = TcpPreamble {
+                                    index,
+                                    id,
+                                    stream: s,
+                                };
+                                preamble.write(&mut stream, other_id, config)?;
+                                progress.established(other_id, index * streams_per_peer + s);
+                                streams.push((preamble, stream));
+                            }
+                        }
+                        eyre::Ok(streams)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for _ in 0..num * id * streams_per_peer {
+                let (stream, preamble) = config.accept(&listener, |mut stream| {
+                    config.configure_stream(&stream)?;
+                    let preamble = TcpPreamble::read(&mut stream, id, &config)?;
+                    if preamble.index >= num {
+                        eyre::bail!(
+                            "party {} requested network {}, but there are {num}",
+                            preamble.id,
+                            preamble.index
+                        );
+                    }
+                    if streams.contains_key(&preamble) {
+                        eyre::bail!(
+                            "party {} connected twice for stream {} of network {}",
+                            preamble.id,
+                            preamble.stream,
+                            preamble.index
+                        );
+                    }
+                    stream.set_read_timeout(None)?;
+                    Ok((stream, preamble))
+                })?;
+                progress.established(
+                    preamble.id,
+                    preamble.index * streams_per_peer + preamble.stream,
+                );
+                streams.insert(preamble, stream);
+            }
+
+            for (other_id, connecting) in (id + 1..).zip(connecting) {
+                for (preamble, stream) in connecting.join().expect("connecting does not panic")? {
+                    // key our own connections by the peer, like the accepted ones
+                    streams.insert(
+                        TcpPreamble {
+                            id: other_id,
+                            ..preamble
+                        },
+                        stream,
+                    );
+                }
+            }
+            eyre::Ok(())
+        })?;
+
+        for net in &mut nets {
+            for other_id in (0..addrs.len()).filter(|&other_id| other_id != id) {
+                let conns = (0..streams_per_peer)
+                    .map(|s| {
+                        let preamble = TcpPreamble {
+                            index: net.index,
+                            id: other_id,
+                            stream: s,
+                        };
+                        let stream = streams
+                            .remove(&preamble)
+                            .expect("all streams are connected");
+                        Ok(RwLock::new(TcpConn::new(
+                            &config, codec, stream, other_id, 0,
+                        )?))
+                    })
+                    .collect::<std::io::Result<_>>()?;
+                net.peers.insert(
+                    other_id,
+                    TcpPeer {
+                        conns,
+                        send_seq: Mutex::new(0),
+                        recv_seq: Mutex::new(0),
+                    },
+                );
+            }
+        }
+
+        if let Some(attempts) = config.reconnect_attempts {
+            listener.set_nonblocking(true)?;
+            let reconnector = Arc::new(Reconnector {
+                attempts,
+                addrs: addrs.to_vec(),
+                listener,
+                pending: Mutex::default(),
+                config,
+            });
+            for net in &mut nets {
+                net.reconnector = Some(Arc::clone(&reconnector));
+            }
+        }
+        Ok(nets)
+    }
+
+    /// The number of bytes sent to the party `to`, including the framing.
+    pub fn bytes_sent(&self, to: usize) -> u64 {
+        self.stats.get(to).map_or(0, PeerStats::bytes_sent)
+    }
+
+    /// The number of bytes received from the party `from`, including the framing.
+    pub fn bytes_recv(&self, from: usize) -> u64 {
+        self.stats.get(from).map_or(0, PeerStats::bytes_recv)
+    }
+
+    /// The total number of bytes sent to and received from all parties.
+    pub fn total_bytes(&self) -> (u64, u64) {
+        self.stats.values().fold((0, 0), |(sent, recv), stats| {
+            (sent + stats.bytes_sent(), recv + stats.bytes_recv())
+        })
+    }
+
+    /// The number of messages sent to the party `to`.
+    pub fn messages_sent(&self, to: usize) -> u64 {
+        self.stats.get(to).map_or(0, PeerStats::messages_sent)
+    }
+
+    /// The number of messages received from the party `from`.
+    pub fn messages_recv(&self, from: usize) -> u64 {
+        self.stats.get(from).map_or(0, PeerStats::messages_recv)
+    }
+
+    /// Reset the communication statistics, e.g., to measure a single protocol phase.
+    pub fn reset_stats(&self) {
+        for stats in self.stats.values() {
+            stats.reset();
+        }
+    }
+
+    /// The SHA-256 hash of all frames exchanged with the party `peer` so far, `None` if
+    /// [`NetworkConfig::transcript_hash`] is off or there is no such party.
+    ///
+    /// The hash covers the frames as they are on the wire, headers included, of both directions,
+    /// and both parties arrive at the same hash once they received everything the other sent,
+    /// e.g., to detect equivocation in a final consistency check. Frames discarded by
+    /// [`Network::drain`] or [`Network::resync_peer`] are only hashed by their sender, so the
+    /// hashes no longer match after either. This waits for running sends to and receives from
+    /// `peer`.
+    pub fn transcript_hash(&self, peer: usize) -> Option<[u8; 32]> {
+        self.transcripts.get(peer).map(TranscriptHasher::finish)
+    }
+
+    fn peer(&self, peer: usize) -> Result<&TcpPeer, NetworkError> {
+        self.peers.get(peer).ok_or(NetworkError::UnknownPeer(peer))
+    }
+
+    /// Runs `op` on the connection `stream` to `peer`, and again after reconnecting if the
+    /// connection broke.
+    fn with_conn<T>(
+        &self,
+        peer: usize,
+        stream: usize,
+        op: impl Fn(&TcpConn) -> Result<T, NetworkError>,
+    ) -> Result<T, NetworkError> {
+        let conn = &self.peer(peer)?.conns[stream];
+        let (generation, res) = {
+            let conn = conn.read();
+            (conn.generation, op(&conn))
+        };
+        let Some(reconnector) = &self.reconnector else {
+            return res;
+        };
+        match res {
+            Err(
+                err @ (NetworkError::PeerClosed(_)
+                | NetworkError::TruncatedMessage(_)
+                | NetworkError::Io(_)),
+            ) => {
+                tracing::warn!("connection to party {peer} broke, reconnecting: {err}");
+                {
+                    // wake up the operations still waiting on the broken connection
+                    let conn = conn.read();
+                    if conn.generation == generation {
+                        let _ = conn.stream.shutdown(Shutdown::Both);
+                    }
+                }
+                let mut conn = conn.write();
+                // another operation may have reconnected in the meantime
+                if conn.generation == generation {
+                    let stream = reconnector.reconnect(self.id, self.index, peer, stream)?;
+                    *conn = TcpConn::new(
+                        &reconnector.config,
+                        self.codec,
+                        stream,
+                        peer,
+                        generation + 1,
+                    )?;
+                }
+                op(&conn)
+            }
+            res => res,
+        }
+    }
+}
+
+impl TcpNetwork {
+    fn send_frame(&self, to: usize, stream: usize, frame: &[u8]) -> Result<(), NetworkError> {
+        self.with_conn(to, stream, |conn| {
+            let mut transcript = self.transcripts.get(to).map(TranscriptHasher::sent);
+            conn.sender
+                .send(&self.codec, frame)
+                .map_err(|err| NetworkError::from_io(to, err))?;
+            if let Some(transcript) = &mut transcript {
+                self.codec.hash_frame(transcript, frame);
+            }
+            Ok(())
+        })
+    }
+
+    /// Receives from the connection `stream` to `from` with `recv`, which passes the frames it
+    /// received to the hash function it is given.
+    fn recv_frame<T>(
+        &self,
+        from: usize,
+        stream: usize,
+        recv: impl Fn(&FrameReader, &mut dyn FnMut(&[u8])) -> Result<T, NetworkError>,
+    ) -> Result<T, NetworkError> {
+        self.with_conn(from, stream, |conn| {
+            let mut transcript = self.transcripts.get(from).map(TranscriptHasher::received);
+            recv(&conn.reader, &mut |frame| {
+                if let Some(transcript) = &mut transcript {
+                    self.codec.hash_frame(transcript, frame);
+                }
+            })
+        })
+    }
+}
+
+impl Network for TcpNetwork {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn num_parties(&self) -> usize {
+        self.num_parties
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        let span = message_span(to, "send");
+        span.record("len", data.len());
+        let _span = span.enter();
+        let peer = self.peer(to)?;
+        let frame_len = if peer.striped() {
+            let mut seq = peer.send_seq.lock();
+            let mut frame = Vec::with_capacity(data.len() + STRIPE_SEQ_LEN);
+            frame.extend_from_slice(data);
+            frame.extend_from_slice(&seq.to_be_bytes());
+            self.send_frame(to, peer.stream(*seq), &frame)?;
+            *seq += 1;
+            frame.len()
+        } else {
+            self.send_frame(to, 0, data)?;
+            data.len()
+        };
+        tracing::trace!("sent");
+        if let Some(stats) = self.stats.get(to) {
+            stats.record_send(data.len(), self.codec.frame_len(frame_len));
+        }
+        Ok(())
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        let span = message_span(from, "recv");
+        let _span = span.enter();
+        let peer = self.peer(from)?;
+        let recv = |reader: &FrameReader, hash: &mut dyn FnMut(&[u8])| {
+            let data = reader.recv(self.read_timeout)?;
+            hash(&data);
+            Ok(data)
+        };
+        let (data, frame_len) = if peer.striped() {
+            let mut next = peer.recv_seq.lock();
+            loop {
+                let mut data = self.recv_frame(from, peer.stream(*next), recv)?;
+                if let Some(len) = check_stripe_seq(from, &mut next, &data)? {
+                    let frame_len = data.len();
+                    data.truncate(len);
+                    break (data, frame_len);
+                }
+            }
+        } else {
+            let data = self.recv_frame(from, 0, recv)?;
+            let frame_len = data.len();
+            (data, frame_len)
+        };
+        span.record("len", data.len());
+        tracing::trace!("received");
+        if let Some(stats) = self.stats.get(from) {
+            stats.record_recv(self.codec.frame_len(frame_len));
+        }
+        Ok(data)
+    }
+
+    fn recv_into(&self, from: usize, buf: &mut Vec<u8>) -> Result<usize, NetworkError> {
+        let span = message_span(from, "recv");
+        let _span = span.enter();
+        let peer = self.peer(from)?;
+        let buf = Mutex::new(buf);
+        let recv = |reader: &FrameReader, hash: &mut dyn FnMut(&[u8])| {
+            let mut buf = buf.lock();
+            let len = reader.recv_into(self.read_timeout, &mut buf)?;
+            hash(&buf[..len]);
+            Ok(len)
+        };
+        let (len, frame_len) = if peer.striped() {
+            let mut next = peer.recv_seq.lock();
+            loop {
+                let frame_len = self.recv_frame(from, peer.stream(*next), recv)?;
+                let mut buf = buf.lock();
+                if let Some(len) = check_stripe_seq(from, &mut next, &buf[..frame_len])? {
+                    buf.truncate(len);
+                    break (len, frame_len);
+                }
+            }
+        } else {
+            let len = self.recv_frame(from, 0, recv)?;
+            (len, len)
+        };
+        span.record("len", len);
+        tracing::trace!("received");
+        if let Some(stats) = self.stats.get(from) {
+            stats.record_recv(self.codec.frame_len(frame_len));
+        }
+        Ok(len)
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        let span = message_span(from, "recv");
+        let _span = span.enter();
+        let peer = self.peer(from)?;
+        let recv = |reader: &FrameReader, hash: &mut dyn FnMut(&[u8])| {
+            let progress = reader.recv_partial(timeout)?;
+            if let RecvProgress::Complete(data) = &progress {
+                hash(data);
+            }
+            Ok(progress)
+        };
+        let (progress, frame_len) = if peer.striped() {
+            let mut next = peer.recv_seq.lock();
+            loop {
+                match self.recv_frame(from, peer.stream(*next), recv)? {
+                    RecvProgress::Complete(mut data) => {
+                        if let Some(len) = check_stripe_seq(from, &mut next, &data)? {
+                            let frame_len = data.len();
+                            data.truncate(len);
+                            break (RecvProgress::Complete(data), frame_len);
+                        }
+                    }
+                    RecvProgress::Partial { received, len } => {
+                        let len = len.map(|len| len.saturating_sub(STRIPE_SEQ_LEN));
+                        let received = len.map_or(received, |len| received.min(len));
+                        break (RecvProgress::Partial { received, len }, 0);
+                    }
+                }
+            }
+        } else {
+            let progress = self.recv_frame(from, 0, recv)?;
+            let frame_len = match &progress {
+                RecvProgress::Complete(data) => data.len(),
+                RecvProgress::Partial { .. } => 0,
+            };
+            (progress, frame_len)
+        };
+        if let RecvProgress::Complete(data) = &progress {
+            span.record("len", data.len());
+            tracing::trace!("received");
+            if let Some(stats) = self.stats.get(from) {
+                stats.record_recv(self.codec.frame_len(frame_len));
+            }
+        }
+        Ok(progress)
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        let peer = self.peer(id)?;
+        let mut send_seq = peer.send_seq.lock();
+        let mut recv_seq = peer.recv_seq.lock();
+        for conn in &peer.conns {
+            conn.read()
+                .sender
+                .resync(&self.codec)
+                .map_err(|err| NetworkError::from_io(id, err))?;
+        }
+        let mut discarded = 0;
+        for conn in &peer.conns {
+            discarded += conn.read().reader.resync(self.read_timeout)?;
+        }
+        // the peer restarts its sequence numbers as well
+        *send_seq = 0;
+        *recv_seq = 0;
+        tracing::debug!("resynced with party {id}, discarded {discarded} frames");
+        Ok(())
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        let peer = self.peer(from)?;
+        if !peer.striped() {
+            return Ok(peer.conns[0].read().reader.drain());
+        }
+        // skip everything up to the last discarded message, the messages before it that are
+        // still in flight on other streams are discarded once they arrive
+        let mut next = peer.recv_seq.lock();
+        let mut discarded = 0;
+        for conn in &peer.conns {
+            discarded += conn.read().reader.drain_frames(|frame| {
+                if let Some(len) = frame.len().checked_sub(STRIPE_SEQ_LEN) {
+                    let seq = u64::from_be_bytes(frame[len..].try_into().expect("8 bytes"));
+                    *next = (*next).max(seq + 1);
+                }
+            });
+        }
+        Ok(discarded)
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        for conn in &self.peer(to)?.conns {
+            conn.read()
+                .sender
+                .flush()
+                .map_err(|err| NetworkError::from_io(to, err))?;
+        }
+        Ok(())
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        for conn in &self.peer(to)?.conns {
+            conn.read()
+                .sender
+                .close()
+                .map_err(|err| NetworkError::from_io(to, err))?;
+        }
+        Ok(())
+    }
+
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        // the first connection is enough to tell that the peer is alive
+        let conn = self.peer(peer)?.conns[0].read();
+        conn.reader
+            .ping(timeout, |nonce| conn.sender.ping(&self.codec, nonce))
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        stats::size_histograms(&self.stats, self.histograms)
+    }
+}
+
+/// A wrapper type for client and server TLS streams
+#[derive(Debug)]
+pub enum TlsStream {
+    /// A Stream with a client connection
+    Client(StreamOwned<ClientConnection, TcpStream>),
+    /// A Stream with a sever connection
+    Server(StreamOwned<ServerConnection, TcpStream>),
+}
+
+impl From<StreamOwned<ClientConnection, TcpStream>> for TlsStream {
+    fn from(value: StreamOwned<ClientConnection, TcpStream>) -> Self {
+        Self::Client(value)
+    }
+}
+
+impl From<StreamOwned<ServerConnection, TcpStream>> for TlsStream {
+    fn from(value: StreamOwned<ServerConnection, TcpStream>) -> Self {
+        Self::Server(value)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            TlsStream::Client(stream) => stream.read(buf),
+            TlsStream::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl TlsStream {
+    /// Sends a close notification and shuts down the writing half of the socket.
+    fn close(&mut self) -> std::io::Result<()> {
+        match self {
+            TlsStream::Client(stream) => stream.conn.send_close_notify(),
+            TlsStream::Server(stream) => stream.conn.send_close_notify(),
+        }
+        self.flush()?;
+        match self {
+            TlsStream::Client(stream) => stream.sock.shutdown(Shutdown::Write),
+            TlsStream::Server(stream) => stream.sock.shutdown(Shutdown::Write),
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            TlsStream::Client(stream) => stream.write(buf),
+            TlsStream::Server(stream) => stream.write(buf),
+        }
+    }
+
+    // `StreamOwned` only writes the first buffer, `Stream` encrypts all of them into one record
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        match self {
+            TlsStream::Client(stream) => {
+                rustls::Stream::new(&mut stream.conn, &mut stream.sock).write_vectored(bufs)
+            }
+            TlsStream::Server(stream) => {
+                rustls::Stream::new(&mut stream.conn, &mut stream.sock).write_vectored(bufs)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            TlsStream::Client(stream) => stream.flush(),
+            TlsStream::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+/// One end of a TLS connection that carries both directions, see [`NetworkConfig::tls_multiplex`].
+///
+/// The reading and the writing end share the connection state, but only lock it to decrypt
+/// received or encrypt sent records, never while waiting on the socket. Otherwise a receive
+/// waiting for data would block sends, and two parties sending large messages to each other at
+/// the same time would deadlock.
+#[derive(Debug)]
+struct SharedTls {
+    conn: Arc<Mutex<rustls::Connection>>,
+    sock: TcpStream,
+    /// The buffer for records read from the socket.
+    incoming: Box<[u8]>,
+    /// The part of `incoming` not passed to the connection yet.
+    pending: Range<usize>,
+}
+
+impl SharedTls {
+    /// The size of the socket reads.
+    const READ_SIZE: usize = 4096;
+
+    /// Split the connection `conn` on `sock` into a reading and a writing end.
+    fn split(
+        conn: impl Into<rustls::Connection>,
+        sock: TcpStream,
+    ) -> std::io::Result<(Self, Self)> {
+        let conn = Arc::new(Mutex::new(conn.into()));
+        let reader = Self {
+            conn: Arc::clone(&conn),
+            sock: sock.try_clone()?,
+            incoming: vec![0; Self::READ_SIZE].into(),
+            pending: 0..0,
+        };
+        let writer = Self {
+            conn,
+            sock,
+            incoming: Box::default(),
+            pending: 0..0,
+        };
+        Ok((reader, writer))
+    }
+
+    /// Encrypts with `op` and writes the resulting records to the socket, outside of the lock.
+    fn write_records<T>(
+        &mut self,
+        op: impl FnOnce(&mut rustls::Connection) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let mut records = Vec::new();
+        let res = {
+            let mut conn = self.conn.lock();
+            let res = op(&mut conn)?;
+            while conn.wants_write() {
+                conn.write_tls(&mut records)?;
+            }
+            res
+        };
+        self.sock.write_all(&records)?;
+        Ok(res)
+    }
+
+    /// Sends a close notification and shuts down the writing half of the socket.
+    fn close(&mut self) -> std::io::Result<()> {
+        self.write_records(|conn| {
+            conn.send_close_notify();
+            Ok(())
+        })?;
+        self.sock.flush()?;
+        self.sock.shutdown(Shutdown::Write)
+    }
+}
+
+impl Read for SharedTls {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.conn.lock().reader().read(buf) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+            if self.pending.is_empty() {
+                let n = self.sock.read(&mut self.incoming)?;
+                self.pending = 0..n;
+            }
+            // only pass on more records once the decrypted data is consumed, the connection
+            // refuses them if its buffer is full, an empty read marks the end of the stream
+            let mut incoming = &self.incoming[self.pending.clone()];
+            let mut conn = self.conn.lock();
+            let n = conn.read_tls(&mut incoming)?;
+            conn.process_new_packets()
+                .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+            self.pending.start += n;
+        }
+    }
+}
+
+impl Write for SharedTls {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_records(|conn| conn.writer().write(buf))
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        self.write_records(|conn| conn.writer().write_vectored(bufs))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+/// The sending half of a [`TlsNetwork`] connection.
+#[derive(Debug)]
+enum TlsSender {
+    Exclusive(Box<TlsStream>),
+    Shared(SharedTls),
+}
+
+impl TlsSender {
+    fn close(&mut self) -> std::io::Result<()> {
+        match self {
+            TlsSender::Exclusive(stream) => stream.close(),
+            TlsSender::Shared(stream) => stream.close(),
+        }
+    }
+}
+
+impl Write for TlsSender {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            TlsSender::Exclusive(stream) => stream.write(buf),
+            TlsSender::Shared(stream) => stream.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        match self {
+            TlsSender::Exclusive(stream) => stream.write_vectored(bufs),
+            TlsSender::Shared(stream) => stream.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            TlsSender::Exclusive(stream) => stream.flush(),
+            TlsSender::Shared(stream) => stream.flush(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TlsNetwork {
+    id: usize,
+    num_parties: usize,
+    codec: Codec,
+    read_timeout: Duration,
+    send: IntMap<usize, Arc<Mutex<TlsSender>>>,
+    recv: IntMap<usize, FrameReader>,
+    stats: IntMap<usize, PeerStats>,
+    /// Whether the stats record histograms, see [`NetworkConfig::histograms`].
+    histograms: bool,
+    transcripts: IntMap<usize, TranscriptHasher>,
+}
+
+impl TlsNetwork {
+    pub fn networks<A: ToSocketAddrs>(
+        id: usize,
+        bind_addr: A,
+        addrs: &[Address],
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        num: usize,
+    ) -> eyre::Result<Vec<Self>> {
+        Self::networks_with_config(
+            id,
+            bind_addr,
+            addrs,
+            certs,
+            key,
+            num,
+            NetworkConfig::default(),
+        )
+    }
+
+    pub fn networks_with_config<A: ToSocketAddrs>(
+        id: usize,
+        bind_addr: A,
+        addrs: &[Address],
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        num: usize,
+        config: NetworkConfig,
+    ) -> eyre::Result<Vec<Self>> {
+        Self::networks_inner(id, bind_addr, addrs, certs, key, num, config, false)
+    }
+
+    /// Like [`TlsNetwork::networks_with_config`], but accepts peer certificates that are not
+    /// valid for the hostnames in `addrs`.
+    ///
+    /// The certificate chain is still verified against the party certificates, only the hostname
+    /// check is skipped. This is meant for running all parties on the loopback interface with
+    /// certificates issued for their real hostnames, so all addresses must resolve to loopback
+    /// addresses. Never use this in production.
+    pub fn networks_danger_accept_invalid_hostnames<A: ToSocketAddrs>(
+        id: usize,
+        bind_addr: A,
+        addrs: &[Address],
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        num: usize,
+        config: NetworkConfig,
+    ) -> eyre::Result<Vec<Self>> {
+        for addr in addrs {
+            if !addr.to_socket_addrs()?.all(|addr| addr.ip().is_loopback()) {
+                eyre::bail!(
+                    "{addr} is not a loopback address, refusing to skip the hostname verification"
+                );
+            }
+        }
+        tracing::warn!(
+            "DANGER: TLS hostname verification is disabled, this must only be used for local testing"
+        );
+        Self::networks_inner(id, bind_addr, addrs, certs, key, num, config, true)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn networks_inner<A: ToSocketAddrs>(
+        id: usize,
+        bind_addr: A,
+        addrs: &[Address],
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        num: usize,
+        config: NetworkConfig,
+        accept_invalid_hostnames: bool,
+    ) -> eyre::Result<Vec<Self>> {
+        tracing::debug!("creating new network");
+        let codec = config.codec();
+
+        let (mut client_config, mut server_config, root_store) =
+            config.tls_configs(id, addrs.len(), &certs, key)?;
+
+        if accept_invalid_hostnames {
+            let verifier = AnyHostnameVerifier {
+                roots: root_store,
+                algorithms: config
+                    .tls_crypto_provider()
+                    .signature_verification_algorithms,
+            };
+            client_config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(verifier));
+        }
+
+        if let Some(cache) = &config.early_data {
+            client_config.resumption = Resumption::store(cache.client.clone());
+            client_config.enable_early_data = true;
+            server_config.session_storage = cache.server.clone();
+            server_config.max_early_data_size = MAX_PREAMBLE_LEN as u32;
+        }
+
+        let client_config = Arc::new(client_config);
+        let server_config = Arc::new(server_config);
+
+        let listener = config.bind(bind_addr)?;
+
+        let mut nets = Vec::with_capacity(num);
+        for _ in 0..num {
+            nets.push(Self {
+                id,
+                num_parties: addrs.len(),
+                codec,
+                read_timeout: config.read_timeout,
+                send: IntMap::default(),
+                recv: IntMap::default(),
+                stats: config.peer_stats(id, addrs.len()),
+                histograms: config.histograms,
+                transcripts: config.peer_transcripts(id, addrs.len()),
+            });
+        }
+
+        const STREAM_0: u8 = 0;
+        const STREAM_1: u8 = 1;
+        // a single connection for both directions
+        const STREAM_BOTH: u8 = 2;
+        let streams: &[u8] = if config.tls_multiplex {
+            &[STREAM_BOTH]
+        } else {
+            &[STREAM_0, STREAM_1]
+        };
+        let stream_index = |i: usize, s: u8| i * streams.len() + usize::from(s == STREAM_1);
+        let progress = SetupProgress::new(&config, (addrs.len() - 1) * num * streams.len());
+
+        for i in 0..num {
+            for &s in streams {
+                for (other_id, addr) in addrs.iter().enumerate() {
+                    match id.cmp(&other_id) {
+                        Ordering::Less => {
+                            let stream = config.connect(id, other_id, addr)?;
+                            config.configure_stream(&stream)?;
+
+                            let name = ServerName::try_from(addr.hostname.clone())?.to_owned();
+                            let mut conn =
+                                ClientConnection::new(client_config.clone(), name.clone())?;
+
+                            let mut preamble = Vec::with_capacity(MAX_PREAMBLE_LEN);
+                            preamble.write_u64::<BigEndian>(i as u64)?;
+                            // with mutual TLS the acceptor knows our id from the certificate
+                            if !config.mutual_tls {
+                                preamble.write_u64::<BigEndian>(id as u64)?;
+                            }
+                            preamble.write_u8(s)?;
+                            preamble.write_u8(codec.flags())?;
+
+                            let sent_early = match conn.early_data() {
+                                Some(mut early) if early.bytes_left() >= preamble.len() => {
+                                    early.write_all(&preamble)?;
+                                    true
+                                }
+                                _ => false,
+                            };
+                            let mut stream = StreamOwned::new(conn, stream);
+                            if sent_early {
+                                while stream.conn.is_handshaking() {
+                                    stream.conn.complete_io(&mut stream.sock)?;
+                                }
+                            }
+                            // the server may reject early data, it is lost then
+                            if sent_early && stream.conn.is_early_data_accepted() {
+                                tracing::debug!("sent preamble to party {other_id} as early data");
+                            } else {
+                                stream.write_all(&preamble)?;
+                            }
+                            codec.check_flags(other_id, stream.read_u8()?)?;
+                            // the hostname alone does not rule out another party listening there
+                            if config.mutual_tls {
+                                let peer_id =
+                                    peer_id_from_cert(stream.conn.peer_certificates(), &certs)?;
+                                if peer_id != other_id {
+                                    eyre::bail!(
+                                        "party {peer_id} answered the connection to party {other_id} at {addr}"
+                                    );
+                                }
+                            }
+                            if let Some(identities) = &config.peer_identities {
+                                identities[other_id]
+                                    .check(stream.conn.peer_certificates(), other_id)?;
+                            }
+
+                            match s {
+                                STREAM_0 => {
+                                    let sender =
+                                        TlsSender::Exclusive(Box::new(TlsStream::Client(stream)));
+                                    nets[i].send.insert(other_id, Arc::new(Mutex::new(sender)));
+                                }
+                                STREAM_1 => {
+                                    let reader = codec.spawn_reader(stream, other_id);
+                                    nets[i].recv.insert(other_id, reader);
+                                }
+                                _ => {
+                                    let (reader, writer) =
+                                        SharedTls::split(stream.conn, stream.sock)?;
+                                    let reader = codec.spawn_reader(reader, other_id);
+                                    nets[i].recv.insert(other_id, reader);
+                                    let sender = TlsSender::Shared(writer);
+                                    nets[i].send.insert(other_id, Arc::new(Mutex::new(sender)));
+                                }
+                            }
+                            progress.established(other_id, stream_index(i, s));
+                        }
+                        Ordering::Greater => {
+                            let (stream, i, other_id, s_) = config.accept(&listener, |stream| {
+                                config.configure_stream(&stream)?;
+
+                                let conn = ServerConnection::new(server_config.clone())?;
+                                let mut stream = StreamOwned::new(conn, stream);
+
+                                // early data is replayable, only use it once the handshake completed
+                                let mut early = Vec::new();
+                                if config.early_data.is_some() {
+                                    while stream.conn.is_handshaking() {
+                                        stream.conn.complete_io(&mut stream.sock)?;
+                                    }
+                                    if let Some(mut data) = stream.conn.early_data() {
+                                        data.read_to_end(&mut early)?;
+                                    }
+                                }
+                                let mut preamble = early.as_slice().chain(&mut stream);
+
+                                let i = preamble.read_u64::<BigEndian>()? as usize;
+                                let other_id = if config.mutual_tls {
+                                    // the handshake is complete once the first data arrived
+                                    peer_id_from_cert(
+                                        preamble.get_ref().1.conn.peer_certificates(),
+                                        &certs,
+                                    )?
+                                } else {
+                                    preamble.read_u64::<BigEndian>()? as usize
+                                };
+                                let s_ = preamble.read_u8()?;
+                                let flags = preamble.read_u8()?;
+                                // echo our flags, so a connecting party with other flags fails as well
+                                let echo = preamble.get_mut().1;
+                                echo.write_u8(codec.flags())?;
+                                echo.flush()?;
+                                if s_ > STREAM_BOTH {
+                                    eyre::bail!("party {other_id} requested unknown stream {s_}");
+                                }
+                                if (s_ == STREAM_BOTH) != config.tls_multiplex {
+                                    eyre::bail!(
+                                        "party {other_id} does not use the same tls_multiplex setting"
+                                    );
+                                }
+                                if s_ != STREAM_1 {
+                                    check_preamble(&nets, id, i, other_id, |net| &net.recv)?;
+                                }
+                                if s_ != STREAM_0 {
+                                    check_preamble(&nets, id, i, other_id, |net| &net.send)?;
+                                }
+                                if let Some(identities) = &config.peer_identities {
+                                    let conn = &preamble.get_ref().1.conn;
+                                    identities[other_id]
+                                        .check(conn.peer_certificates(), other_id)?;
+                                }
+                                codec.check_flags(other_id, flags)?;
+                                stream.sock.set_read_timeout(None)?;
+                                Ok((stream, i, other_id, s_))
+                            })?;
+
+                            match s_ {
+                                STREAM_0 => {
+                                    let reader = codec.spawn_reader(stream, other_id);
+                                    nets[i].recv.insert(other_id, reader);
+                                }
+                                STREAM_1 => {
+                                    let sender =
+                                        TlsSender::Exclusive(Box::new(TlsStream::Server(stream)));
+                                    nets[i].send.insert(other_id, Arc::new(Mutex::new(sender)));
+                                }
+                                _ => {
+                                    let (reader, writer) =
+                                        SharedTls::split(stream.conn, stream.sock)?;
+                                    let reader = codec.spawn_reader(reader, other_id);
+                                    nets[i].recv.insert(other_id, reader);
+                                    let sender = TlsSender::Shared(writer);
+                                    nets[i].send.insert(other_id, Arc::new(Mutex::new(sender)));
+                                }
+                            }
+                            progress.established(other_id, stream_index(i, s_));
+                        }
+                        Ordering::Equal => continue,
+                    }
+                }
+            }
+        }
+
+        // a reader answers the pings of its peer on the sending side to the same peer
+        for net in &nets {
+            for (other_id, reader) in net.recv.iter() {
+                if let Some(sender) = net.send.get(other_id) {
+                    let sender = Arc::downgrade(sender);
+                    reader.answer_pings(move |nonce| {
+                        let sender = sender.upgrade().ok_or(ErrorKind::BrokenPipe)?;
+                        codec.write_pong(&mut *sender.lock(), nonce)
+                    });
+                }
+            }
+        }
+
+        Ok(nets)
+    }
+
+    /// Generate a self-signed certificate and key for each of `num_parties` parties, for tests and
+    /// local development.
+    ///
+    /// The certificates are valid for `localhost`, `127.0.0.1` and `::1`.
+    #[cfg(feature = "test-certs")]
+    pub fn generate_test_certs(
+        num_parties: usize,
+    ) -> (Vec<CertificateDer<'static>>, Vec<PrivateKeyDer<'static>>) {
+        (0..num_parties)
+            .map(|_| {
+                let names = ["localhost", "127.0.0.1", "::1"].map(String::from);
+                let certified = rcgen::generate_simple_self_signed(names)
+                    .expect("can generate a self-signed certificate");
+                let key = PrivateKeyDer::Pkcs8(certified.signing_key.serialize_der().into());
+                (certified.cert.der().clone(), key)
+            })
+            .unzip()
+    }
+
+    /// Load all certificates from the PEM file at `path`.
+    pub fn load_certs(path: &Path) -> eyre::Result<Vec<CertificateDer<'static>>> {
+        let certs = CertificateDer::pem_file_iter(path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| {
+                eyre::eyre!("could not read certificates from {}: {err}", path.display())
+            })?;
+        if certs.is_empty() {
+            eyre::bail!("no certificates found in {}", path.display());
+        }
+        Ok(certs)
+    }
+
+    /// Load the private key from the PEM file at `path`, in PKCS#8, PKCS#1 (RSA) or SEC1 format.
+    pub fn load_key(path: &Path) -> eyre::Result<PrivateKeyDer<'static>> {
+        PrivateKeyDer::from_pem_file(path)
+            .map_err(|err| eyre::eyre!("could not read private key from {}: {err}", path.display()))
+    }
+
+    /// The number of bytes sent to the party `to`, including the framing.
+    pub fn bytes_sent(&self, to: usize) -> u64 {
+        self.stats.get(to).map_or(0, PeerStats::bytes_sent)
+    }
+
+    /// The number of bytes received from the party `from`, including the framing.
+    pub fn bytes_recv(&self, from: usize) -> u64 {
+        self.stats.get(from).map_or(0, PeerStats::bytes_recv)
+    }
+
+    /// The total number of bytes sent to and received from all parties.
+    pub fn total_bytes(&self) -> (u64, u64) {
+        self.stats.values().fold((0, 0), |(sent, recv), stats| {
+            (sent + stats.bytes_sent(), recv + stats.bytes_recv())
+        })
+    }
+
+    /// The number of messages sent to the party `to`.
+    pub fn messages_sent(&self, to: usize) -> u64 {
+        self.stats.get(to).map_or(0, PeerStats::messages_sent)
+    }
+
+    /// The number of messages received from the party `from`.
+    pub fn messages_recv(&self, from: usize) -> u64 {
+        self.stats.get(from).map_or(0, PeerStats::messages_recv)
+    }
+
+    /// Reset the communication statistics, e.g., to measure a single protocol phase.
+    pub fn reset_stats(&self) {
+        for stats in self.stats.values() {
+            stats.reset();
+        }
+    }
+
+    /// The SHA-256 hash of all frames exchanged with the party `peer` so far, `None` if
+    /// [`NetworkConfig::transcript_hash`] is off or there is no such party.
+    ///
+    /// The frames are hashed before encryption, see [`TcpNetwork::transcript_hash`].
+    pub fn transcript_hash(&self, peer: usize) -> Option<[u8; 32]> {
+        self.transcripts.get(peer).map(TranscriptHasher::finish)
+    }
+}
+
+/// The span around sending a message to or receiving a message from `peer`.
+///
+/// The span is disabled unless the trace level is, and then records the length of the message
+/// once it is known, so the events of the transports show which message they belong to.
+fn message_span(peer: usize, direction: &'static str) -> tracing::Span {
+    tracing::trace_span!("message", peer, direction, len = tracing::field::Empty)
+}
+
+/// Checks that the preamble of an accepted connection refers to a connection we are waiting for.
+fn check_preamble<N, T>(
+    nets: &[N],
+    id: usize,
+    i: usize,
+    other_id: usize,
+    streams: impl Fn(&N) -> &IntMap<usize, T>,
+) -> eyre::Result<()> {
+    let Some(net) = nets.get(i) else {
+        eyre::bail!(
+            "party {other_id} requested network {i}, but there are {}",
+            nets.len()
+        );
+    };
+    // only parties with a lower id connect to us
+    if other_id >= id {
+        eyre::bail!("party {other_id} must not connect to party {id}");
+    }
+    if streams(net).contains_key(other_id) {
+        eyre::bail!("party {other_id} connected twice for network {i}");
+    }
+    Ok(())
+}
+
+/// Verifies the chain of server certificates against the root store, but accepts any hostname.
+#[derive(Debug)]
+struct AnyHostnameVerifier {
+    roots: Arc<RootCertStore>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for AnyHostnameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let cert = ParsedCertificate::try_from(end_entity)?;
+        verify_server_cert_signed_by_trust_anchor(
+            &cert,
+            &self.roots,
+            intermediates,
+            now,
+            self.algorithms.all,
+        )?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Map the verified certificate chain `chain` of a peer to its party id.
+fn peer_id_from_cert(
+    chain: Option<&[CertificateDer<'_>]>,
+    certs: &[CertificateDer<'static>],
+) -> eyre::Result<usize> {
+    let cert = chain
+        .and_then(|chain| chain.first())
+        .context("peer did not present a certificate")?;
+    certs
+        .iter()
+        .position(|c| c == cert)
+        .context("peer certificate does not belong to any party")
+}
+
+impl Network for TlsNetwork {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn num_parties(&self) -> usize {
+        self.num_parties
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        let span = message_span(to, "send");
+        span.record("len", data.len());
+        let _span = span.enter();
+        let mut stream = self
+            .send
+            .get(to)
+            .ok_or(NetworkError::UnknownPeer(to))?
+            .lock();
+        self.codec
+            .write_frame(&mut *stream, data)
+            .map_err(|err| NetworkError::from_io(to, err))?;
+        tracing::trace!("sent");
+        if let Some(transcript) = self.transcripts.get(to) {
+            self.codec.hash_frame(&mut transcript.sent(), data);
+        }
+        if let Some(stats) = self.stats.get(to) {
+            stats.record_send(data.len(), self.codec.frame_len(data.len()));
+        }
+        Ok(())
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        let span = message_span(from, "recv");
+        let _span = span.enter();
+        let reader = self.recv.get(from).ok_or(NetworkError::UnknownPeer(from))?;
+        let mut transcript = self.transcripts.get(from).map(TranscriptHasher::received);
+        let data = reader.recv(self.read_timeout)?;
+        span.record("len", data.len());
+        tracing::trace!("received");
+        if let Some(stats) = self.stats.get(from) {
+            stats.record_recv(self.codec.frame_len(data.len()));
+        }
+        if let Some(transcript) = &mut transcript {
+            self.codec.hash_frame(transcript, &data);
+        }
+        Ok(data)
+    }
+
+    fn recv_into(&self, from: usize, buf: &mut Vec<u8>) -> Result<usize, NetworkError> {
+        let span = message_span(from, "recv");
+        let _span = span.enter();
+        let reader = self.recv.get(from).ok_or(NetworkError::UnknownPeer(from))?;
+        let mut transcript = self.transcripts.get(from).map(TranscriptHasher::received);
+        let len = reader.recv_into(self.read_timeout, buf)?;
+        span.record("len", len);
+        tracing::trace!("received");
+        if let Some(stats) = self.stats.get(from) {
+            stats.record_recv(self.codec.frame_len(len));
+        }
+        if let Some(transcript) = &mut transcript {
+            self.codec.hash_frame(transcript, &buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        let span = message_span(from, "recv");
+        let _span = span.enter();
+        let reader = self.recv.get(from).ok_or(NetworkError::UnknownPeer(from))?;
+        let mut transcript = self.transcripts.get(from).map(TranscriptHasher::received);
+        let progress = reader.recv_partial(timeout)?;
+        if let RecvProgress::Complete(data) = &progress {
+            span.record("len", data.len());
+            tracing::trace!("received");
+            if let Some(stats) = self.stats.get(from) {
+                stats.record_recv(self.codec.frame_len(data.len()));
+            }
+            if let Some(transcript) = &mut transcript {
+                self.codec.hash_frame(transcript, data);
+            }
+        }
+        Ok(progress)
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        let reader = self.recv.get(id).ok_or(NetworkError::UnknownPeer(id))?;
+        {
+            let mut stream = self
+                .send
+                .get(id)
+                .ok_or(NetworkError::UnknownPeer(id))?
+                .lock();
+            self.codec
+                .write_resync(&mut *stream)
+                .map_err(|err| NetworkError::from_io(id, err))?;
+        }
+        let discarded = reader.resync(self.read_timeout)?;
+        tracing::debug!("resynced with party {id}, discarded {discarded} frames");
+        Ok(())
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        Ok(self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .drain())
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        self.send
+            .get(to)
+            .ok_or(NetworkError::UnknownPeer(to))?
+            .lock()
+            .flush()
+            .map_err(|err| NetworkError::from_io(to, err))
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        self.send
+            .get(to)
+            .ok_or(NetworkError::UnknownPeer(to))?
+            .lock()
+            .close()
+            .map_err(|err| NetworkError::from_io(to, err))
+    }
+
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        let sender = self.send.get(peer).ok_or(NetworkError::UnknownPeer(peer))?;
+        let reader = self.recv.get(peer).ok_or(NetworkError::UnknownPeer(peer))?;
+        reader.ping(timeout, |nonce| {
+            self.codec.write_ping(&mut *sender.lock(), nonce)
+        })
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        stats::size_histograms(&self.stats, self.histograms)
+    }
+}
+
+/// Properties of a directed link between two [`TestNetwork`] parties.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkProps {
+    /// One-way latency added to every message.
+    pub latency: Duration,
+    /// Bandwidth in bytes per second, `None` means unlimited.
+    pub bandwidth: Option<u64>,
+    /// Probability in `[0, 1]` that a message is silently dropped.
+    ///
+    /// A receive waiting for a dropped message fails with [`NetworkError::Timeout`] once the
+    /// timeout of the receiving [`TestNetwork`] elapses.
+    pub drop: f64,
+    /// The number of messages the link holds back to deliver them out of order, `0` keeps the order.
+    ///
+    /// Once more than `reorder` messages are held, the link delivers a random one of them. The held
+    /// messages are flushed in random order when the sender receives from the same peer, so
+    /// request-response exchanges don't stall, and when it is dropped.
+    pub reorder: usize,
+    /// The number of messages the receiver can have queued, `None` means unlimited.
+    ///
+    /// Like a full socket buffer, a send to a full queue blocks until the receiver takes a
+    /// message, and fails with [`NetworkError::Timeout`] once the timeout of the sending
+    /// [`TestNetwork`] elapses. This surfaces protocols that only work as long as a peer never
+    /// falls behind. Resync markers and the messages held back for reordering are delivered
+    /// regardless of the capacity, so the queue can temporarily hold more messages.
+    pub capacity: Option<usize>,
+}
+
+/// A matrix of [`LinkProps`] indexed by the directed link `(from, to)`.
+///
+/// The random drops and reorderings of all links are derived from a seed, so a run with the same
+/// seed and the same sequence of sends is reproducible.
+#[derive(Debug, Clone)]
+pub struct LinkMatrix {
+    num_parties: usize,
+    props: Vec<LinkProps>,
+    seed: u64,
+}
+
+impl LinkMatrix {
+    /// Construct a new [`LinkMatrix`] where every link has the default (ideal) properties.
+    pub fn new(num_parties: usize) -> Self {
+        Self::uniform(num_parties, LinkProps::default())
+    }
+
+    /// Construct a new [`LinkMatrix`] where every link has the given properties.
+    pub fn uniform(num_parties: usize, props: LinkProps) -> Self {
+        Self {
+            num_parties,
+            props: vec![props; num_parties * num_parties],
+            seed: 0,
+        }
+    }
+
+    /// Drop messages with probability `drop` and reorder them within a window of `reorder` on
+    /// every link, using the given `seed` for the random choices.
+    pub fn with_faults(mut self, seed: u64, drop: f64, reorder: usize) -> Self {
+        self.seed = seed;
+        for props in &mut self.props {
+            props.drop = drop;
+            props.reorder = reorder;
+        }
+        self
+    }
+
+    /// The number of parties this matrix is defined for.
+    pub fn num_parties(&self) -> usize {
+        self.num_parties
+    }
+}
+
+impl Index<(usize, usize)> for LinkMatrix {
+    type Output = LinkProps;
+    fn index(&self, (from, to): (usize, usize)) -> &Self::Output {
+        assert!(from < self.num_parties && to < self.num_parties);
+        &self.props[from * self.num_parties + to]
+    }
+}
+
+impl IndexMut<(usize, usize)> for LinkMatrix {
+    fn index_mut(&mut self, (from, to): (usize, usize)) -> &mut Self::Output {
+        assert!(from < self.num_parties && to < self.num_parties);
+        &mut self.props[from * self.num_parties + to]
+    }
+}
+
+/// A message between [`TestNetwork`]s, `data` is `None` for a resync marker.
+#[derive(Debug)]
+struct TestMessage {
+    data: Option<Vec<u8>>,
+    deliver_at: Instant,
+}
+
+#[derive(Debug)]
+struct LinkState {
+    free_at: Instant,
+    rng: ChaCha8Rng,
+    /// The messages held back to be overtaken, see [`LinkProps::reorder`].
+    held: Vec<TestMessage>,
+}
+
+/// The messages queued on a link with a [`LinkProps::capacity`], shared by both ends.
+#[derive(Debug)]
+struct Backlog {
+    capacity: usize,
+    /// The number of messages the receiver did not take yet, `None` once it is dropped.
+    queued: Mutex<Option<usize>>,
+    cvar: Condvar,
+}
+
+impl Backlog {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            queued: Mutex::new(Some(0)),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// Waits until fewer than `capacity` messages are queued and counts another one.
+    fn reserve(&self, peer: usize, timeout: Duration) -> Result<(), NetworkError> {
+        let deadline = Instant::now() + timeout;
+        let mut queued = self.queued.lock();
+        loop {
+            match *queued {
+                None => return Err(NetworkError::PeerClosed(peer)),
+                Some(count) if count < self.capacity => {
+                    *queued = Some(count + 1);
+                    return Ok(());
+                }
+                Some(_) if Instant::now() >= deadline => return Err(NetworkError::Timeout(peer)),
+                Some(_) => {
+                    self.cvar.wait_until(&mut queued, deadline);
+                }
+            }
+        }
+    }
+
+    /// Counts another message, even if the queue is full.
+    fn push(&self) {
+        if let Some(count) = &mut *self.queued.lock() {
+            *count += 1;
+        }
+    }
+
+    /// Uncounts a message that was taken by the receiver or not delivered after all.
+    fn release(&self) {
+        if let Some(count) = &mut *self.queued.lock() {
+            *count = count.saturating_sub(1);
+        }
+        self.cvar.notify_one();
+    }
+
+    /// Fails all current and future reservations, the receiver is gone.
+    fn close(&self) {
+        *self.queued.lock() = None;
+        self.cvar.notify_all();
+    }
+}
+
+#[derive(Debug)]
+struct TestLink {
+    peer: usize,
+    tx: Sender<TestMessage>,
+    props: LinkProps,
+    state: Mutex<LinkState>,
+    backlog: Option<Arc<Backlog>>,
+}
+
+impl TestLink {
+    fn new(
+        from: usize,
+        to: usize,
+        tx: Sender<TestMessage>,
+        props: LinkProps,
+        seed: u64,
+        backlog: Option<Arc<Backlog>>,
+    ) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        rng.set_stream(((from as u64) << 32) | to as u64);
+        Self {
+            peer: to,
+            tx,
+            props,
+            state: Mutex::new(LinkState {
+                free_at: Instant::now(),
+                rng,
+                held: Vec::new(),
+            }),
+            backlog,
+        }
+    }
+
+    /// Delivers `message`, counting it in the backlog unless a place was `reserved` for it.
+    fn deliver(&self, message: TestMessage, reserved: bool) -> Result<(), NetworkError> {
+        if let (Some(backlog), false) = (&self.backlog, reserved) {
+            backlog.push();
+        }
+        self.tx
+            .send(message)
+            .map_err(|_| NetworkError::PeerClosed(self.peer))
+    }
+
+    /// Delivers all held messages in random order.
+    fn flush(&self) -> Result<(), NetworkError> {
+        let mut state = self.state.lock();
+        self.flush_held(&mut state)
+    }
+
+    fn flush_held(&self, state: &mut LinkState) -> Result<(), NetworkError> {
+        while !state.held.is_empty() {
+            let i = state.rng.random_range(..state.held.len());
+            let message = state.held.swap_remove(i);
+            self.deliver(message, false)?;
+        }
+        Ok(())
+    }
+
+    fn send(&self, data: &[u8], timeout: Duration) -> Result<(), NetworkError> {
+        // wait for the receiver before locking the link, so it can still be flushed meanwhile
+        if let Some(backlog) = &self.backlog {
+            backlog.reserve(self.peer, timeout)?;
+        }
+        let unreserve = || {
+            if let Some(backlog) = &self.backlog {
+                backlog.release();
+            }
+        };
+        let mut state = self.state.lock();
+        // the link is busy until the previous message is fully transmitted
+        let now = Instant::now();
+        if state.free_at < now {
+            state.free_at = now;
+        }
+        if let Some(bandwidth) = self.props.bandwidth {
+            state.free_at += Duration::from_secs_f64(data.len() as f64 / bandwidth as f64);
+        }
+        if self.props.drop > 0.0 && state.rng.random::<f64>() < self.props.drop {
+            unreserve();
+            return Ok(());
+        }
+        let message = TestMessage {
+            data: Some(data.to_owned()),
+            deliver_at: state.free_at + self.props.latency,
+        };
+        if self.props.reorder == 0 {
+            return self.deliver(message, true);
+        }
+        state.held.push(message);
+        let held = state.held.len();
+        if held > self.props.reorder {
+            let i = state.rng.random_range(..held);
+            let message = state.held.swap_remove(i);
+            self.deliver(message, true)?;
+        } else {
+            unreserve();
+        }
+        Ok(())
+    }
+
+    fn send_resync(&self) -> Result<(), NetworkError> {
+        let mut state = self.state.lock();
+        // the marker must not overtake any message
+        self.flush_held(&mut state)?;
+        let deliver_at = state.free_at + self.props.latency;
+        self.deliver(
+            TestMessage {
+                data: None,
+                deliver_at,
+            },
+            false,
+        )
+    }
+}
+
+impl Drop for TestLink {
+    fn drop(&mut self) {
+        // the peer may already be gone
+        let _ = self.flush();
+    }
+}
+
+#[derive(Debug)]
+struct TestReceiver {
+    peer: usize,
+    rx: Mutex<Receiver<TestMessage>>,
+    /// Set if a regular receive consumed the resync marker of the peer.
+    peer_resync: AtomicBool,
+    backlog: Option<Arc<Backlog>>,
+}
+
+impl TestReceiver {
+    fn new(peer: usize, rx: Receiver<TestMessage>, backlog: Option<Arc<Backlog>>) -> Self {
+        Self {
+            peer,
+            rx: Mutex::new(rx),
+            peer_resync: AtomicBool::new(false),
+            backlog,
+        }
+    }
+
+    /// Makes room in the backlog for a message that was taken from the channel.
+    fn taken(&self) {
+        if let Some(backlog) = &self.backlog {
+            backlog.release();
+        }
+    }
+
+    fn recv_message(
+        &self,
+        rx: &Receiver<TestMessage>,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, NetworkError> {
+        let message = rx.recv_timeout(timeout).map_err(|err| match err {
+            RecvTimeoutError::Timeout => NetworkError::Timeout(self.peer),
+            RecvTimeoutError::Disconnected => NetworkError::PeerClosed(self.peer),
+        })?;
+        self.taken();
+        let now = Instant::now();
+        if message.deliver_at > now {
+            std::thread::sleep(message.deliver_at - now);
+        }
+        Ok(message.data)
+    }
+
+    fn recv(&self, timeout: Duration) -> Result<Vec<u8>, NetworkError> {
+        match self.recv_message(&self.rx.lock(), timeout)? {
+            Some(data) => Ok(data),
+            None => {
+                self.peer_resync
+                    .store(true, std::sync::atomic::Ordering::Relaxed);
+                Err(NetworkError::Resync(self.peer))
+            }
+        }
+    }
+
+    fn resync(&self, timeout: Duration) -> Result<usize, NetworkError> {
+        let rx = self.rx.lock();
+        if self
+            .peer_resync
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+        {
+            return Ok(0);
+        }
+        let deadline = Instant::now() + timeout;
+        let mut discarded = 0;
+        while self
+            .recv_message(&rx, deadline.saturating_duration_since(Instant::now()))?
+            .is_some()
+        {
+            discarded += 1;
+        }
+        Ok(discarded)
+    }
+
+    /// Discards the queued messages up to the next resync marker, regardless of their delivery time.
+    fn drain(&self) -> usize {
+        let rx = self.rx.lock();
+        let mut discarded = 0;
+        while let Ok(message) = rx.try_recv() {
+            self.taken();
+            if message.data.is_none() {
+                self.peer_resync
+                    .store(true, std::sync::atomic::Ordering::Relaxed);
+                break;
+            }
+            discarded += 1;
+        }
+        discarded
+    }
+}
+
+impl Drop for TestReceiver {
+    fn drop(&mut self) {
+        if let Some(backlog) = &self.backlog {
+            backlog.close();
+        }
+    }
+}
+
+/// An in-process [`Network`] over channels, e.g., for tests and local simulations.
+///
+/// By default messages are delivered instantly. To estimate the behavior of a protocol over a
+/// realistic WAN, create the networks with [`TestNetwork::networks_with_link_props`], e.g., with
+/// [`LinkMatrix::uniform`] for the same latency and bandwidth on all links.
+/// Set [`LinkProps::capacity`] to bound the queues like socket buffers, so a sender blocks when
+/// its peer can't keep up.
+///
+/// The channels are from the standard library, or from `crossbeam-channel` with the `crossbeam`
+/// feature, which handle high message rates better, e.g., for large in-process benchmarks.
+#[derive(Debug)]
+pub struct TestNetwork {
+    id: usize,
+    num_parties: usize,
+    timeout: Duration,
+    send: IntMap<usize, TestLink>,
+    recv: IntMap<usize, TestReceiver>,
+    stats: IntMap<usize, PeerStats>,
+}
+
+impl TestNetwork {
+    pub fn party_networks(num_parties: usize) -> Vec<Self> {
+        Self::party_networks_with_link_props(&LinkMatrix::new(num_parties))
+    }
+
+    fn party_networks_with_link_props(props: &LinkMatrix) -> Vec<Self> {
+        let num_parties = props.num_parties();
+        let mut networks = Vec::with_capacity(num_parties);
+        let mut senders = Vec::new();
+        let mut receivers = Vec::new();
+
+        for _ in 0..num_parties {
+            senders.push(IntMap::new());
+            receivers.push(IntMap::new());
+        }
+
+        #[allow(clippy::needless_range_loop)]
+        for i in 0..num_parties {
+            for j in 0..num_parties {
+                if i != j {
+                    let (tx, rx) = channel();
+                    let link = props[(i, j)];
+                    let backlog = link
+                        .capacity
+                        .map(|capacity| Arc::new(Backlog::new(capacity)));
+                    senders[i].insert(
+                        j,
+                        TestLink::new(i, j, tx, link, props.seed, backlog.clone()),
+                    );
+                    receivers[j].insert(i, TestReceiver::new(i, rx, backlog));
+                }
+            }
+        }
+
+        for (id, (send, recv)) in senders.into_iter().zip(receivers).enumerate() {
+            let stats = (0..num_parties)
+                .filter(|&other_id| other_id != id)
+                .map(|other_id| (other_id, PeerStats::new(id, other_id, false)))
+                .collect();
+            networks.push(TestNetwork {
+                id,
+                num_parties,
+                timeout: TIMEOUT,
+                send,
+                recv,
+                stats,
+            });
+        }
+
+        networks
+    }
+
+    pub fn networks(num_parties: usize, num: usize) -> Vec<Vec<Self>> {
+        Self::networks_with_link_props(num_parties, num, LinkMatrix::new(num_parties))
+    }
+
+    /// Create `num` networks per party where every directed link `(i, j)` behaves according to `props[(i, j)]`.
+    ///
+    /// A link transmits one message at a time: with a limited bandwidth, a message occupies the
+    /// link for `len / bandwidth` after the previous one, and it becomes available to the
+    /// receiver one latency after it was fully transmitted. A receive waits until then, so the
+    /// wall-clock time of a protocol run reflects the simulated links. The links of the `num`
+    /// networks of a party are simulated independently.
+    pub fn networks_with_link_props(
+        num_parties: usize,
+        num: usize,
+        props: LinkMatrix,
+    ) -> Vec<Vec<Self>> {
+        assert_eq!(
+            props.num_parties(),
+            num_parties,
+            "link matrix must match the number of parties"
+        );
+        let mut nets = (0..num_parties)
+            .map(|_| Vec::with_capacity(num))
+            .collect::<Vec<Vec<_>>>();
+        for _ in 0..num {
+            for (party_id, net) in TestNetwork::party_networks_with_link_props(&props)
+                .into_iter()
+                .enumerate()
+            {
+                nets[party_id].push(net);
+            }
+        }
+        nets
+    }
+
+    /// Fail receives that wait longer than `timeout` for a message, instead of the default 30 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The number of payload bytes sent to the party `to`, there is no framing.
+    pub fn bytes_sent(&self, to: usize) -> u64 {
+        self.stats.get(to).map_or(0, PeerStats::bytes_sent)
+    }
+
+    /// The number of payload bytes received from the party `from`.
+    pub fn bytes_recv(&self, from: usize) -> u64 {
+        self.stats.get(from).map_or(0, PeerStats::bytes_recv)
+    }
+
+    /// The total number of payload bytes sent to and received from all parties.
+    pub fn total_bytes(&self) -> (u64, u64) {
+        self.stats.values().fold((0, 0), |(sent, recv), stats| {
+            (sent + stats.bytes_sent(), recv + stats.bytes_recv())
+        })
+    }
+
+    /// The number of messages sent to the party `to`.
+    pub fn messages_sent(&self, to: usize) -> u64 {
+        self.stats.get(to).map_or(0, PeerStats::messages_sent)
+    }
+
+    /// The number of messages received from the party `from`.
+    pub fn messages_recv(&self, from: usize) -> u64 {
+        self.stats.get(from).map_or(0, PeerStats::messages_recv)
+    }
+
+    /// Reset the communication statistics, e.g., to measure a single protocol phase.
+    pub fn reset_stats(&self) {
+        for stats in self.stats.values() {
+            stats.reset();
+        }
+    }
+}
+
+impl Network for TestNetwork {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn num_parties(&self) -> usize {
+        self.num_parties
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        tracing::trace!(to, len = data.len(), "send");
+        self.send
+            .get(to)
+            .ok_or(NetworkError::UnknownPeer(to))?
+            .send(data, self.timeout)?;
+        if let Some(stats) = self.stats.get(to) {
+            stats.record_send(data.len(), data.len() as u64);
+        }
+        Ok(())
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        // the peer may wait for our held messages before it replies
+        if let Some(link) = self.send.get(from) {
+            link.flush()?;
+        }
+        let data = self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .recv(self.timeout)?;
+        tracing::trace!(from, len = data.len(), "recv");
+        if let Some(stats) = self.stats.get(from) {
+            stats.record_recv(data.len() as u64);
+        }
+        Ok(data)
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        if let Some(link) = self.send.get(from) {
+            link.flush()?;
+        }
+        let receiver = self.recv.get(from).ok_or(NetworkError::UnknownPeer(from))?;
+        // messages arrive as a whole, there is no partial progress
+        let data = match receiver.recv(timeout) {
+            Ok(data) => data,
+            Err(NetworkError::Timeout(_)) => {
+                return Ok(RecvProgress::Partial {
+                    received: 0,
+                    len: None,
+                });
+            }
+            Err(err) => return Err(err),
+        };
+        tracing::trace!(from, len = data.len(), "recv");
+        if let Some(stats) = self.stats.get(from) {
+            stats.record_recv(data.len() as u64);
+        }
+        Ok(RecvProgress::Complete(data))
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        let receiver = self.recv.get(id).ok_or(NetworkError::UnknownPeer(id))?;
+        self.send
+            .get(id)
+            .ok_or(NetworkError::UnknownPeer(id))?
+            .send_resync()?;
+        let discarded = receiver.resync(self.timeout)?;
+        tracing::debug!("resynced with party {id}, discarded {discarded} messages");
+        Ok(())
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        Ok(self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .drain())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct DummyNetwork;
+
+impl DummyNetwork {
+    pub fn networks(num: usize) -> Vec<Self> {
+        vec![DummyNetwork; num]
+    }
+}
+
+impl Network for DummyNetwork {
+    fn id(&self) -> usize {
+        0
+    }
+
+    fn num_parties(&self) -> usize {
+        1
+    }
+
+    fn send(&self, _to: usize, _data: &[u8]) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn recv(&self, _from: usize) -> Result<Vec<u8>, NetworkError> {
+        Ok(vec![])
+    }
+
+    fn recv_timeout(&self, _from: usize, _timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        Ok(RecvProgress::Complete(vec![]))
+    }
+
+    fn resync_peer(&self, _id: usize) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn drain(&self, _from: usize) -> Result<usize, NetworkError> {
+        Ok(0)
+    }
+}
+
+/// A [`Network`] that records all sent messages and replies with canned responses, e.g., to
+/// check the messages a protocol produces without a real transport.
+///
+/// A receive returns the next response queued for the sender with
+/// [`RecordingNetwork::push_response`] and fails if there is none.
+#[derive(Debug)]
+pub struct RecordingNetwork {
+    id: usize,
+    num_parties: usize,
+    sent: Mutex<Vec<(usize, Vec<u8>)>>,
+    responses: Mutex<IntMap<usize, VecDeque<Vec<u8>>>>,
+}
+
+impl RecordingNetwork {
+    /// Create a network for the party `id` out of `num_parties` without any canned responses.
+    pub fn new(id: usize, num_parties: usize) -> Self {
+        Self {
+            id,
+            num_parties,
+            sent: Mutex::default(),
+            responses: Mutex::default(),
+        }
+    }
+
+    /// Queue `data` to be returned by the next receive from the party `from` without a response.
+    pub fn push_response(&self, from: usize, data: Vec<u8>) {
+        self.responses
+            .lock()
+            .entry(from)
+            .or_default()
+            .push_back(data);
+    }
+
+    /// All messages sent so far with their recipient, in the order they were sent.
+    pub fn sent_messages(&self) -> Vec<(usize, Vec<u8>)> {
+        self.sent.lock().clone()
+    }
+
+    fn check_peer(&self, peer: usize) -> Result<(), NetworkError> {
+        if peer == self.id || peer >= self.num_parties {
+            return Err(NetworkError::UnknownPeer(peer));
+        }
+        Ok(())
+    }
+}
+
+impl Network for RecordingNetwork {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn num_parties(&self) -> usize {
+        self.num_parties
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        self.check_peer(to)?;
+        self.sent.lock().push((to, data.to_vec()));
+        Ok(())
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        self.check_peer(from)?;
+        self.responses
+            .lock()
+            .get_mut(from)
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(|| eyre::eyre!("no response from party {from} queued").into())
+    }
+
+    fn recv_timeout(&self, from: usize, _timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        // a receive never waits
+        self.recv(from).map(RecvProgress::Complete)
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        self.check_peer(from)?;
+        Ok(self
+            .responses
+            .lock()
+            .remove(from)
+            .map_or(0, |responses| responses.len()))
     }
 }
@@ -0,0 +1,157 @@
+//! A [`Network`] wrapper that simulates a Byzantine party by corrupting its outgoing messages.
+
+use intmap::IntMap;
+use parking_lot::Mutex;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::{collections::BTreeMap, time::Duration};
+
+use super::{Network, NetworkError, RecvProgress, SizeHistogram};
+
+/// A strategy to corrupt the messages sent by a [`ByzantineNetwork`].
+pub trait Corruption: Send + Sync {
+    /// Returns the corrupted version of `data` that is sent to the party `to` instead.
+    fn corrupt(&self, to: usize, data: &[u8], rng: &mut dyn RngCore) -> Vec<u8>;
+}
+
+impl<F: Fn(usize, &[u8], &mut dyn RngCore) -> Vec<u8> + Send + Sync> Corruption for F {
+    fn corrupt(&self, to: usize, data: &[u8], rng: &mut dyn RngCore) -> Vec<u8> {
+        self(to, data, rng)
+    }
+}
+
+/// Flips the given number of randomly chosen bits, leaving empty messages untouched.
+#[derive(Debug, Clone, Copy)]
+pub struct BitFlip(pub usize);
+
+impl Corruption for BitFlip {
+    fn corrupt(&self, _to: usize, data: &[u8], rng: &mut dyn RngCore) -> Vec<u8> {
+        let mut data = data.to_vec();
+        if !data.is_empty() {
+            for _ in 0..self.0 {
+                let bit = rng.random_range(0..data.len() * 8);
+                data[bit / 8] ^= 1 << (bit % 8);
+            }
+        }
+        data
+    }
+}
+
+/// Truncates messages to a random length shorter than the original.
+#[derive(Debug, Clone, Copy)]
+pub struct Truncate;
+
+impl Corruption for Truncate {
+    fn corrupt(&self, _to: usize, data: &[u8], rng: &mut dyn RngCore) -> Vec<u8> {
+        let len = if data.is_empty() {
+            0
+        } else {
+            rng.random_range(0..data.len())
+        };
+        data[..len].to_vec()
+    }
+}
+
+/// Replaces messages with random bytes of the same length.
+#[derive(Debug, Clone, Copy)]
+pub struct RandomBytes;
+
+impl Corruption for RandomBytes {
+    fn corrupt(&self, _to: usize, data: &[u8], rng: &mut dyn RngCore) -> Vec<u8> {
+        let mut data = vec![0; data.len()];
+        rng.fill_bytes(&mut data);
+        data
+    }
+}
+
+/// A wrapper around a [`Network`] that corrupts all messages sent to a set of target peers.
+///
+/// Every target peer gets its own RNG stream derived from the seed, so the corruption is
+/// reproducible even if messages to different peers are sent concurrently. Messages to all other
+/// peers and all received messages are left untouched.
+pub struct ByzantineNetwork<N, C> {
+    inner: N,
+    corruption: C,
+    rngs: IntMap<usize, Mutex<ChaCha8Rng>>,
+}
+
+impl<N: Network, C: Corruption> ByzantineNetwork<N, C> {
+    /// Wrap `inner`, corrupting all messages to the `targets` according to `corruption`.
+    pub fn new(
+        inner: N,
+        targets: impl IntoIterator<Item = usize>,
+        corruption: C,
+        seed: u64,
+    ) -> Self {
+        let rngs = targets
+            .into_iter()
+            .map(|to| {
+                let mut rng = ChaCha8Rng::seed_from_u64(seed);
+                rng.set_stream(to as u64);
+                (to, Mutex::new(rng))
+            })
+            .collect();
+        Self {
+            inner,
+            corruption,
+            rngs,
+        }
+    }
+
+    /// Returns the inner network.
+    pub fn into_inner(self) -> N {
+        self.inner
+    }
+}
+
+impl<N: Network, C: Corruption> Network for ByzantineNetwork<N, C> {
+    fn id(&self) -> usize {
+        self.inner.id()
+    }
+
+    fn num_parties(&self) -> usize {
+        self.inner.num_parties()
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        match self.rngs.get(to) {
+            Some(rng) => {
+                let data = self.corruption.corrupt(to, data, &mut *rng.lock());
+                self.inner.send(to, &data)
+            }
+            None => self.inner.send(to, data),
+        }
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        self.inner.recv(from)
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        self.inner.recv_timeout(from, timeout)
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        self.inner.resync_peer(id)
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        self.inner.drain(from)
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.flush(to)
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.close(to)
+    }
+
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        self.inner.ping(peer, timeout)
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        self.inner.size_histogram()
+    }
+}
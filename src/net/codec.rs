@@ -0,0 +1,840 @@
+//! The length-prefixed framing shared by the stream based transports.
+//!
+//! Every frame starts with a [`FrameHeader`]: the u32 length of the payload, a byte with the
+//! format version in the upper and the flags in the lower nibble, and the optional fields
+//! announced by the flags in the order of their bits. The payload follows the header. A length
+//! of [`RESYNC_LEN`] instead announces a resync marker without header byte, and a length of
+//! [`CONTROL_LEN`] a control frame of the transport, e.g., a ping, which is handled by the reader
+//! thread and never returned as a message.
+
+use aws_lc_rs::digest;
+use byteorder::{BigEndian, ReadBytesExt};
+use parking_lot::{Mutex, MutexGuard};
+use std::{
+    io::{BufRead, BufReader, ErrorKind, IoSlice, Read, Write},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    time::{Duration, Instant},
+};
+
+use super::{NetworkError, RecvProgress};
+
+/// The version of the frame format, stored in the upper nibble of the header byte.
+const FRAME_VERSION: u8 = 1;
+/// The header contains a CRC32 of the payload.
+const FLAG_CRC: u8 = 1 << 0;
+/// All flags known to this version.
+const KNOWN_FLAGS: u8 = FLAG_CRC;
+
+/// The length of a header with all optional fields.
+const MAX_HEADER_LEN: usize = 9;
+
+const NO_FRAME: usize = usize::MAX;
+/// The number of buffers handed back by [`FrameReader::recv_into`] kept for reuse.
+const MAX_SPARE_BUFFERS: usize = 4;
+
+/// The reserved length prefix announcing a resync marker instead of a frame.
+const RESYNC_LEN: u32 = u32::MAX;
+/// The magic value following [`RESYNC_LEN`].
+const RESYNC_MAGIC: u64 = 0x7265_7379_6e63_2121;
+/// The reserved length prefix announcing a control frame, followed by its kind and a nonce.
+const CONTROL_LEN: u32 = u32::MAX - 1;
+const CONTROL_PING: u8 = 0;
+const CONTROL_PONG: u8 = 1;
+/// The pings that arrive before the sending side answers them, the older ones are dropped.
+const MAX_UNANSWERED_PINGS: usize = 16;
+
+/// The prefix of the hash combining both directions of a transcript.
+const TRANSCRIPT_LABEL: &[u8] = b"mpc-engine transcript";
+
+/// The resync marker as it appears on the wire.
+fn resync_marker() -> [u8; 12] {
+    let mut marker = [0; 12];
+    marker[..4].copy_from_slice(&RESYNC_LEN.to_be_bytes());
+    marker[4..].copy_from_slice(&RESYNC_MAGIC.to_be_bytes());
+    marker
+}
+
+fn write_control<W: Write>(stream: &mut W, kind: u8, nonce: u64) -> std::io::Result<()> {
+    let mut frame = [0; 13];
+    frame[..4].copy_from_slice(&CONTROL_LEN.to_be_bytes());
+    frame[4] = kind;
+    frame[5..].copy_from_slice(&nonce.to_be_bytes());
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+fn header_byte(crc: bool) -> u8 {
+    let mut flags = FRAME_VERSION << 4;
+    if crc {
+        flags |= FLAG_CRC;
+    }
+    flags
+}
+
+/// The header preceding the payload of every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FrameHeader {
+    len: u32,
+    crc: Option<u32>,
+}
+
+impl FrameHeader {
+    /// The header byte announcing the version and the present optional fields.
+    fn flags(&self) -> u8 {
+        header_byte(self.crc.is_some())
+    }
+
+    /// Encodes the header into `buf` and returns its length.
+    fn encode(&self, buf: &mut [u8; MAX_HEADER_LEN]) -> usize {
+        buf[..4].copy_from_slice(&self.len.to_be_bytes());
+        buf[4] = self.flags();
+        if let Some(crc) = self.crc {
+            buf[5..9].copy_from_slice(&crc.to_be_bytes());
+            9
+        } else {
+            5
+        }
+    }
+
+    /// Reads the rest of the header after the length prefix `len`.
+    ///
+    /// Returns `None` if the header byte has an unknown version or flags.
+    fn read<R: Read>(len: u32, stream: &mut R) -> std::io::Result<Option<Self>> {
+        let flags = stream.read_u8()?;
+        if flags >> 4 != FRAME_VERSION || flags & 0x0f & !KNOWN_FLAGS != 0 {
+            return Ok(None);
+        }
+        let crc = if flags & FLAG_CRC != 0 {
+            Some(stream.read_u32::<BigEndian>()?)
+        } else {
+            None
+        };
+        Ok(Some(Self { len, crc }))
+    }
+}
+
+/// The running SHA-256 hashes of the frames exchanged with a peer.
+///
+/// Each direction is hashed separately, so the result does not depend on how sends and receives
+/// interleave, and the directions are combined in the order of the party ids, so both ends
+/// arrive at the same hash.
+pub(crate) struct TranscriptHasher {
+    /// We have the lower id, our frames come first.
+    lower: bool,
+    sent: Mutex<digest::Context>,
+    received: Mutex<digest::Context>,
+}
+
+impl std::fmt::Debug for TranscriptHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TranscriptHasher")
+            .field("lower", &self.lower)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TranscriptHasher {
+    pub(crate) fn new(id: usize, peer: usize) -> Self {
+        Self {
+            lower: id < peer,
+            sent: Mutex::new(digest::Context::new(&digest::SHA256)),
+            received: Mutex::new(digest::Context::new(&digest::SHA256)),
+        }
+    }
+
+    /// Lock the hash of the sent frames, hold it during the send to hash in the order of the wire.
+    pub(crate) fn sent(&self) -> MutexGuard<'_, digest::Context> {
+        self.sent.lock()
+    }
+
+    /// Lock the hash of the received frames, hold it during the receive.
+    pub(crate) fn received(&self) -> MutexGuard<'_, digest::Context> {
+        self.received.lock()
+    }
+
+    /// The hash of both directions up to now.
+    pub(crate) fn finish(&self) -> [u8; 32] {
+        let sent = self.sent.lock().clone().finish();
+        let received = self.received.lock().clone().finish();
+        let (first, second) = if self.lower {
+            (sent, received)
+        } else {
+            (received, sent)
+        };
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(TRANSCRIPT_LABEL);
+        ctx.update(first.as_ref());
+        ctx.update(second.as_ref());
+        ctx.finish().as_ref().try_into().expect("is a SHA-256 hash")
+    }
+}
+
+/// The progress of the frame the reader thread is currently reading.
+#[derive(Debug)]
+struct ReadProgress {
+    /// The announced length, or [`NO_FRAME`] if the reader waits for the next length prefix.
+    len: AtomicUsize,
+    received: AtomicUsize,
+}
+
+#[derive(Debug)]
+enum Incoming {
+    Frame(Vec<u8>),
+    Resync,
+}
+
+/// An operation queued for a writer thread.
+#[derive(Debug)]
+enum Outgoing {
+    Frame(Vec<u8>),
+    Resync,
+    Ping(u64),
+    Pong(u64),
+    /// Flush the stream, with `close` also shut it down, and report the result to `done`.
+    Flush {
+        close: bool,
+        done: oneshot::Sender<std::io::Result<()>>,
+    },
+}
+
+/// The queue of a writer thread, bounded or not.
+#[derive(Debug)]
+enum OutgoingSender {
+    Unbounded(mpsc::Sender<Outgoing>),
+    Bounded(mpsc::SyncSender<Outgoing>),
+}
+
+impl OutgoingSender {
+    fn send(&self, outgoing: Outgoing) -> Result<(), mpsc::SendError<Outgoing>> {
+        match self {
+            OutgoingSender::Unbounded(tx) => tx.send(outgoing),
+            OutgoingSender::Bounded(tx) => tx.send(outgoing),
+        }
+    }
+}
+
+/// The sending end of a writer thread, see [`Codec::spawn_writer`].
+///
+/// Frames are written in the order they were queued. Once a write fails, the thread stops and
+/// every later operation returns its error, queued frames that were not written yet are lost.
+#[derive(Debug)]
+pub(crate) struct FrameWriter {
+    tx: OutgoingSender,
+    /// The kind and message of the error the writer thread stopped with.
+    error: Arc<Mutex<Option<(ErrorKind, String)>>>,
+}
+
+impl FrameWriter {
+    fn queue(&self, outgoing: Outgoing) -> std::io::Result<()> {
+        self.tx.send(outgoing).map_err(|_| self.error())
+    }
+
+    fn error(&self) -> std::io::Error {
+        match &*self.error.lock() {
+            Some((kind, msg)) => std::io::Error::new(*kind, msg.clone()),
+            None => ErrorKind::BrokenPipe.into(),
+        }
+    }
+
+    /// Queues a frame carrying `data`, blocking while a bounded queue is full.
+    pub(crate) fn send(&self, data: Vec<u8>) -> std::io::Result<()> {
+        self.queue(Outgoing::Frame(data))
+    }
+
+    /// Queues a resync marker.
+    pub(crate) fn resync(&self) -> std::io::Result<()> {
+        self.queue(Outgoing::Resync)
+    }
+
+    /// Queues a ping with `nonce`.
+    pub(crate) fn ping(&self, nonce: u64) -> std::io::Result<()> {
+        self.queue(Outgoing::Ping(nonce))
+    }
+
+    /// Queues the answer to the ping with `nonce`.
+    pub(crate) fn pong(&self, nonce: u64) -> std::io::Result<()> {
+        self.queue(Outgoing::Pong(nonce))
+    }
+
+    /// Waits until all queued frames are written and the stream is flushed.
+    pub(crate) fn flush(&self) -> std::io::Result<()> {
+        self.sync(false)
+    }
+
+    /// Like [`FrameWriter::flush`], but also shuts down the stream and stops the writer thread.
+    pub(crate) fn close(&self) -> std::io::Result<()> {
+        self.sync(true)
+    }
+
+    fn sync(&self, close: bool) -> std::io::Result<()> {
+        let (done, rx) = oneshot::channel();
+        self.queue(Outgoing::Flush { close, done })?;
+        rx.recv().map_err(|_| self.error())?
+    }
+}
+
+/// The function answering a ping of the peer with the given nonce.
+type Answer = Box<dyn FnMut(u64) -> std::io::Result<()> + Send>;
+
+/// Where the reader thread hands over the pings of the peer, see [`FrameReader::answer_pings`].
+#[derive(Default)]
+struct PongSlot {
+    answer: Option<Answer>,
+    /// The queue of the thread answering the pings, spawned on the first ping.
+    pings: Option<mpsc::Sender<u64>>,
+    /// The pings that arrived before there was a way to answer them.
+    unanswered: Vec<u64>,
+}
+
+impl std::fmt::Debug for PongSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PongSlot")
+            .field("unanswered", &self.unanswered)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PongSlot {
+    fn pinged(&mut self, nonce: u64) {
+        if self.pings.is_none()
+            && let Some(mut answer) = self.answer.take()
+        {
+            // the reader thread must not wait for a send, whose peer may wait for it in turn
+            let (tx, rx) = mpsc::channel::<u64>();
+            std::thread::spawn(move || {
+                for nonce in rx {
+                    if answer(nonce).is_err() {
+                        break;
+                    }
+                }
+            });
+            self.pings = Some(tx);
+        }
+        match &self.pings {
+            // the thread stops once the sending side is gone
+            Some(pings) => {
+                let _ = pings.send(nonce);
+            }
+            None => {
+                if self.unanswered.len() == MAX_UNANSWERED_PINGS {
+                    self.unanswered.remove(0);
+                }
+                self.unanswered.push(nonce);
+            }
+        }
+    }
+}
+
+/// The receiving end of a reader thread.
+///
+/// The reader thread owns the partially read frame. A receive that times out therefore
+/// never loses any bytes, the next receive simply picks up the frame once it is complete:
+///
+/// 1. The reader waits for the length prefix, no frame is in flight.
+/// 2. After reading the prefix, the announced length is published and the payload is read in
+///    chunks, publishing the number of received bytes after every chunk.
+/// 3. Once the frame is complete (and its checksum verified), it is queued for the receiver and
+///    the reader returns to 1.
+#[derive(Debug)]
+pub(crate) struct FrameReader {
+    peer: usize,
+    rx: Mutex<mpsc::Receiver<Result<Incoming, NetworkError>>>,
+    progress: Arc<ReadProgress>,
+    /// Set if a regular receive consumed the resync marker of the peer.
+    peer_resync: AtomicBool,
+    /// Buffers of received frames that the reader thread reuses for the next frames.
+    spare: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// The nonces of the pongs of the peer, locked during a ping.
+    pongs: Mutex<mpsc::Receiver<u64>>,
+    pings: Arc<Mutex<PongSlot>>,
+}
+
+impl FrameReader {
+    fn frame(&self, incoming: Incoming) -> Result<Vec<u8>, NetworkError> {
+        match incoming {
+            Incoming::Frame(data) => Ok(data),
+            Incoming::Resync => {
+                self.peer_resync.store(true, Ordering::Relaxed);
+                Err(NetworkError::Resync(self.peer))
+            }
+        }
+    }
+
+    fn error(&self, err: RecvTimeoutError) -> NetworkError {
+        match err {
+            RecvTimeoutError::Timeout => NetworkError::Timeout(self.peer),
+            // the reader thread stops when the stream is closed or fails
+            RecvTimeoutError::Disconnected => NetworkError::PeerClosed(self.peer),
+        }
+    }
+
+    pub(crate) fn recv(&self, timeout: Duration) -> Result<Vec<u8>, NetworkError> {
+        let incoming = self
+            .rx
+            .lock()
+            .recv_timeout(timeout)
+            .map_err(|err| self.error(err))??;
+        self.frame(incoming)
+    }
+
+    /// Receives the next frame into `buf` and hands the previous buffer to the reader thread.
+    pub(crate) fn recv_into(
+        &self,
+        timeout: Duration,
+        buf: &mut Vec<u8>,
+    ) -> Result<usize, NetworkError> {
+        let spare = std::mem::replace(buf, self.recv(timeout)?);
+        let mut buffers = self.spare.lock();
+        if buffers.len() < MAX_SPARE_BUFFERS && spare.capacity() > 0 {
+            buffers.push(spare);
+        }
+        Ok(buf.len())
+    }
+
+    pub(crate) fn recv_partial(&self, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        match self.rx.lock().recv_timeout(timeout) {
+            Ok(incoming) => Ok(RecvProgress::Complete(self.frame(incoming?)?)),
+            Err(RecvTimeoutError::Timeout) => {
+                let len = self.progress.len.load(Ordering::Relaxed);
+                if len == NO_FRAME {
+                    Ok(RecvProgress::Partial {
+                        received: 0,
+                        len: None,
+                    })
+                } else {
+                    Ok(RecvProgress::Partial {
+                        received: self.progress.received.load(Ordering::Relaxed),
+                        len: Some(len),
+                    })
+                }
+            }
+            Err(err) => Err(self.error(err)),
+        }
+    }
+
+    /// Answer the pings of the peer with `answer`, including the ones that arrived already.
+    ///
+    /// `answer` sends a pong with the nonce of the ping on the sending side to the peer. It runs
+    /// on a thread of its own, so it may wait for concurrent sends. The thread stops once it fails.
+    pub(crate) fn answer_pings(
+        &self,
+        answer: impl FnMut(u64) -> std::io::Result<()> + Send + 'static,
+    ) {
+        let mut slot = self.pings.lock();
+        slot.answer = Some(Box::new(answer));
+        for nonce in std::mem::take(&mut slot.unanswered) {
+            slot.pinged(nonce);
+        }
+    }
+
+    /// Sends a ping with `send` and waits up to `timeout` for the pong, returning the round-trip time.
+    ///
+    /// Concurrent pings of the same peer wait for each other, and pongs of earlier pings that
+    /// timed out are skipped.
+    pub(crate) fn ping(
+        &self,
+        timeout: Duration,
+        send: impl FnOnce(u64) -> std::io::Result<()>,
+    ) -> Result<Duration, NetworkError> {
+        let pongs = self.pongs.lock();
+        let nonce = rand::random();
+        let start = Instant::now();
+        let deadline = start + timeout;
+        send(nonce).map_err(|err| NetworkError::from_io(self.peer, err))?;
+        loop {
+            let pong = pongs
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .map_err(|err| self.error(err))?;
+            if pong == nonce {
+                return Ok(start.elapsed());
+            }
+        }
+    }
+
+    /// Discards all complete frames that are queued, stopping at the resync marker of the peer.
+    ///
+    /// Returns the number of discarded frames.
+    pub(crate) fn drain(&self) -> usize {
+        self.drain_frames(|_| {})
+    }
+
+    /// Like [`FrameReader::drain`], but passes the discarded frames to `inspect`.
+    pub(crate) fn drain_frames(&self, mut inspect: impl FnMut(&[u8])) -> usize {
+        let rx = self.rx.lock();
+        let mut discarded = 0;
+        while let Ok(incoming) = rx.try_recv() {
+            match incoming {
+                Ok(Incoming::Resync) => {
+                    self.peer_resync.store(true, Ordering::Relaxed);
+                    break;
+                }
+                Ok(Incoming::Frame(data)) => {
+                    inspect(&data);
+                    discarded += 1;
+                }
+                Err(_) => discarded += 1,
+            }
+        }
+        discarded
+    }
+
+    /// Discards all incoming frames until the resync marker of the peer arrives.
+    ///
+    /// Returns the number of discarded frames.
+    pub(crate) fn resync(&self, timeout: Duration) -> Result<usize, NetworkError> {
+        let rx = self.rx.lock();
+        if self.peer_resync.swap(false, Ordering::Relaxed) {
+            return Ok(0);
+        }
+        let deadline = Instant::now() + timeout;
+        let mut discarded = 0;
+        loop {
+            match rx
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .map_err(|err| self.error(err))?
+            {
+                Ok(Incoming::Resync) => return Ok(discarded),
+                // corrupt frames are skipped while the reader scans for the marker
+                Ok(Incoming::Frame(_)) | Err(_) => discarded += 1,
+            }
+        }
+    }
+}
+
+/// What the reader thread needs to handle control frames.
+struct Control {
+    pongs: mpsc::Sender<u64>,
+    pings: Arc<Mutex<PongSlot>>,
+}
+
+/// The framing options of a connection, agreed upon during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Codec {
+    /// Add a CRC32 of the payload to every frame header.
+    pub(crate) crc: bool,
+    /// Frames with a longer payload are rejected before allocating a buffer for them.
+    pub(crate) max_message_size: usize,
+}
+
+impl Codec {
+    /// The header of a frame carrying `data`.
+    fn header(&self, data: &[u8]) -> FrameHeader {
+        FrameHeader {
+            len: data.len() as u32,
+            crc: self.crc.then(|| crc32fast::hash(data)),
+        }
+    }
+
+    /// The number of bytes a frame with a payload of `len` bytes takes up on the wire.
+    pub(crate) fn frame_len(&self, len: usize) -> u64 {
+        let header_len = if self.crc { 9 } else { 5 };
+        (header_len + len) as u64
+    }
+
+    /// The header byte of all frames, also sent in the handshake.
+    pub(crate) fn flags(&self) -> u8 {
+        header_byte(self.crc)
+    }
+
+    /// Checks that the flags announced by `peer` match our own.
+    pub(crate) fn check_flags(&self, peer: usize, flags: u8) -> eyre::Result<()> {
+        if flags != self.flags() {
+            eyre::bail!(
+                "party {peer} uses codec flags {flags:#04x}, but we expect {:#04x}",
+                self.flags()
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_frame<W: Write>(&self, stream: &mut W, data: &[u8]) -> std::io::Result<()> {
+        if data.len() >= CONTROL_LEN as usize {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "message exceeds the maximum frame length",
+            ));
+        }
+        let mut header = [0; MAX_HEADER_LEN];
+        let header_len = self.header(data).encode(&mut header);
+        // write the header and the payload at once, a single syscall for small frames
+        let mut bufs = [IoSlice::new(&header[..header_len]), IoSlice::new(data)];
+        let mut bufs = &mut bufs[..];
+        while !bufs.is_empty() {
+            match stream.write_vectored(bufs) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the frame carrying `data` to the transcript hash `ctx`, exactly as it is on the wire.
+    pub(crate) fn hash_frame(&self, ctx: &mut digest::Context, data: &[u8]) {
+        let mut header = [0; MAX_HEADER_LEN];
+        let header_len = self.header(data).encode(&mut header);
+        ctx.update(&header[..header_len]);
+        ctx.update(data);
+    }
+
+    pub(crate) fn write_resync<W: Write>(&self, stream: &mut W) -> std::io::Result<()> {
+        stream.write_all(&resync_marker())
+    }
+
+    pub(crate) fn write_ping<W: Write>(&self, stream: &mut W, nonce: u64) -> std::io::Result<()> {
+        write_control(stream, CONTROL_PING, nonce)
+    }
+
+    pub(crate) fn write_pong<W: Write>(&self, stream: &mut W, nonce: u64) -> std::io::Result<()> {
+        write_control(stream, CONTROL_PONG, nonce)
+    }
+
+    /// Spawns a thread that writes the frames queued with the returned [`FrameWriter`] to `stream`.
+    ///
+    /// With a `capacity`, queueing blocks while that many operations are queued. `shutdown` shuts
+    /// down the stream for [`FrameWriter::close`]. The thread stops once the writer is dropped and
+    /// all queued frames are written.
+    pub(crate) fn spawn_writer<W: Write + Send + 'static>(
+        self,
+        mut stream: W,
+        capacity: Option<usize>,
+        shutdown: fn(&mut W) -> std::io::Result<()>,
+    ) -> FrameWriter {
+        let (tx, rx) = match capacity {
+            Some(capacity) => {
+                let (tx, rx) = mpsc::sync_channel(capacity);
+                (OutgoingSender::Bounded(tx), rx)
+            }
+            None => {
+                let (tx, rx) = mpsc::channel();
+                (OutgoingSender::Unbounded(tx), rx)
+            }
+        };
+        let error = Arc::new(Mutex::new(None));
+        let writer_error = Arc::clone(&error);
+        std::thread::spawn(move || {
+            for outgoing in rx {
+                let (res, done, close) = match outgoing {
+                    Outgoing::Frame(data) => (self.write_frame(&mut stream, &data), None, false),
+                    Outgoing::Resync => (self.write_resync(&mut stream), None, false),
+                    Outgoing::Ping(nonce) => (self.write_ping(&mut stream, nonce), None, false),
+                    Outgoing::Pong(nonce) => (self.write_pong(&mut stream, nonce), None, false),
+                    Outgoing::Flush { close, done } => {
+                        let mut res = stream.flush();
+                        if close {
+                            res = res.and_then(|()| shutdown(&mut stream));
+                        }
+                        (res, Some(done), close)
+                    }
+                };
+                let stop = match &res {
+                    Err(err) => Some((err.kind(), err.to_string())),
+                    Ok(()) if close => Some((
+                        ErrorKind::BrokenPipe,
+                        "the connection was closed".to_owned(),
+                    )),
+                    Ok(()) => None,
+                };
+                // stored before the queue is dropped, so a failed queueing finds the error
+                let stopped = stop.is_some();
+                if stopped {
+                    *writer_error.lock() = stop;
+                }
+                if let Some(done) = done {
+                    let _ = done.send(res);
+                }
+                if stopped {
+                    break;
+                }
+            }
+        });
+        FrameWriter { tx, error }
+    }
+
+    /// Spawns a thread that reads frames from `stream` and forwards them to the returned [`FrameReader`].
+    ///
+    /// A corrupt frame is forwarded as [`NetworkError::Corrupt`]. Because the frame alignment can
+    /// no longer be trusted afterwards, the thread then scans the stream for the next resync marker.
+    /// A frame longer than the maximum message size is forwarded as [`NetworkError::MessageTooLarge`] and
+    /// its payload is skipped without buffering it.
+    ///
+    /// If the stream ends between two frames, the thread stops and the receives fail with
+    /// [`NetworkError::PeerClosed`] once the queued frames are consumed. If it ends anywhere else,
+    /// e.g., because the peer crashed in the middle of a send, [`NetworkError::TruncatedMessage`]
+    /// is forwarded first. Any other error of the stream is forwarded as well.
+    pub(crate) fn spawn_reader<R: Read + Send + 'static>(
+        self,
+        stream: R,
+        peer: usize,
+    ) -> FrameReader {
+        let (tx, rx) = mpsc::channel();
+        let progress = Arc::new(ReadProgress {
+            len: AtomicUsize::new(NO_FRAME),
+            received: AtomicUsize::new(0),
+        });
+        let reader_progress = Arc::clone(&progress);
+        let spare = Arc::new(Mutex::new(Vec::<Vec<u8>>::new()));
+        let reader_spare = Arc::clone(&spare);
+        let (pong_tx, pong_rx) = mpsc::channel();
+        let pings = Arc::new(Mutex::new(PongSlot::default()));
+        let control = Control {
+            pongs: pong_tx,
+            pings: Arc::clone(&pings),
+        };
+        std::thread::spawn(move || {
+            let mut stream = BufReader::new(stream);
+            if let Err(err) = self.read_frames(
+                &mut stream,
+                peer,
+                &tx,
+                &control,
+                &reader_progress,
+                &reader_spare,
+            ) {
+                reader_progress.len.store(NO_FRAME, Ordering::Relaxed);
+                let err = match err.kind() {
+                    ErrorKind::UnexpectedEof => NetworkError::TruncatedMessage(peer),
+                    _ => NetworkError::from_io(peer, err),
+                };
+                // the network may already be dropped
+                let _ = tx.send(Err(err));
+            }
+        });
+        FrameReader {
+            peer,
+            rx: Mutex::new(rx),
+            progress,
+            peer_resync: AtomicBool::new(false),
+            spare,
+            pongs: Mutex::new(pong_rx),
+            pings,
+        }
+    }
+
+    /// Reads frames from `stream` and forwards them to `tx` until the stream ends between two
+    /// frames or the receiver is dropped, see [`Codec::spawn_reader`].
+    fn read_frames<R: Read>(
+        self,
+        stream: &mut BufReader<R>,
+        peer: usize,
+        tx: &mpsc::Sender<Result<Incoming, NetworkError>>,
+        control: &Control,
+        progress: &ReadProgress,
+        spare: &Mutex<Vec<Vec<u8>>>,
+    ) -> std::io::Result<()> {
+        let mut scanning = false;
+        loop {
+            if scanning {
+                let marker = resync_marker();
+                let mut window = [0; 12];
+                stream.read_exact(&mut window)?;
+                while window != marker {
+                    window.copy_within(1.., 0);
+                    window[11] = stream.read_u8()?;
+                }
+                scanning = false;
+                if tx.send(Ok(Incoming::Resync)).is_err() {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            // the peer closed the connection between two frames
+            if stream.fill_buf()?.is_empty() {
+                return Ok(());
+            }
+            let len = stream.read_u32::<BigEndian>()?;
+            if len == RESYNC_LEN {
+                let incoming = if stream.read_u64::<BigEndian>()? == RESYNC_MAGIC {
+                    Ok(Incoming::Resync)
+                } else {
+                    scanning = true;
+                    Err(NetworkError::Corrupt(peer))
+                };
+                if tx.send(incoming).is_err() {
+                    return Ok(());
+                }
+                continue;
+            }
+            if len == CONTROL_LEN {
+                let kind = stream.read_u8()?;
+                let nonce = stream.read_u64::<BigEndian>()?;
+                match kind {
+                    CONTROL_PING => control.pings.lock().pinged(nonce),
+                    // nobody waits for the pong of a ping that timed out
+                    CONTROL_PONG => {
+                        let _ = control.pongs.send(nonce);
+                    }
+                    _ => {
+                        scanning = true;
+                        if tx.send(Err(NetworkError::Corrupt(peer))).is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let header = match FrameHeader::read(len, stream)? {
+                Some(header) if header.flags() == self.flags() => header,
+                _ => {
+                    scanning = true;
+                    if tx.send(Err(NetworkError::Corrupt(peer))).is_err() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            };
+
+            let len = len as usize;
+            if len > self.max_message_size {
+                let skipped =
+                    std::io::copy(&mut (&mut *stream).take(len as u64), &mut std::io::sink())?;
+                if skipped < len as u64 {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                let err = NetworkError::MessageTooLarge {
+                    peer,
+                    len,
+                    max: self.max_message_size,
+                };
+                if tx.send(Err(err)).is_err() {
+                    return Ok(());
+                }
+                continue;
+            }
+            progress.received.store(0, Ordering::Relaxed);
+            progress.len.store(len, Ordering::Relaxed);
+            let mut data = spare.lock().pop().unwrap_or_default();
+            data.clear();
+            data.resize(len, 0);
+            let mut received = 0;
+            while received < len {
+                match stream.read(&mut data[received..]) {
+                    Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                    Ok(n) => received += n,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                }
+                progress.received.store(received, Ordering::Relaxed);
+            }
+            progress.len.store(NO_FRAME, Ordering::Relaxed);
+
+            let incoming = if header.crc.is_some_and(|crc| crc != crc32fast::hash(&data)) {
+                scanning = true;
+                Err(NetworkError::Corrupt(peer))
+            } else {
+                Ok(Incoming::Frame(data))
+            };
+            if tx.send(incoming).is_err() {
+                // the network was dropped
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! A [`Network`] wrapper that compresses messages with zstd.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use super::{Network, NetworkConfig, NetworkError, RecvProgress, SizeHistogram};
+
+/// The payload precedes the trailer byte as is.
+const RAW: u8 = 0;
+/// The trailer byte is preceded by the zstd frame and the uncompressed length as big-endian `u32`.
+const ZSTD: u8 = 1;
+
+/// A wrapper that compresses the messages of an inner [`Network`] with zstd.
+///
+/// Every message is followed by a trailer byte, compressed messages additionally by their
+/// uncompressed length. The trailer can be stripped without moving the payload. Messages shorter
+/// than the threshold, or that would not get shorter, are sent uncompressed, so small control
+/// messages only pay a single byte. Both parties have to wrap their networks in a
+/// [`CompressedNetwork`], the level and threshold only affect the sending side.
+///
+/// A received message is decompressed to at most the maximum message size, so a small frame can't
+/// expand past the limit the inner network enforces on the compressed bytes.
+#[derive(Debug)]
+pub struct CompressedNetwork<N> {
+    inner: N,
+    level: i32,
+    threshold: usize,
+    max_message_size: usize,
+}
+
+impl<N: Network> CompressedNetwork<N> {
+    /// Construct a new [`CompressedNetwork`] compressing the messages of at least `threshold`
+    /// bytes with the zstd `level`, where `0` selects the zstd default level.
+    ///
+    /// Decompressed messages are limited to the default [`NetworkConfig::max_message_size`].
+    pub fn new(inner: N, level: i32, threshold: usize) -> Self {
+        let max_message_size = NetworkConfig::default().max_message_size;
+        Self::with_max_message_size(inner, level, threshold, max_message_size)
+    }
+
+    /// Like [`CompressedNetwork::new`], but limits decompressed messages to `max_message_size`
+    /// bytes, usually the [`NetworkConfig::max_message_size`] the inner network was created with.
+    pub fn with_max_message_size(
+        inner: N,
+        level: i32,
+        threshold: usize,
+        max_message_size: usize,
+    ) -> Self {
+        Self {
+            inner,
+            level,
+            threshold,
+            max_message_size,
+        }
+    }
+
+    /// Returns the inner network.
+    pub fn into_inner(self) -> N {
+        self.inner
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, NetworkError> {
+        if data.len() >= self.threshold
+            && let Ok(len) = u32::try_from(data.len())
+        {
+            let compressed = zstd::bulk::compress(data, self.level).map_err(eyre::Report::new)?;
+            if compressed.len() + 4 < data.len() {
+                let mut buf = compressed;
+                buf.extend_from_slice(&len.to_be_bytes());
+                buf.push(ZSTD);
+                return Ok(buf);
+            }
+        }
+        let mut buf = Vec::with_capacity(data.len() + 1);
+        buf.extend_from_slice(data);
+        buf.push(RAW);
+        Ok(buf)
+    }
+
+    fn decode(&self, from: usize, mut data: Vec<u8>) -> Result<Vec<u8>, NetworkError> {
+        match data.pop() {
+            Some(RAW) => Ok(data),
+            Some(ZSTD) if data.len() >= 4 => {
+                let (frame, len) = data.split_at(data.len() - 4);
+                let len = u32::from_be_bytes(len.try_into().expect("4 bytes")) as usize;
+                if len > self.max_message_size {
+                    return Err(NetworkError::MessageTooLarge {
+                        peer: from,
+                        len,
+                        max: self.max_message_size,
+                    });
+                }
+                // the capacity bounds the output, a frame claiming less than it holds fails
+                let decompressed =
+                    zstd::bulk::decompress(frame, len).map_err(|_| NetworkError::Corrupt(from))?;
+                if decompressed.len() != len {
+                    return Err(NetworkError::Corrupt(from));
+                }
+                Ok(decompressed)
+            }
+            _ => Err(NetworkError::Corrupt(from)),
+        }
+    }
+}
+
+impl<N: Network> Network for CompressedNetwork<N> {
+    fn id(&self) -> usize {
+        self.inner.id()
+    }
+
+    fn num_parties(&self) -> usize {
+        self.inner.num_parties()
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        self.inner.send(to, &self.encode(data)?)
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        let data = self.inner.recv(from)?;
+        self.decode(from, data)
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        // the progress of a partial receive counts the compressed bytes
+        match self.inner.recv_timeout(from, timeout)? {
+            RecvProgress::Complete(data) => Ok(RecvProgress::Complete(self.decode(from, data)?)),
+            partial => Ok(partial),
+        }
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        self.inner.resync_peer(id)
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        self.inner.drain(from)
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.flush(to)
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.close(to)
+    }
+
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        self.inner.ping(peer, timeout)
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        self.inner.size_histogram()
+    }
+}
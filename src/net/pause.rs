@@ -0,0 +1,173 @@
+//! A [`Network`] wrapper that can temporarily pause traffic to individual peers.
+
+use parking_lot::{Condvar, Mutex};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use super::{Network, NetworkError, RecvProgress, SizeHistogram};
+
+#[derive(Debug, Default)]
+struct PeerState {
+    paused: bool,
+    buffered: VecDeque<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+struct Peer {
+    state: Mutex<PeerState>,
+    resumed: Condvar,
+    /// Held while sending to the peer, so a send can't overtake the buffered messages.
+    sending: Mutex<()>,
+}
+
+/// A wrapper around a [`Network`] that can quiesce the traffic to a peer without tearing down the connection.
+///
+/// While a peer is paused, up to `capacity` outgoing messages are buffered and further sends
+/// block until the peer is resumed. Receives from a paused peer block as well. Messages keep
+/// arriving at the inner network in the meantime, so no framing state is lost.
+///
+/// A paused [`Network::recv`] only starts waiting on the inner network once the peer is
+/// resumed, so the pause itself never trips a receive timeout on this side. The peer, however,
+/// does not know about the pause and its receives will time out if the pause outlasts its timeout.
+#[derive(Debug)]
+pub struct PausableNetwork<N> {
+    inner: N,
+    capacity: usize,
+    peers: Vec<Peer>,
+}
+
+impl<N: Network> PausableNetwork<N> {
+    /// Wrap `inner`, buffering at most `capacity` messages per paused peer.
+    pub fn new(inner: N, capacity: usize) -> Self {
+        let peers = (0..inner.num_parties()).map(|_| Peer::default()).collect();
+        Self {
+            inner,
+            capacity,
+            peers,
+        }
+    }
+
+    /// Pause all traffic to and from the party `id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not a valid party id.
+    pub fn pause_peer(&self, id: usize) {
+        self.peers[id].state.lock().paused = true;
+    }
+
+    /// Resume the traffic to and from the party `id`, sending all buffered messages first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not a valid party id.
+    pub fn resume_peer(&self, id: usize) -> eyre::Result<()> {
+        let peer = &self.peers[id];
+        let _sending = peer.sending.lock();
+        loop {
+            let mut state = peer.state.lock();
+            let Some(data) = state.buffered.pop_front() else {
+                state.paused = false;
+                peer.resumed.notify_all();
+                return Ok(());
+            };
+            drop(state);
+            if let Err(err) = self.inner.send(id, &data) {
+                peer.state.lock().buffered.push_front(data);
+                return Err(err.into());
+            }
+        }
+    }
+
+    /// Whether the party `id` is currently paused.
+    pub fn is_paused(&self, id: usize) -> bool {
+        self.peers
+            .get(id)
+            .is_some_and(|peer| peer.state.lock().paused)
+    }
+
+    fn wait_resumed(&self, id: usize) {
+        if let Some(peer) = self.peers.get(id) {
+            let mut state = peer.state.lock();
+            while state.paused {
+                peer.resumed.wait(&mut state);
+            }
+        }
+    }
+
+    /// Returns the inner network, dropping all buffered messages.
+    pub fn into_inner(self) -> N {
+        self.inner
+    }
+}
+
+impl<N: Network> Network for PausableNetwork<N> {
+    fn id(&self) -> usize {
+        self.inner.id()
+    }
+
+    fn num_parties(&self) -> usize {
+        self.inner.num_parties()
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        let Some(peer) = self.peers.get(to) else {
+            return self.inner.send(to, data);
+        };
+        loop {
+            let sending = peer.sending.lock();
+            let mut state = peer.state.lock();
+            if !state.paused {
+                drop(state);
+                return self.inner.send(to, data);
+            }
+            if state.buffered.len() < self.capacity {
+                state.buffered.push_back(data.to_owned());
+                return Ok(());
+            }
+            // a resume needs the send lock to flush the buffer
+            drop(sending);
+            peer.resumed.wait(&mut state);
+        }
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        self.wait_resumed(from);
+        self.inner.recv(from)
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        self.wait_resumed(from);
+        self.inner.recv_timeout(from, timeout)
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        // buffered messages belong to the rounds that are being discarded
+        if let Some(peer) = self.peers.get(id) {
+            peer.state.lock().buffered.clear();
+        }
+        self.inner.resync_peer(id)
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        self.inner.drain(from)
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.flush(to)
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.close(to)
+    }
+
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        self.inner.ping(peer, timeout)
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        self.inner.size_histogram()
+    }
+}
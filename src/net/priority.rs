@@ -0,0 +1,228 @@
+//! A [`Network`] wrapper that lets small control messages overtake large messages.
+
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use super::{Network, NetworkConfig, NetworkError, SizeHistogram};
+
+const KIND_CONTROL: u8 = 0;
+const KIND_CHUNK: u8 = 1;
+const KIND_LAST_CHUNK: u8 = 2;
+
+#[derive(Debug, Default)]
+struct PeerRecv {
+    /// The chunks of the bulk message that is currently being reassembled.
+    partial: Vec<u8>,
+    /// The rest of the bulk message is dropped, it exceeded the maximum message size.
+    oversized: bool,
+    bulk: VecDeque<Vec<u8>>,
+    control: VecDeque<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+struct Peer {
+    /// Held during a bulk send, so the chunks of two bulk messages never interleave.
+    send: Mutex<()>,
+    /// Held while reading a frame from the inner network.
+    reader: Mutex<()>,
+    recv: Mutex<PeerRecv>,
+}
+
+/// A wrapper around a [`Network`] that sends regular messages in chunks, so control messages sent
+/// with [`PriorityNetwork::send_control`] can be slipped in between the chunks of a large message.
+///
+/// Every frame of the inner network starts with a kind byte:
+///
+/// * `0`: a complete control message.
+/// * `1`: a chunk of a regular message, more chunks follow.
+/// * `2`: the last chunk of a regular message.
+///
+/// The receiver appends chunks to the message being reassembled until the last chunk arrives.
+/// Control frames may arrive between any two chunks and are queued separately, so
+/// [`Network::recv`] only returns regular messages and [`PriorityNetwork::recv_control`] only
+/// returns control messages. Both sides must use a `PriorityNetwork`.
+///
+/// The inner network only bounds the size of a single chunk, so a regular message that grows past
+/// the maximum message size while it is reassembled is rejected and the rest of it is dropped.
+#[derive(Debug)]
+pub struct PriorityNetwork<N> {
+    inner: N,
+    chunk_size: usize,
+    max_message_size: usize,
+    peers: Vec<Peer>,
+}
+
+impl<N: Network> PriorityNetwork<N> {
+    /// Wrap `inner`, sending regular messages in chunks of at most `chunk_size` bytes.
+    ///
+    /// Reassembled messages are limited to the default [`NetworkConfig::max_message_size`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn new(inner: N, chunk_size: usize) -> Self {
+        let max_message_size = NetworkConfig::default().max_message_size;
+        Self::with_max_message_size(inner, chunk_size, max_message_size)
+    }
+
+    /// Like [`PriorityNetwork::new`], but limits reassembled messages to `max_message_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn with_max_message_size(inner: N, chunk_size: usize, max_message_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk size must not be zero");
+        let peers = (0..inner.num_parties()).map(|_| Peer::default()).collect();
+        Self {
+            inner,
+            chunk_size,
+            max_message_size,
+            peers,
+        }
+    }
+
+    /// Send the control message `data` to `to`, overtaking a regular message that is being sent.
+    pub fn send_control(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        let mut frame = Vec::with_capacity(data.len() + 1);
+        frame.push(KIND_CONTROL);
+        frame.extend_from_slice(data);
+        self.inner.send(to, &frame)
+    }
+
+    /// Receive the next control message from `from`, queueing regular messages that arrive first.
+    pub fn recv_control(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        self.recv_queued(from, |state| &mut state.control)
+    }
+
+    /// Returns the inner network.
+    pub fn into_inner(self) -> N {
+        self.inner
+    }
+
+    fn peer(&self, id: usize) -> Result<&Peer, NetworkError> {
+        self.peers.get(id).ok_or(NetworkError::UnknownPeer(id))
+    }
+
+    /// Reads frames from `from` until the queue selected by `queue` has a message.
+    fn recv_queued(
+        &self,
+        from: usize,
+        queue: impl Fn(&mut PeerRecv) -> &mut VecDeque<Vec<u8>>,
+    ) -> Result<Vec<u8>, NetworkError> {
+        let peer = self.peer(from)?;
+        loop {
+            if let Some(data) = queue(&mut peer.recv.lock()).pop_front() {
+                return Ok(data);
+            }
+            // another receiver may have queued our message while we waited for the reader
+            let _reader = peer.reader.lock();
+            if let Some(data) = queue(&mut peer.recv.lock()).pop_front() {
+                return Ok(data);
+            }
+            let frame = self.inner.recv(from)?;
+            let (kind, data) = frame
+                .split_first()
+                .ok_or_else(|| eyre::eyre!("party {from} sent an empty frame"))?;
+            let mut state = peer.recv.lock();
+            match *kind {
+                KIND_CONTROL => state.control.push_back(data.to_vec()),
+                KIND_CHUNK | KIND_LAST_CHUNK => {
+                    let last = *kind == KIND_LAST_CHUNK;
+                    if state.oversized {
+                        state.oversized = !last;
+                        continue;
+                    }
+                    let len = state.partial.len() + data.len();
+                    if len > self.max_message_size {
+                        state.partial = Vec::new();
+                        state.oversized = !last;
+                        return Err(NetworkError::MessageTooLarge {
+                            peer: from,
+                            len,
+                            max: self.max_message_size,
+                        });
+                    }
+                    state.partial.extend_from_slice(data);
+                    if last {
+                        let message = std::mem::take(&mut state.partial);
+                        state.bulk.push_back(message);
+                    }
+                }
+                kind => {
+                    return Err(
+                        eyre::eyre!("party {from} sent a frame of unknown kind {kind}").into(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl<N: Network> Network for PriorityNetwork<N> {
+    fn id(&self) -> usize {
+        self.inner.id()
+    }
+
+    fn num_parties(&self) -> usize {
+        self.inner.num_parties()
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        let _send = self.peer(to)?.send.lock();
+        let mut chunks = data.chunks(self.chunk_size).peekable();
+        if chunks.peek().is_none() {
+            return self.inner.send(to, &[KIND_LAST_CHUNK]);
+        }
+        let mut frame = Vec::with_capacity(self.chunk_size.min(data.len()) + 1);
+        while let Some(chunk) = chunks.next() {
+            frame.clear();
+            frame.push(if chunks.peek().is_some() {
+                KIND_CHUNK
+            } else {
+                KIND_LAST_CHUNK
+            });
+            frame.extend_from_slice(chunk);
+            self.inner.send(to, &frame)?;
+        }
+        Ok(())
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        self.recv_queued(from, |state| &mut state.bulk)
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        *self.peer(id)?.recv.lock() = PeerRecv::default();
+        self.inner.resync_peer(id)
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        let queued = {
+            let mut state = self.peer(from)?.recv.lock();
+            let queued = state.bulk.len() + state.control.len();
+            *state = PeerRecv::default();
+            queued
+        };
+        // counts inner frames, i.e., chunks and control messages
+        Ok(queued + self.inner.drain(from)?)
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.flush(to)
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.close(to)
+    }
+
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        self.inner.ping(peer, timeout)
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        self.inner.size_histogram()
+    }
+}
@@ -0,0 +1,488 @@
+//! A [`Network`] over QUIC, multiplexing all networks of a party over one connection per peer.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use eyre::ContextCompat;
+use intmap::IntMap;
+use parking_lot::Mutex;
+use quinn::{
+    ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig, TransportConfig,
+    VarInt,
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Read, Write},
+    net::ToSocketAddrs,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::runtime::Runtime;
+
+use super::{
+    Address, Network, NetworkConfig, NetworkError, RecvProgress, SizeHistogram,
+    codec::{Codec, FrameReader},
+    stats::{self, PeerStats},
+};
+
+/// The network index, the party id and the codec flags.
+const PREAMBLE_LEN: usize = 17;
+/// Keeps idle connections open, e.g., while the parties compute locally.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The endpoint of a party and the runtime driving it, shared by all of its streams.
+#[derive(Debug)]
+struct Driver {
+    endpoint: Endpoint,
+    runtime: Runtime,
+}
+
+/// The receiving half of a QUIC stream, blocking on the runtime.
+#[derive(Debug)]
+struct QuicRecv {
+    stream: RecvStream,
+    driver: Arc<Driver>,
+}
+
+impl Read for QuicRecv {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.driver.runtime.block_on(self.stream.read(buf))?;
+        // `None` marks the end of the stream
+        Ok(read.unwrap_or(0))
+    }
+}
+
+/// The sending half of a QUIC stream, blocking on the runtime.
+#[derive(Debug)]
+struct QuicSend {
+    stream: SendStream,
+    driver: Arc<Driver>,
+    write_timeout: Duration,
+}
+
+impl Write for QuicSend {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let write =
+            async { tokio::time::timeout(self.write_timeout, self.stream.write(buf)).await };
+        match self.driver.runtime.block_on(write) {
+            Ok(written) => Ok(written?),
+            Err(_) => Err(ErrorKind::TimedOut.into()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl QuicSend {
+    /// Finishes the stream, the peer reads the end of the stream after all sent data.
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.stream
+            .finish()
+            .map_err(|err| std::io::Error::new(ErrorKind::BrokenPipe, err))
+    }
+}
+
+/// A [`Network`] over QUIC, for high latency links between the parties.
+///
+/// Every party has a single UDP endpoint and a single connection to each peer, authenticated
+/// with the same certificates as [`TlsNetwork`](super::TlsNetwork). Each of the `num` networks
+/// uses its own bidirectional stream on that connection, so a large message on one network does
+/// not hold up the messages of the others, as it would on a shared TCP stream. Within a stream,
+/// messages use the same framing as the other stream based transports.
+///
+/// The connecting party opens the streams and sends the network index, its id and the codec
+/// flags on each of them. With [`NetworkConfig::mutual_tls`] and
+/// [`NetworkConfig::peer_identities`], the claimed id is checked against the certificate of
+/// the peer.
+#[derive(Debug)]
+pub struct QuicNetwork {
+    id: usize,
+    num_parties: usize,
+    codec: Codec,
+    read_timeout: Duration,
+    send: IntMap<usize, Mutex<QuicSend>>,
+    recv: IntMap<usize, FrameReader>,
+    stats: IntMap<usize, PeerStats>,
+    /// Whether the stats record histograms, see [`NetworkConfig::histograms`].
+    histograms: bool,
+}
+
+impl QuicNetwork {
+    pub fn networks<A: ToSocketAddrs>(
+        id: usize,
+        bind_addr: A,
+        addrs: &[Address],
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        num: usize,
+    ) -> eyre::Result<Vec<Self>> {
+        Self::networks_with_config(
+            id,
+            bind_addr,
+            addrs,
+            certs,
+            key,
+            num,
+            NetworkConfig::default(),
+        )
+    }
+
+    pub fn networks_with_config<A: ToSocketAddrs>(
+        id: usize,
+        bind_addr: A,
+        addrs: &[Address],
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        num: usize,
+        config: NetworkConfig,
+    ) -> eyre::Result<Vec<Self>> {
+        tracing::debug!("creating new network");
+        let codec = config.codec();
+        let (client_config, server_config, _) = config.tls_configs(id, addrs.len(), &certs, key)?;
+
+        let mut transport = TransportConfig::default();
+        transport.max_concurrent_bidi_streams(VarInt::from_u32(num.try_into()?));
+        transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+        let transport = Arc::new(transport);
+        let mut client_config =
+            ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_config)?));
+        client_config.transport_config(transport.clone());
+        let mut server_config =
+            ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_config)?));
+        server_config.transport_config(transport);
+
+        let bind_addr = bind_addr
+            .to_socket_addrs()?
+            .next()
+            .context("bind address did not resolve")?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let endpoint = {
+            let _runtime = runtime.enter();
+            Endpoint::server(server_config, bind_addr)?
+        };
+        let driver = Arc::new(Driver { endpoint, runtime });
+
+        let mut nets = Vec::with_capacity(num);
+        for _ in 0..num {
+            nets.push(Self {
+                id,
+                num_parties: addrs.len(),
+                codec,
+                read_timeout: config.read_timeout,
+                send: IntMap::default(),
+                recv: IntMap::default(),
+                stats: config.peer_stats(id, addrs.len()),
+                histograms: config.histograms,
+            });
+        }
+        let streams = |send, recv| {
+            let send = QuicSend {
+                stream: send,
+                driver: driver.clone(),
+                write_timeout: config.write_timeout,
+            };
+            let recv = QuicRecv {
+                stream: recv,
+                driver: driver.clone(),
+            };
+            (send, recv)
+        };
+
+        std::thread::scope(|scope| {
+            // connect to the parties with a higher id, one thread per party, while accepting the
+            // connections of the parties with a lower id
+            let connecting = addrs
+                .iter()
+                .enumerate()
+                .skip(id + 1)
+                .map(|(other_id, addr)| {
+                    let (config, certs, driver) = (&config, &certs, &driver);
+                    let (client_config, streams) = (&client_config, &streams);
+                    scope.spawn(move || {
+                        let socket_addr = addr
+                            .to_socket_addrs()?
+                            .next()
+                            .with_context(|| format!("{addr} did not resolve"))?;
+                        let conn = config.connect_with(id, other_id, addr, || {
+                            driver.runtime.block_on(async {
+                                driver
+                                    .endpoint
+                                    .connect_with(
+                                        client_config.clone(),
+                                        socket_addr,
+                                        &addr.hostname,
+                                    )
+                                    .map_err(std::io::Error::other)?
+                                    .await
+                                    .map_err(std::io::Error::other)
+                            })
+                        })?;
+                        check_peer_cert(&conn, other_id, config, certs)?;
+                        (0..num)
+                            .map(|i| {
+                                let (mut send, mut recv) =
+                                    driver.runtime.block_on(conn.open_bi())?;
+                                let mut preamble = Vec::with_capacity(PREAMBLE_LEN);
+                                preamble.write_u64::<BigEndian>(i as u64)?;
+                                preamble.write_u64::<BigEndian>(id as u64)?;
+                                preamble.write_u8(codec.flags())?;
+                                driver.runtime.block_on(send.write_all(&preamble))?;
+                                let mut echo = [0];
+                                driver.runtime.block_on(recv.read_exact(&mut echo))?;
+                                codec.check_flags(other_id, echo[0])?;
+                                Ok(streams(send, recv))
+                            })
+                            .collect::<eyre::Result<Vec<_>>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            // the streams of a connection are only opened after the connection was accepted
+            let mut accepted = 0;
+            while num > 0 && accepted < id {
+                let incoming = driver
+                    .runtime
+                    .block_on(driver.endpoint.accept())
+                    .context("endpoint was closed")?;
+                let remote = incoming.remote_address();
+                let handshake = async {
+                    let conn = incoming.await?;
+                    let mut opened = Vec::with_capacity(num);
+                    let mut peer = None;
+                    for _ in 0..num {
+                        let (mut send, mut recv) = conn.accept_bi().await?;
+                        let mut preamble = [0; PREAMBLE_LEN];
+                        recv.read_exact(&mut preamble).await?;
+                        let mut preamble = preamble.as_slice();
+                        let i = preamble.read_u64::<BigEndian>()? as usize;
+                        let other_id = preamble.read_u64::<BigEndian>()? as usize;
+                        let flags = preamble.read_u8()?;
+                        // echo our flags, so a connecting party with other flags fails as well
+                        send.write_all(&[codec.flags()]).await?;
+                        super::check_preamble(&nets, id, i, other_id, |net| &net.recv)?;
+                        codec.check_flags(other_id, flags)?;
+                        let peer = *peer.get_or_insert(other_id);
+                        if other_id != peer {
+                            eyre::bail!("party {peer} sent the id of party {other_id}");
+                        }
+                        if opened.iter().any(|(j, _, _)| *j == i) {
+                            eyre::bail!("party {other_id} opened network {i} twice");
+                        }
+                        opened.push((i, send, recv));
+                    }
+                    let other_id = peer.expect("at least one stream was opened");
+                    check_peer_cert(&conn, other_id, &config, &certs)?;
+                    eyre::Ok((other_id, opened))
+                };
+                let handshake = driver.runtime.block_on(async {
+                    match config.handshake_timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, handshake)
+                            .await
+                            .unwrap_or_else(|_| Err(eyre::eyre!("handshake timed out"))),
+                        None => handshake.await,
+                    }
+                });
+                let (other_id, opened) = match handshake {
+                    Ok(res) => res,
+                    Err(err) if config.handshake_timeout.is_some() => {
+                        tracing::warn!("dropping connection from {remote}: {err:?}");
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                for (i, send, recv) in opened {
+                    let (send, recv) = streams(send, recv);
+                    nets[i].send.insert(other_id, Mutex::new(send));
+                    let reader = codec.spawn_reader(recv, other_id);
+                    nets[i].recv.insert(other_id, reader);
+                }
+                accepted += 1;
+            }
+
+            for (other_id, connecting) in (id + 1..).zip(connecting) {
+                let opened = connecting.join().expect("connecting does not panic")?;
+                for (net, (send, recv)) in nets.iter_mut().zip(opened) {
+                    net.send.insert(other_id, Mutex::new(send));
+                    let reader = codec.spawn_reader(recv, other_id);
+                    net.recv.insert(other_id, reader);
+                }
+            }
+            Ok(nets)
+        })
+    }
+
+    /// The number of bytes sent to the party `to`, including the framing.
+    pub fn bytes_sent(&self, to: usize) -> u64 {
+        self.stats.get(to).map_or(0, PeerStats::bytes_sent)
+    }
+
+    /// The number of bytes received from the party `from`, including the framing.
+    pub fn bytes_recv(&self, from: usize) -> u64 {
+        self.stats.get(from).map_or(0, PeerStats::bytes_recv)
+    }
+
+    /// The total number of bytes sent to and received from all parties.
+    pub fn total_bytes(&self) -> (u64, u64) {
+        self.stats.values().fold((0, 0), |(sent, recv), stats| {
+            (sent + stats.bytes_sent(), recv + stats.bytes_recv())
+        })
+    }
+
+    /// The number of messages sent to the party `to`.
+    pub fn messages_sent(&self, to: usize) -> u64 {
+        self.stats.get(to).map_or(0, PeerStats::messages_sent)
+    }
+
+    /// The number of messages received from the party `from`.
+    pub fn messages_recv(&self, from: usize) -> u64 {
+        self.stats.get(from).map_or(0, PeerStats::messages_recv)
+    }
+
+    /// Reset the communication statistics, e.g., to measure a single protocol phase.
+    pub fn reset_stats(&self) {
+        for stats in self.stats.values() {
+            stats.reset();
+        }
+    }
+}
+
+/// Checks the certificate of the party `id` on `conn` as configured in `config`.
+fn check_peer_cert(
+    conn: &Connection,
+    id: usize,
+    config: &NetworkConfig,
+    certs: &[CertificateDer<'static>],
+) -> eyre::Result<()> {
+    let chain = conn
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok());
+    let chain = chain.as_deref().map(Vec::as_slice);
+    if config.mutual_tls {
+        let peer_id = super::peer_id_from_cert(chain, certs)?;
+        if peer_id != id {
+            eyre::bail!(
+                "expected the certificate of party {id}, but got the one of party {peer_id}"
+            );
+        }
+    }
+    if let Some(identities) = &config.peer_identities {
+        identities[id].check(chain, id)?;
+    }
+    Ok(())
+}
+
+impl Network for QuicNetwork {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn num_parties(&self) -> usize {
+        self.num_parties
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        let mut stream = self
+            .send
+            .get(to)
+            .ok_or(NetworkError::UnknownPeer(to))?
+            .lock();
+        tracing::trace!(to, len = data.len(), "send");
+        self.codec
+            .write_frame(&mut *stream, data)
+            .map_err(|err| NetworkError::from_io(to, err))?;
+        if let Some(stats) = self.stats.get(to) {
+            stats.record_send(data.len(), self.codec.frame_len(data.len()));
+        }
+        Ok(())
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        let data = self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .recv(self.read_timeout)?;
+        tracing::trace!(from, len = data.len(), "recv");
+        if let Some(stats) = self.stats.get(from) {
+            stats.record_recv(self.codec.frame_len(data.len()));
+        }
+        Ok(data)
+    }
+
+    fn recv_into(&self, from: usize, buf: &mut Vec<u8>) -> Result<usize, NetworkError> {
+        let len = self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .recv_into(self.read_timeout, buf)?;
+        tracing::trace!(from, len, "recv");
+        if let Some(stats) = self.stats.get(from) {
+            stats.record_recv(self.codec.frame_len(len));
+        }
+        Ok(len)
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        let progress = self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .recv_partial(timeout)?;
+        if let (RecvProgress::Complete(data), Some(stats)) = (&progress, self.stats.get(from)) {
+            stats.record_recv(self.codec.frame_len(data.len()));
+        }
+        Ok(progress)
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        let reader = self.recv.get(id).ok_or(NetworkError::UnknownPeer(id))?;
+        {
+            let mut stream = self
+                .send
+                .get(id)
+                .ok_or(NetworkError::UnknownPeer(id))?
+                .lock();
+            self.codec
+                .write_resync(&mut *stream)
+                .map_err(|err| NetworkError::from_io(id, err))?;
+        }
+        let discarded = reader.resync(self.read_timeout)?;
+        tracing::debug!("resynced with party {id}, discarded {discarded} frames");
+        Ok(())
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        Ok(self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .drain())
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        self.send
+            .get(to)
+            .ok_or(NetworkError::UnknownPeer(to))?
+            .lock()
+            .flush()
+            .map_err(|err| NetworkError::from_io(to, err))
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        self.send
+            .get(to)
+            .ok_or(NetworkError::UnknownPeer(to))?
+            .lock()
+            .finish()
+            .map_err(|err| NetworkError::from_io(to, err))
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        stats::size_histograms(&self.stats, self.histograms)
+    }
+}
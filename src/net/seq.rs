@@ -0,0 +1,275 @@
+//! A [`Network`] wrapper that numbers the messages to every peer for crash recovery.
+
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use super::{Network, NetworkError, RecvProgress, SizeHistogram};
+
+const KIND_DATA: u8 = 0;
+const KIND_RECOVER: u8 = 1;
+/// The kind byte and the u64 sequence number prefixed to every message.
+const HEADER_LEN: usize = 9;
+
+/// A snapshot of the sequence state of a [`SequencedNetwork`].
+///
+/// Both vectors are indexed by party id, the entry for the own id is unused.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SequenceCheckpoint {
+    /// The sequence number of the next message sent to each peer.
+    pub next_send: Vec<u64>,
+    /// The sequence number of the next message expected from each peer.
+    pub next_recv: Vec<u64>,
+}
+
+#[derive(Debug, Default)]
+struct SendState {
+    next: u64,
+    history: VecDeque<(u64, Vec<u8>)>,
+}
+
+/// A wrapper around a [`Network`] that prefixes every message with a per-peer sequence number.
+///
+/// The sequence state can be saved with [`SequencedNetwork::checkpoint`] and restored with
+/// [`SequencedNetwork::restore`], so a party that crashes and restarts can resume the framed
+/// streams where its checkpoint left off:
+///
+/// * Messages with a sequence number below the expected one are dropped on receive. A restarted
+///   party that replays its computation from the checkpoint therefore never delivers a message
+///   twice, and its peers silently discard the messages it re-sends.
+/// * A message with a sequence number above the expected one means that messages were lost and
+///   is reported as an error instead of desyncing the stream.
+/// * The last `history` messages to every peer are retained. After both sides are connected again,
+///   [`SequencedNetwork::recover_peer`] re-sends the retained messages the peer has not received
+///   according to its restored state.
+///
+/// Together this gives exactly-once delivery as long as no party falls behind by more than `history`
+/// messages, and at-most-once delivery with an error on the gap otherwise. The retained messages are
+/// not part of the checkpoint, a surviving party keeps them by moving its state to the new
+/// connection with [`SequencedNetwork::rewrap`].
+#[derive(Debug)]
+pub struct SequencedNetwork<N> {
+    inner: N,
+    history: usize,
+    send: Vec<Mutex<SendState>>,
+    recv: Vec<Mutex<u64>>,
+}
+
+impl<N: Network> SequencedNetwork<N> {
+    /// Wrap `inner`, retaining the last `history` messages sent to every peer.
+    pub fn new(inner: N, history: usize) -> Self {
+        let num_parties = inner.num_parties();
+        Self {
+            inner,
+            history,
+            send: (0..num_parties).map(|_| Mutex::default()).collect(),
+            recv: (0..num_parties).map(|_| Mutex::new(0)).collect(),
+        }
+    }
+
+    /// Snapshot the sequence state of all peers.
+    ///
+    /// The snapshot is only consistent if no messages are sent or received concurrently.
+    pub fn checkpoint(&self) -> SequenceCheckpoint {
+        SequenceCheckpoint {
+            next_send: self.send.iter().map(|state| state.lock().next).collect(),
+            next_recv: self.recv.iter().map(|next| *next.lock()).collect(),
+        }
+    }
+
+    /// Restore the sequence state of all peers from `checkpoint`, dropping the retained messages.
+    pub fn restore(&self, checkpoint: &SequenceCheckpoint) -> eyre::Result<()> {
+        let num_parties = self.inner.num_parties();
+        if checkpoint.next_send.len() != num_parties || checkpoint.next_recv.len() != num_parties {
+            eyre::bail!("checkpoint does not match the number of parties {num_parties}");
+        }
+        for (state, next) in self.send.iter().zip(&checkpoint.next_send) {
+            let mut state = state.lock();
+            state.next = *next;
+            state.history.clear();
+        }
+        for (state, next) in self.recv.iter().zip(&checkpoint.next_recv) {
+            *state.lock() = *next;
+        }
+        Ok(())
+    }
+
+    /// Move the sequence state and the retained messages to a new inner network, e.g., after
+    /// reconnecting.
+    pub fn rewrap<M: Network>(self, inner: M) -> eyre::Result<SequencedNetwork<M>> {
+        if inner.id() != self.inner.id() || inner.num_parties() != self.inner.num_parties() {
+            eyre::bail!("new network does not match the id and number of parties");
+        }
+        Ok(SequencedNetwork {
+            inner,
+            history: self.history,
+            send: self.send,
+            recv: self.recv,
+        })
+    }
+
+    /// Exchange the sequence state with the party `id` and re-send the messages it is missing.
+    ///
+    /// Both parties have to call this before any other message is exchanged on the new connection.
+    /// Fails if the peer expects a message that is no longer retained.
+    pub fn recover_peer(&self, id: usize) -> eyre::Result<()> {
+        // hold the send lock so no new message can overtake the re-sent ones
+        let state = self.send_state(id)?.lock();
+        let next_recv = *self.recv_state(id)?.lock();
+        self.inner.send(id, &encode(KIND_RECOVER, next_recv, &[]))?;
+
+        let data = self.inner.recv(id)?;
+        let (kind, requested) = decode(id, &data)?;
+        if kind != KIND_RECOVER {
+            eyre::bail!("expected recovery from party {id}, got a message");
+        }
+        // if the peer is ahead, our replay is dropped on its side
+        if requested < state.next {
+            let oldest = state.history.front().map_or(state.next, |(seq, _)| *seq);
+            if requested < oldest {
+                eyre::bail!(
+                    "party {id} expects message {requested}, but the oldest retained message is {oldest}"
+                );
+            }
+            for (seq, data) in state.history.iter().filter(|(seq, _)| *seq >= requested) {
+                self.inner.send(id, &encode(KIND_DATA, *seq, data))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the inner network.
+    pub fn into_inner(self) -> N {
+        self.inner
+    }
+
+    fn send_state(&self, id: usize) -> Result<&Mutex<SendState>, NetworkError> {
+        self.send.get(id).ok_or(NetworkError::UnknownPeer(id))
+    }
+
+    fn recv_state(&self, id: usize) -> Result<&Mutex<u64>, NetworkError> {
+        self.recv.get(id).ok_or(NetworkError::UnknownPeer(id))
+    }
+
+    /// Check the sequence number of a message from `from`, returns `None` for duplicates.
+    fn accept(&self, from: usize, data: Vec<u8>) -> eyre::Result<Option<Vec<u8>>> {
+        let (kind, seq) = decode(from, &data)?;
+        if kind != KIND_DATA {
+            eyre::bail!("unexpected recovery from party {from}");
+        }
+        let mut next = self.recv_state(from)?.lock();
+        if seq < *next {
+            tracing::debug!("dropping duplicate message {seq} from party {from}");
+            return Ok(None);
+        }
+        if seq > *next {
+            eyre::bail!("missing messages {}..{seq} from party {from}", *next);
+        }
+        *next += 1;
+        let mut data = data;
+        data.drain(..HEADER_LEN);
+        Ok(Some(data))
+    }
+}
+
+fn encode(kind: u8, seq: u64, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + data.len());
+    buf.push(kind);
+    buf.extend_from_slice(&seq.to_be_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+fn decode(from: usize, data: &[u8]) -> eyre::Result<(u8, u64)> {
+    if data.len() < HEADER_LEN {
+        eyre::bail!("message from party {from} is missing the sequence header");
+    }
+    let seq = u64::from_be_bytes(data[1..HEADER_LEN].try_into().expect("8 bytes"));
+    Ok((data[0], seq))
+}
+
+impl<N: Network> Network for SequencedNetwork<N> {
+    fn id(&self) -> usize {
+        self.inner.id()
+    }
+
+    fn num_parties(&self) -> usize {
+        self.inner.num_parties()
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        let mut state = self.send_state(to)?.lock();
+        let seq = state.next;
+        self.inner.send(to, &encode(KIND_DATA, seq, data))?;
+        state.next += 1;
+        if self.history > 0 {
+            if state.history.len() == self.history {
+                state.history.pop_front();
+            }
+            state.history.push_back((seq, data.to_vec()));
+        }
+        Ok(())
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        loop {
+            if let Some(data) = self.accept(from, self.inner.recv(from)?)? {
+                return Ok(data);
+            }
+        }
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.inner.recv_timeout(from, timeout)? {
+                RecvProgress::Complete(data) => {
+                    if let Some(data) = self.accept(from, data)? {
+                        return Ok(RecvProgress::Complete(data));
+                    }
+                    if Instant::now() >= deadline {
+                        return Ok(RecvProgress::Partial {
+                            received: 0,
+                            len: None,
+                        });
+                    }
+                }
+                RecvProgress::Partial { received, len } => {
+                    return Ok(RecvProgress::Partial {
+                        received: received.saturating_sub(HEADER_LEN),
+                        len: len.map(|len| len.saturating_sub(HEADER_LEN)),
+                    });
+                }
+            }
+        }
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        // frames dropped by the resync show up as a gap, follow up with recover_peer
+        self.inner.resync_peer(id)
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        // drained messages show up as a gap as well
+        self.inner.drain(from)
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.flush(to)
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.close(to)
+    }
+
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        self.inner.ping(peer, timeout)
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        self.inner.size_histogram()
+    }
+}
@@ -0,0 +1,297 @@
+//! A [`Network`] over shared memory ring buffers for parties running on the same host.
+
+use intmap::IntMap;
+use parking_lot::Mutex;
+use shared_memory::{Shmem, ShmemConf, ShmemError};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use super::{Network, NetworkConfig, NetworkError, TIMEOUT};
+
+/// Marks a ring as initialized by its creator.
+const MAGIC: u64 = 0x6d70_632d_7368_6d21;
+/// The offsets of the header fields, each in its own cache line.
+const MAGIC_OFFSET: usize = 0;
+const HEAD_OFFSET: usize = 64;
+const TAIL_OFFSET: usize = 128;
+const DATA_OFFSET: usize = 192;
+
+/// A single producer single consumer byte ring in a shared memory segment.
+///
+/// The writer only advances `head` and the reader only advances `tail`, both are monotonically
+/// increasing byte counts. The bytes between `tail` and `head` are readable, the rest is free.
+struct Ring {
+    shmem: Shmem,
+    capacity: usize,
+    /// The party on the other end of the ring.
+    peer: usize,
+}
+
+// SAFETY: the mapping is valid for the lifetime of `Shmem` and all shared state is accessed through
+// atomics. The ring has a single writer and a single reader, which is ensured by the locks in
+// `ShmemNetwork`, so the data regions they access never overlap.
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn create(os_id: &str, capacity: usize, peer: usize) -> eyre::Result<Self> {
+        let shmem = match ShmemConf::new()
+            .size(DATA_OFFSET + capacity)
+            .os_id(os_id)
+            .create()
+        {
+            Ok(shmem) => shmem,
+            Err(ShmemError::MappingIdExists) => {
+                eyre::bail!("shared memory segment {os_id} already exists, is it a stale one?")
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let ring = Self {
+            shmem,
+            capacity,
+            peer,
+        };
+        ring.atomic(MAGIC_OFFSET).store(MAGIC, Ordering::Release);
+        Ok(ring)
+    }
+
+    /// Opens the ring created by the peer, waiting until it exists and is initialized.
+    fn open(os_id: &str, peer: usize, deadline: Instant) -> eyre::Result<Self> {
+        loop {
+            if let Ok(shmem) = ShmemConf::new().os_id(os_id).open()
+                && shmem.len() > DATA_OFFSET
+            {
+                let ring = Self {
+                    capacity: shmem.len() - DATA_OFFSET,
+                    shmem,
+                    peer,
+                };
+                if ring.atomic(MAGIC_OFFSET).load(Ordering::Acquire) == MAGIC {
+                    return Ok(ring);
+                }
+            }
+            if Instant::now() >= deadline {
+                eyre::bail!("timed out waiting for shared memory segment {os_id}");
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn atomic(&self, offset: usize) -> &AtomicU64 {
+        // SAFETY: the offset is within the mapping and 8 byte aligned, as the mapping is page aligned
+        unsafe { &*(self.shmem.as_ptr().add(offset) as *const AtomicU64) }
+    }
+
+    fn data(&self) -> *mut u8 {
+        // SAFETY: the data region starts within the mapping
+        unsafe { self.shmem.as_ptr().add(DATA_OFFSET) }
+    }
+
+    fn write_all(&self, mut buf: &[u8], timeout: Duration) -> Result<(), NetworkError> {
+        let head = self.atomic(HEAD_OFFSET);
+        let tail = self.atomic(TAIL_OFFSET);
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Backoff::default();
+        while !buf.is_empty() {
+            let pos = head.load(Ordering::Relaxed);
+            let free = self.capacity - (pos - tail.load(Ordering::Acquire)) as usize;
+            if free == 0 {
+                backoff.wait(deadline, self.peer)?;
+                continue;
+            }
+            let start = pos as usize % self.capacity;
+            let n = buf.len().min(free).min(self.capacity - start);
+            // SAFETY: the bytes from start to start + n are free and only written by us
+            unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), self.data().add(start), n) };
+            head.store(pos + n as u64, Ordering::Release);
+            buf = &buf[n..];
+            backoff = Backoff::default();
+        }
+        Ok(())
+    }
+
+    fn read_exact(&self, mut buf: &mut [u8], timeout: Duration) -> Result<(), NetworkError> {
+        let head = self.atomic(HEAD_OFFSET);
+        let tail = self.atomic(TAIL_OFFSET);
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Backoff::default();
+        while !buf.is_empty() {
+            let pos = tail.load(Ordering::Relaxed);
+            let available = (head.load(Ordering::Acquire) - pos) as usize;
+            if available == 0 {
+                backoff.wait(deadline, self.peer)?;
+                continue;
+            }
+            let start = pos as usize % self.capacity;
+            let n = buf.len().min(available).min(self.capacity - start);
+            // SAFETY: the bytes from start to start + n were written and are only read by us
+            unsafe { std::ptr::copy_nonoverlapping(self.data().add(start), buf.as_mut_ptr(), n) };
+            tail.store(pos + n as u64, Ordering::Release);
+            buf = &mut buf[n..];
+            backoff = Backoff::default();
+        }
+        Ok(())
+    }
+}
+
+/// Spins first, then yields and finally sleeps while waiting for the peer.
+#[derive(Default)]
+struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    fn wait(&mut self, deadline: Instant, peer: usize) -> Result<(), NetworkError> {
+        if Instant::now() >= deadline {
+            return Err(NetworkError::Timeout(peer));
+        }
+        match self.step {
+            0..64 => std::hint::spin_loop(),
+            64..128 => std::thread::yield_now(),
+            _ => std::thread::sleep(Duration::from_micros(50)),
+        }
+        self.step = self.step.saturating_add(1);
+        Ok(())
+    }
+}
+
+/// A [`Network`] for parties running as processes on the same host, using shared memory rings.
+///
+/// Every party creates the rings for its outgoing messages and opens the rings of its peers,
+/// identified by a common `name`. Each message is written as a u32 length prefix followed by the
+/// payload, messages larger than the ring are streamed through it. The segments are removed when
+/// the creating party drops its network, a segment left over by a crashed party has to be removed
+/// manually (on Linux from `/dev/shm`).
+pub struct ShmemNetwork {
+    id: usize,
+    num_parties: usize,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    max_message_size: usize,
+    send: IntMap<usize, Mutex<Ring>>,
+    recv: IntMap<usize, Mutex<Ring>>,
+}
+
+impl ShmemNetwork {
+    /// Create `num` networks for the party `id`, using rings of `capacity` bytes per direction.
+    ///
+    /// All parties must use the same `name`, which must not be used by other processes on the host.
+    pub fn networks(
+        id: usize,
+        num_parties: usize,
+        name: &str,
+        capacity: usize,
+        num: usize,
+    ) -> eyre::Result<Vec<Self>> {
+        Self::networks_with_config(
+            id,
+            num_parties,
+            name,
+            capacity,
+            num,
+            NetworkConfig::default(),
+        )
+    }
+
+    /// Like [`ShmemNetwork::networks`], but uses the read and write timeouts and the maximum
+    /// message size of `config`. The other settings are ignored.
+    pub fn networks_with_config(
+        id: usize,
+        num_parties: usize,
+        name: &str,
+        capacity: usize,
+        num: usize,
+        config: NetworkConfig,
+    ) -> eyre::Result<Vec<Self>> {
+        if id >= num_parties {
+            eyre::bail!("party id {id} is out of range for {num_parties} parties");
+        }
+        if capacity == 0 {
+            eyre::bail!("ring capacity must not be zero");
+        }
+        let peers = (0..num_parties).filter(|&other_id| other_id != id);
+        let mut nets = Vec::with_capacity(num);
+        for i in 0..num {
+            let mut send = IntMap::default();
+            for other_id in peers.clone() {
+                let ring =
+                    Ring::create(&format!("{name}_{i}_{id}_{other_id}"), capacity, other_id)?;
+                send.insert(other_id, Mutex::new(ring));
+            }
+            nets.push(Self {
+                id,
+                num_parties,
+                read_timeout: config.read_timeout,
+                write_timeout: config.write_timeout,
+                max_message_size: config.max_message_size,
+                send,
+                recv: IntMap::default(),
+            });
+        }
+
+        let deadline = Instant::now() + TIMEOUT;
+        for (i, net) in nets.iter_mut().enumerate() {
+            for other_id in peers.clone() {
+                let ring = Ring::open(&format!("{name}_{i}_{other_id}_{id}"), other_id, deadline)?;
+                net.recv.insert(other_id, Mutex::new(ring));
+            }
+        }
+        Ok(nets)
+    }
+}
+
+impl Network for ShmemNetwork {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn num_parties(&self) -> usize {
+        self.num_parties
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        let len = u32::try_from(data.len()).map_err(|_| {
+            eyre::eyre!("message of {} bytes exceeds the maximum length", data.len())
+        })?;
+        let ring = self
+            .send
+            .get(to)
+            .ok_or(NetworkError::UnknownPeer(to))?
+            .lock();
+        ring.write_all(&len.to_be_bytes(), self.write_timeout)?;
+        ring.write_all(data, self.write_timeout)
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        let ring = self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .lock();
+        let mut len = [0; 4];
+        ring.read_exact(&mut len, self.read_timeout)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > self.max_message_size {
+            return Err(NetworkError::MessageTooLarge {
+                peer: from,
+                len,
+                max: self.max_message_size,
+            });
+        }
+        let mut data = vec![0; len];
+        ring.read_exact(&mut data, self.read_timeout)?;
+        Ok(data)
+    }
+
+    fn exchange(&self, with: usize, data: &[u8]) -> Result<Vec<u8>, NetworkError> {
+        // a message larger than the ring blocks the sender until the peer reads it
+        std::thread::scope(|scope| {
+            let send = scope.spawn(|| self.send(with, data));
+            let received = self.recv(with);
+            send.join().expect("send does not panic")?;
+            received
+        })
+    }
+}
@@ -0,0 +1,158 @@
+//! Communication statistics collected by the transports.
+
+use intmap::IntMap;
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const NUM_BUCKETS: usize = u32::BITS as usize + 1;
+
+/// A histogram of message sizes with power-of-two buckets.
+///
+/// Bucket `0` counts empty messages and bucket `i > 0` counts messages with a length in
+/// `[2^(i-1), 2^i)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeHistogram {
+    buckets: [u64; NUM_BUCKETS],
+}
+
+impl SizeHistogram {
+    /// The index of the bucket a message of length `len` falls into.
+    pub fn bucket_index(len: u32) -> usize {
+        (u32::BITS - len.leading_zeros()) as usize
+    }
+
+    /// The message counts per bucket.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// The total number of recorded messages.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AtomicSizeHistogram {
+    buckets: [AtomicU64; NUM_BUCKETS],
+}
+
+impl AtomicSizeHistogram {
+    fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    fn record(&self, len: usize) {
+        // frames are limited to u32::MAX bytes by the codec
+        self.buckets[SizeHistogram::bucket_index(len as u32)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> SizeHistogram {
+        SizeHistogram {
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The statistics of the link to a single peer.
+///
+/// The byte counts include the framing, i.e., they are the number of bytes on the wire.
+#[derive(Debug)]
+pub(crate) struct PeerStats {
+    sent_sizes: Option<AtomicSizeHistogram>,
+    bytes_sent: AtomicU64,
+    bytes_recv: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_recv: AtomicU64,
+    #[cfg(feature = "metrics")]
+    metrics: crate::metrics::PeerMetrics,
+}
+
+impl PeerStats {
+    /// The statistics of the link of party `id` to `peer`.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn new(id: usize, peer: usize, histograms: bool) -> Self {
+        Self {
+            sent_sizes: histograms.then(AtomicSizeHistogram::new),
+            bytes_sent: AtomicU64::new(0),
+            bytes_recv: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            messages_recv: AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            metrics: crate::metrics::PeerMetrics::new(id, peer),
+        }
+    }
+
+    /// Records a sent message with a payload of `len` bytes, taking up `frame_len` bytes on the wire.
+    pub(crate) fn record_send(&self, len: usize, frame_len: u64) {
+        if let Some(sent_sizes) = &self.sent_sizes {
+            sent_sizes.record(len);
+        }
+        self.bytes_sent.fetch_add(frame_len, Ordering::Relaxed);
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.record_send(frame_len);
+    }
+
+    /// Records a received message taking up `frame_len` bytes on the wire.
+    pub(crate) fn record_recv(&self, frame_len: u64) {
+        self.bytes_recv.fetch_add(frame_len, Ordering::Relaxed);
+        self.messages_recv.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.record_recv(frame_len);
+    }
+
+    pub(crate) fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes_recv(&self) -> u64 {
+        self.bytes_recv.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn messages_sent(&self) -> u64 {
+        self.messages_sent.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn messages_recv(&self) -> u64 {
+        self.messages_recv.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reset(&self) {
+        if let Some(sent_sizes) = &self.sent_sizes {
+            sent_sizes.reset();
+        }
+        self.bytes_sent.store(0, Ordering::Relaxed);
+        self.bytes_recv.store(0, Ordering::Relaxed);
+        self.messages_sent.store(0, Ordering::Relaxed);
+        self.messages_recv.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent_sizes(&self) -> Option<SizeHistogram> {
+        self.sent_sizes.as_ref().map(AtomicSizeHistogram::snapshot)
+    }
+}
+
+/// The histograms of the sizes of the messages sent to each peer, `None` if they are not recorded.
+pub(crate) fn size_histograms(
+    stats: &IntMap<usize, PeerStats>,
+    histograms: bool,
+) -> Option<BTreeMap<usize, SizeHistogram>> {
+    if !histograms {
+        return None;
+    }
+    stats
+        .iter()
+        .map(|(peer, stats)| Some((peer, stats.sent_sizes()?)))
+        .collect()
+}
@@ -0,0 +1,99 @@
+//! A [`Network`] wrapper that mirrors all outgoing traffic to a second network.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use super::{Network, NetworkError, RecvProgress, SizeHistogram};
+
+/// A wrapper that sends every message over a primary [`Network`] and mirrors it to a secondary one.
+///
+/// Receives are served by the primary network only, so the secondary network can be a recorder
+/// or a live transport feeding a hot-standby observer. A message is only mirrored after the
+/// primary send succeeded, and a failing mirror is reported as an error of the send.
+#[derive(Debug)]
+pub struct TeeNetwork<P, S> {
+    primary: P,
+    secondary: S,
+}
+
+impl<P: Network, S: Network> TeeNetwork<P, S> {
+    /// Construct a new [`TeeNetwork`] mirroring the sends of `primary` to `secondary`.
+    pub fn new(primary: P, secondary: S) -> Self {
+        Self { primary, secondary }
+    }
+
+    /// The primary network.
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    /// The secondary network.
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+
+    /// Returns the primary and secondary network.
+    pub fn into_inner(self) -> (P, S) {
+        (self.primary, self.secondary)
+    }
+}
+
+impl<P: Network, S: Network> Network for TeeNetwork<P, S> {
+    fn id(&self) -> usize {
+        self.primary.id()
+    }
+
+    fn num_parties(&self) -> usize {
+        self.primary.num_parties()
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        self.primary.send(to, data)?;
+        self.secondary.send(to, data).map_err(mirror_error)
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        self.primary.recv(from)
+    }
+
+    fn recv_into(&self, from: usize, buf: &mut Vec<u8>) -> Result<usize, NetworkError> {
+        self.primary.recv_into(from, buf)
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        self.primary.recv_timeout(from, timeout)
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        self.primary.resync_peer(id)
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        self.primary.drain(from)
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        self.primary.flush(to)?;
+        self.secondary.flush(to).map_err(mirror_error)
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        self.primary.close(to)?;
+        self.secondary.close(to).map_err(mirror_error)
+    }
+
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        self.primary.ping(peer, timeout)
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        self.primary.size_histogram()
+    }
+}
+
+/// Wraps an error of the secondary network, keeping the context.
+///
+/// The conversion from [`eyre::Report`] would unwrap the original [`NetworkError`] again and drop
+/// the context, so the report is wrapped explicitly.
+fn mirror_error(err: NetworkError) -> NetworkError {
+    NetworkError::Other(eyre::Report::new(err).wrap_err("while mirroring to secondary network"))
+}
@@ -0,0 +1,346 @@
+//! A [`Network`] wrapper that records a transcript of all messages and a network replaying it.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use eyre::Context;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    time::Duration,
+};
+
+use super::{MAX_MESSAGE_SIZE, Network, NetworkError, RecvProgress, SizeHistogram};
+
+/// The magic bytes at the start of a transcript, the last one is the format version.
+const MAGIC: &[u8; 8] = b"MPCTRAN\x01";
+const SENT: u8 = 0;
+const RECEIVED: u8 = 1;
+
+#[derive(Debug)]
+struct TranscriptWriter {
+    out: BufWriter<File>,
+    /// The number of messages recorded per peer, for sent and received messages.
+    sent: Vec<u64>,
+    received: Vec<u64>,
+}
+
+impl TranscriptWriter {
+    fn record(&mut self, direction: u8, peer: usize, data: &[u8]) -> std::io::Result<()> {
+        let counter = match direction {
+            SENT => &mut self.sent[peer],
+            _ => &mut self.received[peer],
+        };
+        self.out.write_u8(direction)?;
+        self.out.write_u32::<BigEndian>(peer as u32)?;
+        self.out.write_u64::<BigEndian>(*counter)?;
+        self.out.write_u32::<BigEndian>(data.len() as u32)?;
+        self.out.write_all(data)?;
+        *counter += 1;
+        Ok(())
+    }
+}
+
+/// A wrapper around a [`Network`] that records every sent and received message to a transcript file.
+///
+/// The transcript starts with the id and number of parties, followed by one entry per message
+/// with its direction, the peer, its index among the messages in this direction with this peer,
+/// and the bytes. The entries are in the order the operations completed, so the file captures
+/// the interleaving across all peers. Feed it to a [`ReplayNetwork`] to re-run the logic of this
+/// party deterministically without its peers.
+///
+/// Only messages are recorded, not resyncs, and messages discarded by [`Network::drain`] or a
+/// resync are missing from the transcript. The entries are buffered and written when the network
+/// is flushed or dropped.
+#[derive(Debug)]
+pub struct TranscriptNetwork<N> {
+    inner: N,
+    writer: Mutex<TranscriptWriter>,
+}
+
+impl<N: Network> TranscriptNetwork<N> {
+    /// Wrap `inner`, recording its messages to the file at `path`, which is truncated if it exists.
+    pub fn new(inner: N, path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("while creating transcript {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        let num_parties = inner.num_parties();
+        out.write_all(MAGIC)?;
+        out.write_u32::<BigEndian>(inner.id() as u32)?;
+        out.write_u32::<BigEndian>(num_parties as u32)?;
+        Ok(Self {
+            inner,
+            writer: Mutex::new(TranscriptWriter {
+                out,
+                sent: vec![0; num_parties],
+                received: vec![0; num_parties],
+            }),
+        })
+    }
+
+    /// Write all buffered entries to the transcript file.
+    pub fn flush_transcript(&self) -> eyre::Result<()> {
+        self.writer
+            .lock()
+            .out
+            .flush()
+            .context("while writing the transcript")
+    }
+
+    /// Returns the inner network, the remaining entries are written when the transcript is dropped.
+    pub fn into_inner(self) -> N {
+        self.inner
+    }
+
+    fn record(&self, direction: u8, peer: usize, data: &[u8]) -> Result<(), NetworkError> {
+        Ok(self
+            .writer
+            .lock()
+            .record(direction, peer, data)
+            .context("while writing the transcript")?)
+    }
+}
+
+impl<N: Network> Network for TranscriptNetwork<N> {
+    fn id(&self) -> usize {
+        self.inner.id()
+    }
+
+    fn num_parties(&self) -> usize {
+        self.inner.num_parties()
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        self.inner.send(to, data)?;
+        self.record(SENT, to, data)
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        let data = self.inner.recv(from)?;
+        self.record(RECEIVED, from, &data)?;
+        Ok(data)
+    }
+
+    fn recv_into(&self, from: usize, buf: &mut Vec<u8>) -> Result<usize, NetworkError> {
+        let len = self.inner.recv_into(from, buf)?;
+        self.record(RECEIVED, from, &buf[..len])?;
+        Ok(len)
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        let progress = self.inner.recv_timeout(from, timeout)?;
+        if let RecvProgress::Complete(data) = &progress {
+            self.record(RECEIVED, from, data)?;
+        }
+        Ok(progress)
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        self.inner.resync_peer(id)
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        self.inner.drain(from)
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.flush(to)?;
+        Ok(self.flush_transcript()?)
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        self.inner.close(to)?;
+        Ok(self.flush_transcript()?)
+    }
+
+    fn ping(&self, peer: usize, timeout: Duration) -> Result<Duration, NetworkError> {
+        self.inner.ping(peer, timeout)
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        self.inner.size_histogram()
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// The position of the entry in the transcript.
+    position: usize,
+    data: Vec<u8>,
+}
+
+/// A [`Network`] that replays a transcript recorded by a [`TranscriptNetwork`].
+///
+/// A receive returns the next message recorded from the peer, and a send checks that the
+/// message equals the next one recorded to the peer. Running out of recorded messages or
+/// sending a different message fails with an error, so the replay stops where the logic
+/// diverges from the recording. The order is only enforced per peer and direction by default,
+/// e.g., for parties that talk to their peers from multiple threads. With
+/// [`ReplayNetwork::strict`] all operations must follow the recorded interleaving.
+#[derive(Debug)]
+pub struct ReplayNetwork {
+    id: usize,
+    num_parties: usize,
+    sent: Vec<Mutex<VecDeque<Entry>>>,
+    received: Vec<Mutex<VecDeque<Entry>>>,
+    /// The position of the next entry in strict mode.
+    strict: Option<Mutex<usize>>,
+}
+
+impl ReplayNetwork {
+    /// Read the transcript at `path`.
+    pub fn open(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("while opening transcript {}", path.display()))?;
+        Self::read(BufReader::new(file))
+            .with_context(|| format!("while reading transcript {}", path.display()))
+    }
+
+    fn read(mut reader: impl Read) -> eyre::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            eyre::bail!("not a transcript or unsupported version");
+        }
+        let id = reader.read_u32::<BigEndian>()? as usize;
+        let num_parties = reader.read_u32::<BigEndian>()? as usize;
+        if id >= num_parties {
+            eyre::bail!("party id {id} out of range for {num_parties} parties");
+        }
+        let mut sent = (0..num_parties)
+            .map(|_| VecDeque::new())
+            .collect::<Vec<_>>();
+        let mut received = (0..num_parties)
+            .map(|_| VecDeque::new())
+            .collect::<Vec<_>>();
+        for position in 0.. {
+            let direction = match reader.read_u8() {
+                Ok(direction) => direction,
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            };
+            let peer = reader.read_u32::<BigEndian>()? as usize;
+            let index = reader.read_u64::<BigEndian>()?;
+            let len = reader.read_u32::<BigEndian>()? as usize;
+            if peer == id || peer >= num_parties {
+                eyre::bail!("entry {position} refers to invalid party {peer}");
+            }
+            if len > MAX_MESSAGE_SIZE {
+                eyre::bail!("entry {position} exceeds the maximum message size");
+            }
+            let queue: &mut VecDeque<Entry> = match direction {
+                SENT => &mut sent[peer],
+                RECEIVED => &mut received[peer],
+                _ => eyre::bail!("entry {position} has invalid direction {direction}"),
+            };
+            if index != queue.len() as u64 {
+                eyre::bail!("entry {position} is out of order");
+            }
+            let mut data = vec![0; len];
+            reader.read_exact(&mut data)?;
+            queue.push_back(Entry { position, data });
+        }
+        Ok(Self {
+            id,
+            num_parties,
+            sent: sent.into_iter().map(Mutex::new).collect(),
+            received: received.into_iter().map(Mutex::new).collect(),
+            strict: None,
+        })
+    }
+
+    /// Require all sends and receives to happen in the recorded order across all peers.
+    pub fn strict(mut self) -> Self {
+        self.strict = Some(Mutex::new(0));
+        self
+    }
+
+    /// The number of recorded messages that were not replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.sent
+            .iter()
+            .chain(&self.received)
+            .map(|queue| queue.lock().len())
+            .sum()
+    }
+
+    /// Take the next entry from `queue`, which must also be the next one overall in strict mode.
+    fn next(
+        &self,
+        queue: &Mutex<VecDeque<Entry>>,
+        what: impl FnOnce() -> String,
+    ) -> Result<Entry, NetworkError> {
+        let mut next_position = self.strict.as_ref().map(Mutex::lock);
+        let mut queue = queue.lock();
+        let Some(entry) = queue.front() else {
+            return Err(eyre::eyre!("transcript has no further {}", what()).into());
+        };
+        if let Some(next_position) = &mut next_position {
+            if entry.position != **next_position {
+                return Err(eyre::eyre!(
+                    "{} is entry {} of the transcript, expected entry {next_position}",
+                    what(),
+                    entry.position
+                )
+                .into());
+            }
+            **next_position += 1;
+        }
+        Ok(queue.pop_front().expect("checked above"))
+    }
+
+    fn check_peer(&self, peer: usize) -> Result<(), NetworkError> {
+        if peer == self.id || peer >= self.num_parties {
+            return Err(NetworkError::UnknownPeer(peer));
+        }
+        Ok(())
+    }
+}
+
+impl Network for ReplayNetwork {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn num_parties(&self) -> usize {
+        self.num_parties
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        self.check_peer(to)?;
+        let entry = self.next(&self.sent[to], || format!("message to party {to}"))?;
+        if entry.data != data {
+            return Err(eyre::eyre!(
+                "message to party {to} differs from entry {} of the transcript",
+                entry.position
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        self.check_peer(from)?;
+        let entry = self.next(&self.received[from], || {
+            format!("message from party {from}")
+        })?;
+        Ok(entry.data)
+    }
+
+    fn recv_timeout(&self, from: usize, _timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        // a replayed message is always available
+        self.recv(from).map(RecvProgress::Complete)
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        self.check_peer(id)
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        self.check_peer(from)?;
+        Ok(0)
+    }
+}
@@ -0,0 +1,333 @@
+//! A [`Network`] over Unix domain sockets for parties running on the same host.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use intmap::IntMap;
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Write},
+    net::Shutdown,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::{
+    Network, NetworkConfig, NetworkError, RecvProgress, SizeHistogram,
+    codec::{Codec, FrameReader},
+    stats::{self, PeerStats},
+};
+
+/// A listener that removes its socket file when dropped.
+struct Listener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Listener {
+    fn bind(path: &Path) -> eyre::Result<Self> {
+        let listener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(err) if err.kind() == ErrorKind::AddrInUse => {
+                eyre::bail!(
+                    "socket file {} already exists, is it a stale one?",
+                    path.display()
+                )
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            listener,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            tracing::warn!(
+                "could not remove socket file {}: {err}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// A [`Network`] for parties running as processes on the same host, using Unix domain sockets.
+///
+/// Every party listens on the socket file at its own entry of `paths` and connects to the socket
+/// files of the parties with a higher id, using the same handshake and framing as
+/// [`TcpNetwork`](super::TcpNetwork). The socket file only exists during the setup, it is removed
+/// once all connections are established or the setup failed. A socket file left over by a crashed
+/// party has to be removed manually.
+#[derive(Debug)]
+pub struct UnixNetwork {
+    id: usize,
+    num_parties: usize,
+    codec: Codec,
+    read_timeout: Duration,
+    send: IntMap<usize, Mutex<UnixStream>>,
+    recv: IntMap<usize, FrameReader>,
+    stats: IntMap<usize, PeerStats>,
+    /// Whether the stats record histograms, see [`NetworkConfig::histograms`].
+    histograms: bool,
+}
+
+impl UnixNetwork {
+    /// Create `num` networks for the party `id`, where `paths[i]` is the socket file of party `i`.
+    pub fn networks<P: AsRef<Path> + Sync>(
+        id: usize,
+        paths: &[P],
+        num: usize,
+    ) -> eyre::Result<Vec<Self>> {
+        Self::networks_with_config(id, paths, num, NetworkConfig::default())
+    }
+
+    pub fn networks_with_config<P: AsRef<Path> + Sync>(
+        id: usize,
+        paths: &[P],
+        num: usize,
+        config: NetworkConfig,
+    ) -> eyre::Result<Vec<Self>> {
+        tracing::debug!("creating new network");
+        if id >= paths.len() {
+            eyre::bail!("party id {id} is out of range for {} parties", paths.len());
+        }
+        let codec = config.codec();
+        let listener = Listener::bind(paths[id].as_ref())?;
+
+        let mut nets = Vec::with_capacity(num);
+        for _ in 0..num {
+            nets.push(Self {
+                id,
+                num_parties: paths.len(),
+                codec,
+                read_timeout: config.read_timeout,
+                send: IntMap::default(),
+                recv: IntMap::default(),
+                stats: config.peer_stats(id, paths.len()),
+                histograms: config.histograms,
+            });
+        }
+
+        std::thread::scope(|scope| {
+            // connect to the parties with a higher id, one thread per party, while accepting the
+            // connections of the parties with a lower id
+            let connecting = paths
+                .iter()
+                .enumerate()
+                .skip(id + 1)
+                .map(|(other_id, path)| {
+                    let config = &config;
+                    scope.spawn(move || {
+                        let path = path.as_ref();
+                        (0..num)
+                            .map(|i| {
+                                let mut stream =
+                                    config.connect_with(id, other_id, path.display(), || {
+                                        UnixStream::connect(path)
+                                    })?;
+                                stream.set_write_timeout(Some(config.write_timeout))?;
+                                stream.write_u64::<BigEndian>(i as u64)?;
+                                stream.write_u64::<BigEndian>(id as u64)?;
+                                stream.write_u8(codec.flags())?;
+                                codec.check_flags(other_id, stream.read_u8()?)?;
+                                Ok(stream)
+                            })
+                            .collect::<eyre::Result<Vec<_>>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for _ in 0..num * id {
+                let (stream, i, other_id) = loop {
+                    let (mut stream, _) = listener.listener.accept()?;
+                    stream.set_read_timeout(config.handshake_timeout)?;
+                    let mut handshake = || {
+                        stream.set_write_timeout(Some(config.write_timeout))?;
+                        let i = stream.read_u64::<BigEndian>()? as usize;
+                        let other_id = stream.read_u64::<BigEndian>()? as usize;
+                        let flags = stream.read_u8()?;
+                        // echo our flags, so a connecting party with other flags fails as well
+                        stream.write_u8(codec.flags())?;
+                        super::check_preamble(&nets, id, i, other_id, |net| &net.recv)?;
+                        codec.check_flags(other_id, flags)?;
+                        stream.set_read_timeout(None)?;
+                        eyre::Ok((i, other_id))
+                    };
+                    match handshake() {
+                        Ok((i, other_id)) => break (stream, i, other_id),
+                        Err(err) if config.handshake_timeout.is_some() => {
+                            tracing::warn!("dropping connection: {err:?}");
+                        }
+                        Err(err) => return Err(err),
+                    }
+                };
+                nets[i]
+                    .send
+                    .insert(other_id, Mutex::new(stream.try_clone()?));
+                let reader = codec.spawn_reader(stream, other_id);
+                nets[i].recv.insert(other_id, reader);
+            }
+
+            for (other_id, connecting) in (id + 1..).zip(connecting) {
+                let streams = connecting.join().expect("connecting does not panic")?;
+                for (net, stream) in nets.iter_mut().zip(streams) {
+                    net.send.insert(other_id, Mutex::new(stream.try_clone()?));
+                    let reader = codec.spawn_reader(stream, other_id);
+                    net.recv.insert(other_id, reader);
+                }
+            }
+            Ok(nets)
+        })
+    }
+
+    /// The number of bytes sent to the party `to`, including the framing.
+    pub fn bytes_sent(&self, to: usize) -> u64 {
+        self.stats.get(to).map_or(0, PeerStats::bytes_sent)
+    }
+
+    /// The number of bytes received from the party `from`, including the framing.
+    pub fn bytes_recv(&self, from: usize) -> u64 {
+        self.stats.get(from).map_or(0, PeerStats::bytes_recv)
+    }
+
+    /// The total number of bytes sent to and received from all parties.
+    pub fn total_bytes(&self) -> (u64, u64) {
+        self.stats.values().fold((0, 0), |(sent, recv), stats| {
+            (sent + stats.bytes_sent(), recv + stats.bytes_recv())
+        })
+    }
+
+    /// The number of messages sent to the party `to`.
+    pub fn messages_sent(&self, to: usize) -> u64 {
+        self.stats.get(to).map_or(0, PeerStats::messages_sent)
+    }
+
+    /// The number of messages received from the party `from`.
+    pub fn messages_recv(&self, from: usize) -> u64 {
+        self.stats.get(from).map_or(0, PeerStats::messages_recv)
+    }
+
+    /// Reset the communication statistics, e.g., to measure a single protocol phase.
+    pub fn reset_stats(&self) {
+        for stats in self.stats.values() {
+            stats.reset();
+        }
+    }
+}
+
+impl Network for UnixNetwork {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn num_parties(&self) -> usize {
+        self.num_parties
+    }
+
+    fn send(&self, to: usize, data: &[u8]) -> Result<(), NetworkError> {
+        let mut stream = self
+            .send
+            .get(to)
+            .ok_or(NetworkError::UnknownPeer(to))?
+            .lock();
+        tracing::trace!(to, len = data.len(), "send");
+        self.codec
+            .write_frame(&mut *stream, data)
+            .map_err(|err| NetworkError::from_io(to, err))?;
+        if let Some(stats) = self.stats.get(to) {
+            stats.record_send(data.len(), self.codec.frame_len(data.len()));
+        }
+        Ok(())
+    }
+
+    fn recv(&self, from: usize) -> Result<Vec<u8>, NetworkError> {
+        let data = self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .recv(self.read_timeout)?;
+        tracing::trace!(from, len = data.len(), "recv");
+        if let Some(stats) = self.stats.get(from) {
+            stats.record_recv(self.codec.frame_len(data.len()));
+        }
+        Ok(data)
+    }
+
+    fn recv_into(&self, from: usize, buf: &mut Vec<u8>) -> Result<usize, NetworkError> {
+        let len = self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .recv_into(self.read_timeout, buf)?;
+        tracing::trace!(from, len, "recv");
+        if let Some(stats) = self.stats.get(from) {
+            stats.record_recv(self.codec.frame_len(len));
+        }
+        Ok(len)
+    }
+
+    fn recv_timeout(&self, from: usize, timeout: Duration) -> Result<RecvProgress, NetworkError> {
+        let progress = self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .recv_partial(timeout)?;
+        if let (RecvProgress::Complete(data), Some(stats)) = (&progress, self.stats.get(from)) {
+            stats.record_recv(self.codec.frame_len(data.len()));
+        }
+        Ok(progress)
+    }
+
+    fn resync_peer(&self, id: usize) -> Result<(), NetworkError> {
+        let reader = self.recv.get(id).ok_or(NetworkError::UnknownPeer(id))?;
+        {
+            let mut stream = self
+                .send
+                .get(id)
+                .ok_or(NetworkError::UnknownPeer(id))?
+                .lock();
+            self.codec
+                .write_resync(&mut *stream)
+                .map_err(|err| NetworkError::from_io(id, err))?;
+        }
+        let discarded = reader.resync(self.read_timeout)?;
+        tracing::debug!("resynced with party {id}, discarded {discarded} frames");
+        Ok(())
+    }
+
+    fn drain(&self, from: usize) -> Result<usize, NetworkError> {
+        Ok(self
+            .recv
+            .get(from)
+            .ok_or(NetworkError::UnknownPeer(from))?
+            .drain())
+    }
+
+    fn flush(&self, to: usize) -> Result<(), NetworkError> {
+        self.send
+            .get(to)
+            .ok_or(NetworkError::UnknownPeer(to))?
+            .lock()
+            .flush()
+            .map_err(|err| NetworkError::from_io(to, err))
+    }
+
+    fn close(&self, to: usize) -> Result<(), NetworkError> {
+        let mut stream = self
+            .send
+            .get(to)
+            .ok_or(NetworkError::UnknownPeer(to))?
+            .lock();
+        stream
+            .flush()
+            .and_then(|_| stream.shutdown(Shutdown::Write))
+            .map_err(|err| NetworkError::from_io(to, err))
+    }
+
+    fn size_histogram(&self) -> Option<BTreeMap<usize, SizeHistogram>> {
+        stats::size_histograms(&self.stats, self.histograms)
+    }
+}
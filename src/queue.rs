@@ -1,15 +1,49 @@
 use intmap::IntMap;
 use parking_lot::{Condvar, Mutex};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant},
+};
 
 #[derive(Debug)]
 struct Inner<T> {
     num: usize,
     queue: IntMap<usize, T>,
     next_index: usize,
+    poisoned: BTreeSet<usize>,
+    #[cfg(feature = "metrics")]
+    available: metrics::Gauge,
 }
 
-// TODO we could just put num, queue and next_index in a mutex
+impl<T> Inner<T> {
+    /// Takes the next item in round-robin order, `None` if it is in use or there are no items.
+    ///
+    /// Poisoned items are skipped, they may be left in the middle of a message.
+    fn take_next(&mut self) -> Option<(usize, T)> {
+        // keep next_index bounded by num so it can't overflow in long running services
+        let index = (0..self.num)
+            .map(|offset| (self.next_index + offset) % self.num)
+            .find(|index| !self.poisoned.contains(index))?;
+        let item = self.queue.remove(index)?;
+        self.next_index = (index + 1) % self.num;
+        self.changed();
+        Some((index, item))
+    }
+
+    /// Updates the metrics after an item was taken from or put into the queue.
+    fn changed(&self) {
+        #[cfg(feature = "metrics")]
+        self.available.set(self.queue.len() as f64);
+    }
+}
+
+/// A queue of `num` items addressed by the indices `0..num`.
+///
+/// An item is either in the queue or in use until it is pushed back at the index it was popped
+/// from. The indices of all items, in the queue or in use, always are exactly `0..num`:
+/// [`NetworkQueue::remove`] only takes the item with the highest index and
+/// [`NetworkQueue::insert`] appends at index `num`. Every waiter re-checks its condition after a
+/// change, so a pop never waits for an index that no longer exists.
 #[derive(Debug)]
 pub struct NetworkQueue<T> {
     inner: Mutex<Inner<T>>,
@@ -27,53 +61,339 @@ impl<T> NetworkQueue<T> {
                 num: queue.len(),
                 queue,
                 next_index: 0,
+                poisoned: BTreeSet::new(),
+                #[cfg(feature = "metrics")]
+                available: metrics::Gauge::noop(),
             }),
             cvar: Condvar::new(),
         }
     }
 
+    /// Report the number of items not in use to `gauge`.
+    #[cfg(feature = "metrics")]
+    pub fn with_available_gauge(self, gauge: metrics::Gauge) -> Self {
+        let mut inner = self.inner.lock();
+        inner.available = gauge;
+        inner.changed();
+        drop(inner);
+        self
+    }
+
     pub fn pop(&self) -> (usize, T) {
         let mut inner = self.inner.lock();
-        let index = inner.next_index % inner.num;
-        inner.next_index += 1;
+        loop {
+            // all items may have been removed or the next one is in use
+            if let Some(popped) = inner.take_next() {
+                return popped;
+            }
+            // we can get woken up if another item was added back or num changed,
+            // so we loop and recompute the index we are waiting for
+            self.cvar.wait(&mut inner);
+        }
+    }
+
+    /// Like [`NetworkQueue::pop`], but returns `None` instead of waiting if the next item is in use.
+    ///
+    /// The round-robin index only advances if an item is returned, so the items are still popped
+    /// in the same order as with [`NetworkQueue::pop`].
+    pub fn try_pop(&self) -> Option<(usize, T)> {
+        self.inner.lock().take_next()
+    }
+
+    /// Like [`NetworkQueue::pop`], but returns `None` if the next item is still in use after `timeout`.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<(usize, T)> {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.inner.lock();
+        loop {
+            if let Some(popped) = inner.take_next() {
+                return Some(popped);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            // spurious wakeups and pushes of other items check again until the deadline
+            self.cvar.wait_until(&mut inner, deadline);
+        }
+    }
+
+    /// Pops the item at `index % num`, waiting until it is available.
+    ///
+    /// In contrast to [`NetworkQueue::pop`], this does not advance the round-robin index and
+    /// returns the item even if it is poisoned, as the caller chose it.
+    pub fn pop_index(&self, index: usize) -> (usize, T) {
+        let mut inner = self.inner.lock();
+        loop {
+            if let Some(index) = index.checked_rem(inner.num)
+                && let Some(item) = inner.queue.remove(index)
+            {
+                inner.changed();
+                return (index, item);
+            }
+            // remove and insert change num, so recompute the index after every change
+            self.cvar.wait(&mut inner);
+        }
+    }
 
-        // we can get woken up if another item was added back,
-        // so we loop and check if it was the one we are wating for
-        while inner.queue.get(index).is_none() {
+    /// Pops the item at `index % num` if it is not in use, poisoned or the queue is empty.
+    ///
+    /// In contrast to [`NetworkQueue::pop`], this does not advance the round-robin index.
+    pub fn try_pop_index(&self, index: usize) -> Option<(usize, T)> {
+        let mut inner = self.inner.lock();
+        let index = index.checked_rem(inner.num)?;
+        if inner.poisoned.contains(&index) {
+            return None;
+        }
+        let item = inner.queue.remove(index)?;
+        inner.changed();
+        Some((index, item))
+    }
+
+    /// Runs `f` on all items, waiting until none of them is in use.
+    ///
+    /// Other threads may pop, remove or insert items while this waits, `f` sees the items
+    /// `0..num` of the moment no item was in use.
+    pub fn with_all<R>(&self, f: impl FnOnce(&IntMap<usize, T>) -> R) -> R {
+        let mut inner = self.inner.lock();
+
+        // push, remove and insert notify, so this re-checks against the current num after each change
+        while inner.queue.len() < inner.num {
             self.cvar.wait(&mut inner);
         }
 
-        // we got woken up, item must be present now
-        // only main thread can call pop, so no other thread can be here
-        let item = inner.queue.remove(index).expect("must exist");
-        (index, item)
+        f(&inner.queue)
+    }
+
+    /// Waits until no item is in use.
+    pub fn wait_idle(&self) {
+        let mut inner = self.inner.lock();
+        // every push and remove notifies, so this re-checks after each change
+        while inner.queue.len() < inner.num {
+            self.cvar.wait(&mut inner);
+        }
     }
 
     pub fn push(&self, index: usize, item: T) {
         let mut inner = self.inner.lock();
 
-        // add item back and notfiy main thread if it was wating on the condvar
+        // add item back and notify the threads wating on the condvar, a pop, with_all and remove
+        // may wait at the same time
+        debug_assert!(index < inner.num, "pushed index {index} was never popped");
         inner.queue.insert(index, item);
-        self.cvar.notify_one();
+        inner.changed();
+        self.cvar.notify_all();
     }
 
+    /// Removes the item with the highest index, waiting until it is not in use.
+    ///
+    /// Returns `None` if there are no items left.
     pub fn remove(&self) -> Option<T> {
         let mut inner = self.inner.lock();
-
-        if inner.queue.is_empty() {
-            return None;
+        loop {
+            let index = inner.num.checked_sub(1)?;
+            if let Some(item) = inner.queue.remove(index) {
+                inner.num = index;
+                // the index no longer exists, a later insert reuses it for a fresh item
+                inner.poisoned.remove(&index);
+                inner.changed();
+                // a pop may wait for the removed index and has to pick another one
+                self.cvar.notify_all();
+                return Some(item);
+            }
+            self.cvar.wait(&mut inner);
         }
-
-        let index = inner.queue.len() - 1;
-        inner.num -= 1;
-        inner.queue.remove(index)
     }
 
+    /// Adds `item` at index `num`, the index of the last removed item if any.
     pub fn insert(&self, item: T) {
         let mut inner = self.inner.lock();
 
-        let index = inner.queue.len();
+        let index = inner.num;
         inner.num += 1;
+        // the item is fresh, even if the index was poisoned before it got removed
+        inner.poisoned.remove(&index);
         inner.queue.insert(index, item);
+        inner.changed();
+        // pop may wait for the queue to become non-empty
+        self.cvar.notify_all();
+    }
+
+    /// The number of items in total, in the queue or in use.
+    pub fn num(&self) -> usize {
+        self.inner.lock().num
+    }
+
+    /// The number of items that are currently not in use.
+    pub fn available(&self) -> usize {
+        self.inner.lock().queue.len()
+    }
+
+    /// The indices of all items that are currently in use.
+    pub fn in_use(&self) -> Vec<usize> {
+        let inner = self.inner.lock();
+        (0..inner.num)
+            .filter(|&index| inner.queue.get(index).is_none())
+            .collect()
+    }
+
+    /// Mark the item at `index` as poisoned, e.g., because it was abandoned while still in use.
+    ///
+    /// Poisoned items are not popped until their flag is cleared or the item is removed.
+    pub fn poison(&self, index: usize) {
+        self.inner.lock().poisoned.insert(index);
+    }
+
+    /// Clear the poisoned flag of the item at `index`.
+    pub fn clear_poison(&self, index: usize) {
+        self.inner.lock().poisoned.remove(&index);
+        // a pop may wait because all items were poisoned
+        self.cvar.notify_all();
+    }
+
+    /// The indices of all poisoned items.
+    pub fn poisoned(&self) -> Vec<usize> {
+        self.inner.lock().poisoned.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pop_skips_poisoned() {
+        let queue = NetworkQueue::new(vec!['a', 'b', 'c']);
+        queue.poison(0);
+        assert_eq!(queue.pop(), (1, 'b'));
+        assert_eq!(queue.pop(), (2, 'c'));
+        queue.push(1, 'b');
+        queue.push(2, 'c');
+        assert_eq!(queue.pop(), (1, 'b'));
+        queue.push(1, 'b');
+
+        queue.clear_poison(0);
+        assert_eq!(queue.pop(), (2, 'c'));
+        assert_eq!(queue.pop(), (0, 'a'));
+    }
+
+    #[test]
+    fn pop_waits_while_all_poisoned() {
+        let queue = NetworkQueue::new(vec!['a']);
+        queue.poison(0);
+        assert_eq!(queue.try_pop(), None);
+        std::thread::scope(|s| {
+            let popped = s.spawn(|| queue.pop());
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!popped.is_finished());
+            queue.clear_poison(0);
+            assert_eq!(popped.join().unwrap(), (0, 'a'));
+        });
+    }
+
+    #[test]
+    fn pop_waits_after_all_removed() {
+        let queue = NetworkQueue::new(vec!['a', 'b']);
+        assert_eq!(queue.remove(), Some('b'));
+        assert_eq!(queue.remove(), Some('a'));
+        assert_eq!(queue.remove(), None);
+        assert_eq!(queue.try_pop(), None);
+        std::thread::scope(|s| {
+            let popped = s.spawn(|| queue.pop());
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!popped.is_finished());
+            queue.insert('c');
+            assert_eq!(popped.join().unwrap(), (0, 'c'));
+        });
+    }
+
+    #[test]
+    fn next_index_stays_bounded() {
+        let queue = NetworkQueue::new(vec!['a', 'b', 'c']);
+        for round in 0..10 {
+            for index in 0..3 {
+                assert_eq!(queue.pop(), (index, ['a', 'b', 'c'][index]));
+                queue.push(index, ['a', 'b', 'c'][index]);
+                assert!(queue.inner.lock().next_index < 3, "round {round}");
+            }
+        }
+        // shrinking the queue keeps the round-robin order within the remaining items
+        queue.remove();
+        assert_eq!(queue.pop(), (0, 'a'));
+        assert_eq!(queue.pop(), (1, 'b'));
+        queue.push(0, 'a');
+        queue.push(1, 'b');
+        assert_eq!(queue.pop(), (0, 'a'));
+    }
+
+    #[test]
+    fn try_pop_index_does_not_wait() {
+        let queue = NetworkQueue::new(vec!['a', 'b']);
+        assert_eq!(queue.try_pop_index(2), Some((0, 'a')));
+        assert_eq!(queue.try_pop_index(0), None);
+        queue.poison(1);
+        assert_eq!(queue.try_pop_index(1), None);
+        queue.clear_poison(1);
+        assert_eq!(queue.try_pop_index(1), Some((1, 'b')));
+        // the round-robin index did not advance
+        queue.push(0, 'a');
+        assert_eq!(queue.pop(), (0, 'a'));
+    }
+
+    #[test]
+    fn remove_and_insert_clear_poison() {
+        let queue = NetworkQueue::new(vec!['a', 'b']);
+        queue.poison(1);
+        assert_eq!(queue.remove(), Some('b'));
+        assert!(queue.poisoned().is_empty());
+        queue.poison(1);
+        queue.insert('c');
+        assert!(queue.poisoned().is_empty());
+        assert_eq!(queue.pop(), (0, 'a'));
+        assert_eq!(queue.pop(), (1, 'c'));
+    }
+
+    #[test]
+    fn concurrent_pop_push_remove_insert() {
+        // every item is its own index, so a renumbering bug shows up as a mismatch
+        let queue = NetworkQueue::new((0..4).collect::<Vec<usize>>());
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let (index, item) = queue.pop();
+                        assert_eq!(index, item);
+                        queue.push(index, item);
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..100 {
+                    queue.with_all(|items| {
+                        assert!(items.iter().all(|(index, item)| index == *item));
+                    });
+                }
+            });
+            s.spawn(|| {
+                for round in 0..200 {
+                    let last = queue.num() - 1;
+                    if round % 2 == 0 {
+                        queue.poison(last);
+                    }
+                    let item = queue.remove().unwrap();
+                    assert_eq!(item, last);
+                    queue.insert(item);
+                    // shrink and grow the queue between one and four items
+                    if round % 3 == 0 && queue.num() > 1 {
+                        queue.remove().unwrap();
+                    } else if queue.num() < 4 {
+                        queue.insert(queue.num());
+                    }
+                }
+            });
+        });
+        assert!(queue.poisoned().is_empty());
+        let num = queue.num();
+        queue.with_all(|items| {
+            assert!((0..num).all(|index| items.get(index) == Some(&index)));
+        });
     }
 }
@@ -0,0 +1,544 @@
+use mpc_engine::{JoinError, MpcEngine, Network, RecvProgress, TestNetwork};
+use std::time::Duration;
+
+#[test]
+fn engine_over_boxed_networks() {
+    let nets = TestNetwork::networks(3, 2);
+    let results = std::thread::scope(|scope| {
+        let handles = nets
+            .into_iter()
+            .enumerate()
+            .map(|(id, nets)| {
+                let nets: Vec<Box<dyn Network>> = nets.into_iter().map(Network::boxed).collect();
+                scope.spawn(move || {
+                    let engine = MpcEngine::new(id, 2, 1, nets);
+                    engine.install_net(|net| {
+                        net.send_next(&[id as u8])?;
+                        net.recv_prev()
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap().unwrap())
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(results, [vec![2], vec![0], vec![1]]);
+}
+
+#[test]
+fn for_each_peer_indexes_results_by_peer() {
+    let results = mpc_engine::simulate(4, |engine| {
+        let id = engine.id();
+        engine.for_each_peer(|peer, net| {
+            net.send(peer, &[id as u8]).unwrap();
+            net.recv(peer).unwrap()[0] as usize
+        })
+    })
+    .unwrap();
+    for (id, res) in results.into_iter().enumerate() {
+        let expected = (0..4)
+            .map(|peer| (peer != id).then_some(peer))
+            .collect::<Vec<_>>();
+        assert_eq!(res, expected);
+    }
+}
+
+#[test]
+fn for_each_peer_uses_one_network_per_peer() {
+    let results = mpc_engine::simulate(4, |engine| {
+        engine.for_each_peer(|_, net| std::ptr::from_ref(net) as usize)
+    })
+    .unwrap();
+    for res in results {
+        let nets = res
+            .into_iter()
+            .flatten()
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(nets.len(), 3);
+    }
+}
+
+#[test]
+fn gather_and_scatter_keep_party_order() {
+    // messages of different sizes so that the parallel receives finish out of order
+    let message = |id: usize| vec![id as u8; 1 << (4 * id)];
+    let results = mpc_engine::simulate(4, |engine| {
+        let id = engine.id();
+        let gathered = engine.gather_to(2, &message(id)).unwrap();
+        let per_party = gathered
+            .clone()
+            .map(|messages| messages.into_iter().rev().collect());
+        let scattered = engine.scatter_from(2, per_party).unwrap();
+        (gathered, scattered)
+    })
+    .unwrap();
+    for (id, (gathered, scattered)) in results.into_iter().enumerate() {
+        if id == 2 {
+            assert_eq!(gathered, Some((0..4).map(message).collect()));
+        } else {
+            assert_eq!(gathered, None);
+        }
+        assert_eq!(scattered, message(3 - id));
+    }
+}
+
+#[test]
+fn install_net_timeout_poisons_the_abandoned_network() {
+    mpc_engine::simulate(2, |engine| {
+        if engine.id() == 0 {
+            let res = engine.install_net_timeout(
+                |_| std::thread::sleep(Duration::from_millis(200)),
+                Duration::from_millis(20),
+            );
+            assert_eq!(res, None);
+            assert_eq!(engine.poisoned_networks(), [0]);
+            // the abandoned closure returns the network, which stays poisoned
+            engine.wait_idle();
+            // network 0 is skipped when the round-robin order wraps around
+            for round in 0..engine.num_networks() as u8 {
+                engine.install_net(|net| net.send(1, &[round]).unwrap());
+            }
+            engine.clear_poisoned(0);
+            assert!(engine.poisoned_networks().is_empty());
+            // keep the networks open until party 1 checked network 0
+            engine.install_net(|net| net.recv(1).unwrap());
+        } else {
+            assert_eq!(
+                engine.install_net_timeout(|_| (), Duration::from_secs(5)),
+                Some(())
+            );
+            for round in 0..engine.num_networks() as u8 - 1 {
+                assert_eq!(engine.install_net(|net| net.recv(0).unwrap()), [round]);
+            }
+            let progress =
+                engine.install_net(|net| net.recv_timeout(0, Duration::from_millis(100)).unwrap());
+            assert!(matches!(
+                progress,
+                RecvProgress::Partial { received: 0, .. }
+            ));
+            let last = engine.num_networks() as u8 - 1;
+            assert_eq!(engine.install_net(|net| net.recv(0).unwrap()), [last]);
+            engine.install_net(|net| net.send(0, &[]).unwrap());
+        }
+    })
+    .unwrap();
+}
+
+#[test]
+fn install_net_timeout_survives_a_late_panic() {
+    mpc_engine::simulate(1, |engine| {
+        let res = engine.install_net_timeout(
+            |_| {
+                std::thread::sleep(Duration::from_millis(100));
+                panic!("panicked after the timeout");
+            },
+            Duration::from_millis(10),
+        );
+        assert_eq!(res, None::<()>);
+        // the network is returned although the closure panicked
+        engine.wait_idle();
+        assert_eq!(engine.available_networks(), engine.num_networks());
+        engine.shutdown().unwrap();
+    })
+    .unwrap();
+}
+
+#[test]
+fn install_net_timeout_resumes_a_panic_in_time() {
+    mpc_engine::simulate(1, |engine| {
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            engine.install_net_timeout(|_| panic!("panicked in time"), Duration::from_secs(5))
+        }));
+        assert!(res.is_err());
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            engine.join_net_timeout(
+                |_| panic!("panicked in time"),
+                |_| std::thread::sleep(Duration::from_millis(100)),
+                Duration::from_millis(10),
+            )
+        }));
+        assert!(res.is_err());
+        // the second network timed out before the panic of the first one was resumed
+        assert_eq!(engine.poisoned_networks().len(), 1);
+        engine.wait_idle();
+        assert!(
+            engine
+                .poisoned_networks()
+                .iter()
+                .all(|&index| index < engine.num_networks())
+        );
+    })
+    .unwrap();
+}
+
+#[test]
+fn spawn_net_with_hint_uses_the_hinted_network() {
+    mpc_engine::simulate(2, |engine| {
+        if engine.id() == 0 {
+            let handle = engine.spawn_net_with_hint(Some(3), |net| net.send(1, &[3]).unwrap());
+            handle.join().unwrap();
+            // the hint did not advance the round-robin order
+            engine.install_net(|net| net.recv(1).unwrap());
+        } else {
+            for _ in 0..3 {
+                let progress = engine
+                    .install_net(|net| net.recv_timeout(0, Duration::from_millis(20)).unwrap());
+                assert!(matches!(
+                    progress,
+                    RecvProgress::Partial { received: 0, .. }
+                ));
+            }
+            assert_eq!(engine.install_net(|net| net.recv(0).unwrap()), [3]);
+            engine
+                .spawn_net_with_hint(Some(0), |net| net.send(0, &[]).unwrap())
+                .join()
+                .unwrap();
+        }
+    })
+    .unwrap();
+}
+
+#[test]
+fn spawn_net_with_hint_falls_back_if_busy() {
+    mpc_engine::simulate(1, |engine| {
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let busy = engine.spawn_net_with_hint(Some(3), move |_| released.recv().unwrap());
+        // network 3 is in use, so this runs on another network instead of waiting
+        let fallback = engine.spawn_net_with_hint(Some(3), |_| ());
+        assert!(fallback.join_timeout(Duration::from_secs(5)).is_ok());
+        release.send(()).unwrap();
+        busy.join().unwrap();
+    })
+    .unwrap();
+}
+
+#[test]
+fn join_many_net_with_more_closures_than_networks() {
+    let results = mpc_engine::simulate(2, |engine| {
+        let id = engine.id();
+        let num = 3 * engine.num_networks();
+        let fs = (0..num)
+            .map(|i| {
+                Box::new(move |net: &TestNetwork| {
+                    net.send(1 - id, &[i as u8]).unwrap();
+                    net.recv(1 - id).unwrap()[0] as usize
+                }) as Box<dyn FnOnce(&TestNetwork) -> usize + Send>
+            })
+            .collect();
+        engine.join_many_net(fs)
+    })
+    .unwrap();
+    for res in results {
+        assert_eq!(
+            res,
+            (0..3 * mpc_engine::NUM_THREADS_NET).collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test]
+fn join_many_net_propagates_a_panic_after_all_closures() {
+    mpc_engine::simulate(1, |engine| {
+        let finished = std::sync::atomic::AtomicUsize::new(0);
+        let fs = (0..2 * engine.num_networks())
+            .map(|i| {
+                let finished = &finished;
+                Box::new(move |_: &TestNetwork| {
+                    if i == 3 {
+                        panic!("closure {i} panicked");
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                    finished.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }) as Box<dyn FnOnce(&TestNetwork) + Send + '_>
+            })
+            .collect();
+        let res =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| engine.join_many_net(fs)));
+        assert!(res.is_err());
+        assert_eq!(
+            finished.load(std::sync::atomic::Ordering::Relaxed),
+            2 * engine.num_networks() - 1
+        );
+        // the network of the panicking closure was returned as well
+        assert_eq!(engine.available_networks(), engine.num_networks());
+    })
+    .unwrap();
+}
+
+#[test]
+fn scope_net_borrows_from_the_stack() {
+    let results = mpc_engine::simulate(2, |engine| {
+        let id = engine.id();
+        let mut received = vec![0; 4];
+        engine.scope_net(|scope| {
+            for (i, slot) in received.iter_mut().enumerate() {
+                scope.spawn(move |net| {
+                    net.send(1 - id, &[i as u8 + 10 * id as u8]).unwrap();
+                    *slot = net.recv(1 - id).unwrap()[0];
+                });
+            }
+        });
+        received
+    })
+    .unwrap();
+    assert_eq!(results, [vec![10, 11, 12, 13], vec![0, 1, 2, 3]]);
+}
+
+#[test]
+fn scope_net_propagates_a_panic() {
+    mpc_engine::simulate(1, |engine| {
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            engine.scope_net(|scope| {
+                scope.spawn(|_| panic!("spawned closure panicked"));
+                scope.spawn(|_| std::thread::sleep(Duration::from_millis(10)));
+            })
+        }));
+        assert!(res.is_err());
+        assert_eq!(engine.available_networks(), engine.num_networks());
+    })
+    .unwrap();
+}
+
+#[test]
+fn broadcast_tree_reaches_every_party() {
+    for num_parties in [1, 2, 3, 5, 8] {
+        for root in [0, num_parties / 2, num_parties - 1] {
+            let payload = format!("from {root} of {num_parties}").into_bytes();
+            let results = mpc_engine::simulate(num_parties, |engine| {
+                let data = (engine.id() == root).then_some(payload.as_slice());
+                engine.broadcast_tree(root, data).unwrap()
+            })
+            .unwrap();
+            assert!(
+                results.iter().all(|data| *data == payload),
+                "{num_parties} parties, root {root}"
+            );
+        }
+    }
+}
+
+#[test]
+fn all_reduce_combines_every_party_once() {
+    for num_parties in [1, 2, 3, 5, 8] {
+        let results = mpc_engine::simulate(num_parties, |engine| {
+            let id = engine.id() as u64;
+            let sum = engine
+                .all_reduce((id + 1).to_be_bytes().to_vec(), |a, b| {
+                    let a = u64::from_be_bytes(a.try_into().unwrap());
+                    let b = u64::from_be_bytes(b.try_into().unwrap());
+                    (a + b).to_be_bytes().to_vec()
+                })
+                .unwrap();
+            // concatenating is not commutative, but all parties still get the same bytes
+            let concat = engine
+                .all_reduce(vec![id as u8], |a, b| [a, b].concat())
+                .unwrap();
+            (sum, concat)
+        })
+        .unwrap();
+        let expected_sum = (num_parties * (num_parties + 1) / 2) as u64;
+        for (sum, concat) in &results {
+            assert_eq!(*sum, expected_sum.to_be_bytes(), "{num_parties} parties");
+            assert_eq!(*concat, results[0].1);
+            let mut ids = concat.clone();
+            ids.sort();
+            assert_eq!(ids, (0..num_parties as u8).collect::<Vec<_>>());
+        }
+    }
+}
+
+#[test]
+fn shutdown_waits_for_unjoined_handles() {
+    let finished = mpc_engine::simulate(2, |engine| {
+        let id = engine.id();
+        let finished = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let handles = (0..4)
+            .map(|i| {
+                let finished = finished.clone();
+                engine.spawn_net(move |net| {
+                    std::thread::sleep(Duration::from_millis(20));
+                    net.send(1 - id, &[i]).unwrap();
+                    assert_eq!(net.recv(1 - id).unwrap(), [i]);
+                    finished.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(handles);
+        engine.shutdown().unwrap();
+        finished.load(std::sync::atomic::Ordering::Relaxed)
+    })
+    .unwrap();
+    assert_eq!(finished, [4, 4]);
+}
+
+#[test]
+fn wait_idle_waits_for_all_spawned_work() {
+    mpc_engine::simulate(1, |engine| {
+        let finished = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let task = |finished: &std::sync::Arc<std::sync::atomic::AtomicUsize>| {
+            let finished = finished.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(30));
+                finished.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+        };
+        let cpu = engine.spawn_cpu(task(&finished));
+        let net = engine.spawn_net({
+            let task = task(&finished);
+            move |_| task()
+        });
+        // the abandoned closure still holds its network after the timeout
+        let abandoned = task(&finished);
+        let res = engine.install_net_timeout(move |_| abandoned(), Duration::ZERO);
+        assert_eq!(res, None);
+
+        engine.wait_idle();
+        assert_eq!(finished.load(std::sync::atomic::Ordering::Relaxed), 3);
+        assert_eq!(engine.in_flight(), 0);
+        assert_eq!(engine.available_networks(), engine.num_networks());
+        cpu.join().unwrap();
+        net.join().unwrap();
+    })
+    .unwrap();
+}
+
+#[test]
+fn join_returns_a_panic_as_error() {
+    mpc_engine::simulate(1, |engine| {
+        let handle = engine.spawn_cpu(|| -> usize { panic!("cpu task panicked") });
+        assert_eq!(
+            handle.join(),
+            Err(JoinError::Panicked("cpu task panicked".to_owned()))
+        );
+        let handle = engine.spawn_net(|_| panic!("net task panicked: {}", 42));
+        assert_eq!(
+            handle.join(),
+            Err::<(), _>(JoinError::Panicked("net task panicked: 42".to_owned()))
+        );
+        // the engine is still usable
+        assert_eq!(engine.spawn_cpu(|| 1).join(), Ok(1));
+    })
+    .unwrap();
+}
+
+#[test]
+fn try_join_does_not_block() {
+    mpc_engine::simulate(1, |engine| {
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let handle = engine.spawn_cpu(move || released.recv().unwrap());
+        // the task waits for the release, so the handle is returned
+        let mut handle = handle.try_join().unwrap_err();
+        release.send(()).unwrap();
+        loop {
+            match handle.try_join() {
+                Ok(res) => break assert_eq!(res, Ok(())),
+                Err(pending) => handle = pending,
+            }
+            std::thread::yield_now();
+        }
+    })
+    .unwrap();
+}
+
+#[test]
+fn join_timeout_returns_the_handle() {
+    mpc_engine::simulate(1, |engine| {
+        let handle = engine.spawn_net(|_| {
+            std::thread::sleep(Duration::from_millis(100));
+            7
+        });
+        let handle = handle.join_timeout(Duration::from_millis(10)).unwrap_err();
+        // the task kept running and can be joined later
+        assert_eq!(handle.join_timeout(Duration::from_secs(5)).unwrap(), Ok(7));
+    })
+    .unwrap();
+}
+
+/// Polls `future` on the current thread until it is ready, parking while it is pending.
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    struct Unpark(std::thread::Thread);
+
+    impl std::task::Wake for Unpark {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = std::task::Waker::from(std::sync::Arc::new(Unpark(std::thread::current())));
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            std::task::Poll::Ready(res) => return res,
+            std::task::Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[test]
+fn awaiting_a_handle_yields_its_result() {
+    mpc_engine::simulate(1, |engine| {
+        let handle = engine.spawn_cpu(|| {
+            std::thread::sleep(Duration::from_millis(20));
+            3
+        });
+        assert_eq!(block_on(handle), Ok(3));
+        let handle = engine.spawn_cpu(|| -> usize { panic!("awaited task panicked") });
+        assert_eq!(
+            block_on(handle),
+            Err(JoinError::Panicked("awaited task panicked".to_owned()))
+        );
+    })
+    .unwrap();
+}
+
+#[test]
+fn map_transforms_the_result() {
+    mpc_engine::simulate(1, |engine| {
+        let handle = engine
+            .spawn_cpu(|| 20)
+            .map(|res| res + 1)
+            .map(|res| res * 2);
+        assert_eq!(handle.join(), Ok(42));
+        let handle = engine
+            .spawn_cpu(|| -> usize { panic!("mapped task panicked") })
+            .map(|_| -> usize { unreachable!("not called after a panic") });
+        assert_eq!(
+            handle.join(),
+            Err(JoinError::Panicked("mapped task panicked".to_owned()))
+        );
+    })
+    .unwrap();
+}
+
+#[test]
+fn join_all_joins_every_handle_after_an_error() {
+    mpc_engine::simulate(1, |engine| {
+        let finished = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let handles = (0..4)
+            .map(|i| {
+                let finished = finished.clone();
+                engine.spawn_cpu(move || {
+                    if i == 1 {
+                        panic!("task {i} panicked");
+                    }
+                    std::thread::sleep(Duration::from_millis(20));
+                    finished.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    i
+                })
+            })
+            .collect();
+        assert_eq!(
+            mpc_engine::join_all(handles),
+            Err(JoinError::Panicked("task 1 panicked".to_owned()))
+        );
+        // the handles after the failed one were joined as well
+        assert_eq!(finished.load(std::sync::atomic::Ordering::Relaxed), 3);
+
+        let handles = (0..4).map(|i| engine.spawn_cpu(move || i)).collect();
+        assert_eq!(mpc_engine::join_all(handles), Ok(vec![0, 1, 2, 3]));
+    })
+    .unwrap();
+}
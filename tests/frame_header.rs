@@ -0,0 +1,73 @@
+//! Exchange messages over every combination of the settings that change the frame header.
+
+use mpc_engine::{Address, Network, NetworkConfig, TcpNetwork};
+use std::net::SocketAddr;
+
+fn messages() -> Vec<Vec<u8>> {
+    [0, 1, 255, 4096, 1 << 20]
+        .into_iter()
+        .map(|len| (0..len).map(|i| (i % 251) as u8).collect())
+        .collect()
+}
+
+/// Sets up two parties on `base_port` with `config` and checks that `wrap`ped networks exchange
+/// all messages in order.
+fn exchange<W: Network>(
+    base_port: u16,
+    config: NetworkConfig,
+    wrap: impl Fn(TcpNetwork) -> W + Sync,
+) {
+    let addrs: Vec<SocketAddr> = (0..2)
+        .map(|i| SocketAddr::from(([127, 0, 0, 1], base_port + i)))
+        .collect();
+    let list: Vec<Address> = addrs.iter().map(|&addr| addr.into()).collect();
+    std::thread::scope(|scope| {
+        for id in 0..2 {
+            let (addrs, list, config, wrap) = (&addrs, &list, config.clone(), &wrap);
+            scope.spawn(move || {
+                let net = TcpNetwork::networks_with_config(id, addrs[id], list, 1, config)
+                    .unwrap()
+                    .pop()
+                    .unwrap();
+                let net = wrap(net);
+                let other = 1 - id;
+                for msg in messages() {
+                    net.send(other, &msg).unwrap();
+                }
+                for msg in messages() {
+                    assert_eq!(net.recv(other).unwrap(), msg);
+                }
+            });
+        }
+    });
+}
+
+fn configs() -> impl Iterator<Item = NetworkConfig> {
+    [false, true].into_iter().flat_map(|crc| {
+        [1, 3]
+            .into_iter()
+            .map(move |streams_per_peer| NetworkConfig {
+                crc,
+                streams_per_peer,
+                ..Default::default()
+            })
+    })
+}
+
+#[test]
+fn header_matrix() {
+    for (i, config) in configs().enumerate() {
+        exchange(17200 + 2 * i as u16, config, |net| net);
+    }
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn header_matrix_compressed() {
+    use mpc_engine::CompressedNetwork;
+    for (i, config) in configs().enumerate() {
+        exchange(17220 + 2 * i as u16, config, |net| {
+            CompressedNetwork::new(net, 3, 64)
+        });
+    }
+}
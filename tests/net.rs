@@ -0,0 +1,213 @@
+use mpc_engine::{
+    Address, BitFlip, ByzantineNetwork, LinkMatrix, LinkProps, Network, NetworkConfig,
+    NetworkError, PausableNetwork, RecvProgress, TcpNetwork, TestNetwork,
+};
+use std::{net::SocketAddr, time::Duration};
+
+fn tcp_pair(base_port: u16, configs: [NetworkConfig; 2]) -> Vec<eyre::Result<Vec<TcpNetwork>>> {
+    let addrs: Vec<SocketAddr> = (0..2)
+        .map(|i| SocketAddr::from(([127, 0, 0, 1], base_port + i)))
+        .collect();
+    let list: Vec<Address> = addrs.iter().map(|&addr| addr.into()).collect();
+    std::thread::scope(|scope| {
+        let handles = configs
+            .into_iter()
+            .enumerate()
+            .map(|(id, config)| {
+                let (addrs, list) = (&addrs, &list);
+                scope
+                    .spawn(move || TcpNetwork::networks_with_config(id, addrs[id], list, 1, config))
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+#[test]
+fn flag_mismatch_fails_on_both_sides() {
+    let crc = NetworkConfig {
+        crc: true,
+        ..Default::default()
+    };
+    for res in tcp_pair(17100, [crc, NetworkConfig::default()]) {
+        let err = res.unwrap_err();
+        assert!(format!("{err:?}").contains("codec flags"), "{err:?}");
+    }
+}
+
+#[test]
+fn blocked_pausable_send_does_not_block_recv() {
+    let props = LinkMatrix::uniform(
+        2,
+        LinkProps {
+            capacity: Some(1),
+            ..Default::default()
+        },
+    );
+    let mut nets = TestNetwork::networks_with_link_props(2, 1, props);
+    let net1 = nets.pop().unwrap().pop().unwrap();
+    let net0 = PausableNetwork::new(nets.pop().unwrap().pop().unwrap(), 1);
+    let (received, wait_received) = std::sync::mpsc::channel();
+    std::thread::scope(|scope| {
+        // the second send blocks until party 1 takes the first message
+        let sender = scope.spawn(|| {
+            net0.send(1, b"a").unwrap();
+            net0.send(1, b"b").unwrap();
+        });
+        let receiver = scope.spawn(|| {
+            assert_eq!(net0.recv(1).unwrap(), b"x");
+            received.send(()).unwrap();
+        });
+        net1.send(0, b"x").unwrap();
+        // party 1 only receives once party 0 received, while its send is still blocked
+        wait_received.recv().unwrap();
+        assert_eq!(net1.recv(0).unwrap(), b"a");
+        assert_eq!(net1.recv(0).unwrap(), b"b");
+        sender.join().unwrap();
+        receiver.join().unwrap();
+    });
+}
+
+#[test]
+fn resume_sends_buffered_messages_first() {
+    let mut nets = TestNetwork::party_networks(2);
+    let net1 = nets.pop().unwrap();
+    let net0 = PausableNetwork::new(nets.pop().unwrap(), 2);
+    net0.pause_peer(1);
+    net0.send(1, b"a").unwrap();
+    net0.send(1, b"b").unwrap();
+    std::thread::scope(|scope| {
+        // the buffer is full, this send waits for the resume
+        let sender = scope.spawn(|| net0.send(1, b"c").unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        net0.resume_peer(1).unwrap();
+        sender.join().unwrap();
+    });
+    for expected in [b"a", b"b", b"c"] {
+        assert_eq!(net1.recv(0).unwrap(), expected);
+    }
+}
+
+#[test]
+fn recv_timeout_does_not_block() {
+    let mut nets = TestNetwork::party_networks(2);
+    let net1 = nets.pop().unwrap();
+    let net0 = nets.pop().unwrap();
+    let timeout = std::time::Duration::from_millis(20);
+    assert!(matches!(
+        net0.recv_timeout(1, timeout).unwrap(),
+        RecvProgress::Partial { received: 0, .. }
+    ));
+    net1.send(0, b"x").unwrap();
+    assert!(matches!(
+        net0.recv_timeout(1, timeout).unwrap(),
+        RecvProgress::Complete(data) if data == b"x"
+    ));
+}
+
+#[test]
+fn size_histogram_is_none_when_disabled() {
+    for (port, histograms) in [(17110, false), (17111, true)] {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let config = NetworkConfig {
+            histograms,
+            ..Default::default()
+        };
+        let net = TcpNetwork::networks_with_config(0, addr, &[addr.into()], 1, config)
+            .unwrap()
+            .pop()
+            .unwrap();
+        // a single party has no peers, but the histograms are still reported if enabled
+        assert_eq!(net.size_histogram().is_some(), histograms);
+    }
+}
+
+#[test]
+fn consistent_broadcast_detects_equivocation() {
+    let results = std::thread::scope(|scope| {
+        let handles = TestNetwork::party_networks(3)
+            .into_iter()
+            .map(|net| {
+                scope.spawn(move || {
+                    let id = net.id();
+                    // party 0 sends a different value to party 1
+                    let net: Box<dyn Network> = if id == 0 {
+                        Box::new(ByzantineNetwork::new(net, [1], BitFlip(1), 0))
+                    } else {
+                        Box::new(net)
+                    };
+                    net.consistent_broadcast(&[id as u8; 8])
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    let Err(NetworkError::Other(err)) = &results[2] else {
+        panic!("party 2 did not detect the equivocation: {:?}", results[2]);
+    };
+    assert!(err.to_string().contains("party 0 equivocated"), "{err}");
+}
+
+#[test]
+fn consistent_broadcast_returns_values_by_party() {
+    let results = mpc_engine::simulate(3, |engine| {
+        let id = engine.id();
+        engine.install_net(|net| net.consistent_broadcast(&[id as u8]).unwrap())
+    })
+    .unwrap();
+    for (id, values) in results.into_iter().enumerate() {
+        for (j, value) in values.into_iter().enumerate() {
+            assert_eq!(value, (j != id).then(|| vec![j as u8]));
+        }
+    }
+}
+
+/// Sends the bytes `0..32` from party 0 to party 1 over faulty links and returns what arrived.
+fn faulty_delivery(seed: u64, drop_rate: f64, reorder: usize) -> Vec<u8> {
+    let links = LinkMatrix::new(2).with_faults(seed, drop_rate, reorder);
+    let mut nets = TestNetwork::networks_with_link_props(2, 1, links);
+    let receiver = nets.pop().unwrap().remove(0);
+    let sender = nets.pop().unwrap().remove(0);
+    for i in 0..32 {
+        sender.send(1, &[i]).unwrap();
+    }
+    // dropping the sender flushes the messages held back for reordering
+    drop(sender);
+    let mut received = Vec::new();
+    while let Ok(data) = receiver.recv(0) {
+        received.extend(data);
+    }
+    received
+}
+
+#[test]
+fn seeded_faults_are_deterministic() {
+    let reordered = faulty_delivery(7, 0.0, 4);
+    assert_eq!(reordered, faulty_delivery(7, 0.0, 4));
+    assert_ne!(reordered, (0..32).collect::<Vec<_>>());
+    let mut sorted = reordered.clone();
+    sorted.sort();
+    assert_eq!(sorted, (0..32).collect::<Vec<_>>());
+
+    let dropped = faulty_delivery(7, 0.5, 0);
+    assert_eq!(dropped, faulty_delivery(7, 0.5, 0));
+    assert!(dropped.len() < 32);
+    assert!(dropped.is_sorted());
+}
+
+#[test]
+fn dropped_message_times_out() {
+    let links = LinkMatrix::new(2).with_faults(0, 1.0, 0);
+    let mut nets = TestNetwork::networks_with_link_props(2, 1, links);
+    let receiver = nets
+        .pop()
+        .unwrap()
+        .remove(0)
+        .with_timeout(Duration::from_millis(50));
+    let sender = nets.pop().unwrap().remove(0);
+    sender.send(1, b"lost").unwrap();
+    assert!(matches!(receiver.recv(0), Err(NetworkError::Timeout(0))));
+}
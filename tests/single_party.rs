@@ -0,0 +1,37 @@
+//! The collective operations are no-ops with a single party.
+
+use mpc_engine::{Network, TestNetwork};
+
+fn single_party() -> TestNetwork {
+    let net = TestNetwork::party_networks(1).pop().unwrap();
+    assert_eq!(net.num_parties(), 1);
+    net
+}
+
+#[test]
+fn broadcast_does_nothing() {
+    let net = single_party();
+    net.broadcast(b"data").unwrap();
+    net.broadcast_acked(b"data").unwrap();
+}
+
+#[test]
+fn recv_from_all_is_empty() {
+    assert!(single_party().recv_from_all().unwrap().is_empty());
+}
+
+#[test]
+fn for_each_peer_never_calls_f() {
+    let results = mpc_engine::simulate(1, |engine| {
+        engine.for_each_peer(|peer, _| panic!("called for peer {peer}"))
+    })
+    .unwrap();
+    assert_eq!(results, [vec![None::<()>]]);
+}
+
+#[test]
+fn ring_helpers_fail() {
+    let net = single_party();
+    assert!(net.send_next(b"data").is_err());
+    assert!(net.recv_prev().is_err());
+}
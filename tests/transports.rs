@@ -0,0 +1,337 @@
+use mpc_engine::{Network, NetworkConfig};
+
+/// Sends a message from every party to the other one and checks that it arrived.
+fn exchange<N: Network>(nets: &[N; 2]) {
+    std::thread::scope(|scope| {
+        for net in nets {
+            scope.spawn(move || {
+                let other = 1 - net.id();
+                net.send(other, &[net.id() as u8; 1000]).unwrap();
+                assert_eq!(net.recv(other).unwrap(), [other as u8; 1000]);
+            });
+        }
+    });
+}
+
+/// Runs the setup of both parties concurrently and returns their first network.
+fn setup<N: Send>(setup: impl Fn(usize) -> eyre::Result<Vec<N>> + Sync) -> [eyre::Result<N>; 2] {
+    std::thread::scope(|scope| {
+        let setup = &setup;
+        let handles =
+            [0, 1].map(|id| scope.spawn(move || setup(id).map(|mut nets| nets.remove(0))));
+        handles.map(|handle| handle.join().unwrap())
+    })
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_exchange() {
+    use mpc_engine::UnixNetwork;
+
+    let dir = std::env::temp_dir();
+    let paths = [0, 1].map(|id| dir.join(format!("mpc_engine_{}_{id}.sock", std::process::id())));
+    let [n0, n1] =
+        setup(|id| UnixNetwork::networks_with_config(id, &paths, 1, NetworkConfig::default()));
+    let nets = [n0.unwrap(), n1.unwrap()];
+    exchange(&nets);
+    // the socket files are removed after the setup
+    assert!(paths.iter().all(|path| !path.exists()));
+}
+
+#[cfg(feature = "shmem")]
+#[test]
+fn shmem_enforces_the_config() {
+    use mpc_engine::{NetworkError, ShmemNetwork};
+    use std::time::Duration;
+
+    let config = NetworkConfig {
+        read_timeout: Duration::from_millis(50),
+        max_message_size: 16,
+        ..Default::default()
+    };
+    let name = format!("mpc_engine_test_{}", std::process::id());
+    let mut nets = std::thread::scope(|scope| {
+        let handles = (0..2)
+            .map(|id| {
+                let (name, config) = (&name, config.clone());
+                scope.spawn(move || {
+                    ShmemNetwork::networks_with_config(id, 2, name, 64, 1, config)
+                        .unwrap()
+                        .remove(0)
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    let (n1, n0) = (nets.pop().unwrap(), nets.pop().unwrap());
+
+    n0.send(1, &[7; 16]).unwrap();
+    assert_eq!(n1.recv(0).unwrap(), [7; 16]);
+    assert!(matches!(n1.recv(0), Err(NetworkError::Timeout(0))));
+    n0.send(1, &[7; 17]).unwrap();
+    assert!(matches!(
+        n1.recv(0),
+        Err(NetworkError::MessageTooLarge {
+            peer: 0,
+            len: 17,
+            max: 16
+        })
+    ));
+}
+
+/// The loopback addresses of two parties listening on `port` and `port + 1`.
+#[cfg(feature = "test-certs")]
+fn loopback(port: u16) -> [std::net::SocketAddr; 2] {
+    [0, 1].map(|id| std::net::SocketAddr::from(([127, 0, 0, 1], port + id)))
+}
+
+#[cfg(all(feature = "quic", feature = "test-certs"))]
+#[test]
+fn quic_exchange() {
+    use mpc_engine::{QuicNetwork, TlsNetwork};
+
+    let addrs = loopback(17300);
+    let peers = addrs.map(Into::into);
+    let (certs, keys) = TlsNetwork::generate_test_certs(2);
+    let [n0, n1] = setup(|id| {
+        QuicNetwork::networks_with_config(
+            id,
+            addrs[id],
+            &peers,
+            certs.clone(),
+            keys[id].clone_key(),
+            1,
+            NetworkConfig::default(),
+        )
+    });
+    exchange(&[n0.unwrap(), n1.unwrap()]);
+}
+
+/// Sets up a [`TlsNetwork`](mpc_engine::TlsNetwork) between two parties listening at `port` and
+/// `port + 1`, with the config `config` built from their certificates.
+#[cfg(feature = "test-certs")]
+fn tls_setup(
+    port: u16,
+    config: impl Fn(&[rustls::pki_types::CertificateDer<'static>]) -> NetworkConfig + Sync,
+) -> [eyre::Result<mpc_engine::TlsNetwork>; 2] {
+    use mpc_engine::TlsNetwork;
+
+    let addrs = loopback(port);
+    let peers = addrs.map(Into::into);
+    let (certs, keys) = TlsNetwork::generate_test_certs(2);
+    setup(|id| {
+        TlsNetwork::networks_with_config(
+            id,
+            addrs[id],
+            &peers,
+            certs.clone(),
+            keys[id].clone_key(),
+            1,
+            config(&certs),
+        )
+    })
+}
+
+#[cfg(feature = "test-certs")]
+#[test]
+fn tls_mutual_exchange() {
+    let [n0, n1] = tls_setup(17310, |_| NetworkConfig {
+        mutual_tls: true,
+        ..Default::default()
+    });
+    exchange(&[n0.unwrap(), n1.unwrap()]);
+}
+
+#[cfg(feature = "test-certs")]
+#[test]
+fn tls_accepts_matching_peer_identities() {
+    use mpc_engine::PeerIdentity;
+
+    let [n0, n1] = tls_setup(17320, |certs| NetworkConfig {
+        peer_identities: Some(certs.iter().map(PeerIdentity::fingerprint).collect()),
+        ..Default::default()
+    });
+    exchange(&[n0.unwrap(), n1.unwrap()]);
+}
+
+#[cfg(feature = "test-certs")]
+#[test]
+fn tls_rejects_a_mismatched_peer_identity() {
+    use mpc_engine::PeerIdentity;
+    use std::time::Duration;
+
+    // both parties expect the certificate of the other party to be their own
+    let [n0, n1] = tls_setup(17330, |certs| NetworkConfig {
+        peer_identities: Some(certs.iter().rev().map(PeerIdentity::fingerprint).collect()),
+        connect_timeout: Duration::from_secs(2),
+        ..Default::default()
+    });
+    let err = n0.unwrap_err();
+    assert!(
+        err.to_string().contains("does not match its identity"),
+        "{err:?}"
+    );
+    assert!(n1.is_err());
+}
+
+#[cfg(feature = "test-certs")]
+#[test]
+fn tls_multiplex_exchange() {
+    let [n0, n1] = tls_setup(17340, |_| NetworkConfig {
+        tls_multiplex: true,
+        ..Default::default()
+    });
+    exchange(&[n0.unwrap(), n1.unwrap()]);
+}
+
+/// A minimal SOCKS5 proxy relaying the connections of [`NetworkConfig::socks5_proxy`].
+struct Proxy {
+    addr: std::net::SocketAddr,
+    /// Both sockets of every relayed connection.
+    relays: std::sync::Arc<std::sync::Mutex<Vec<std::net::TcpStream>>>,
+}
+
+impl Proxy {
+    /// Starts a proxy that requires the `credentials`, if any.
+    fn start(credentials: Option<(&'static str, &'static str)>) -> Self {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relays = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let relays_ = relays.clone();
+        std::thread::spawn(move || {
+            for client in listener.incoming() {
+                let mut client = client.unwrap();
+                let mut header = [0; 2];
+                client.read_exact(&mut header).unwrap();
+                let mut methods = vec![0; header[1] as usize];
+                client.read_exact(&mut methods).unwrap();
+                let method = if credentials.is_some() { 2 } else { 0 };
+                assert!(methods.contains(&method));
+                client.write_all(&[5, method]).unwrap();
+                if let Some((username, password)) = credentials {
+                    let mut version = [0; 1];
+                    client.read_exact(&mut version).unwrap();
+                    let mut field = || {
+                        let mut len = [0; 1];
+                        client.read_exact(&mut len).unwrap();
+                        let mut field = vec![0; len[0] as usize];
+                        client.read_exact(&mut field).unwrap();
+                        field
+                    };
+                    let (user, pass) = (field(), field());
+                    let ok = user == username.as_bytes() && pass == password.as_bytes();
+                    client.write_all(&[1, u8::from(!ok)]).unwrap();
+                    if !ok {
+                        continue;
+                    }
+                }
+                // only IPv4 targets
+                let mut request = [0; 10];
+                client.read_exact(&mut request).unwrap();
+                assert_eq!(request[..4], [5, 1, 0, 1]);
+                let ip = std::net::Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+                let port = u16::from_be_bytes([request[8], request[9]]);
+                let target = std::net::TcpStream::connect((ip, port)).unwrap();
+                client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+                let mut relays = relays_.lock().unwrap();
+                for (mut from, mut to) in [
+                    (client.try_clone().unwrap(), target.try_clone().unwrap()),
+                    (target.try_clone().unwrap(), client.try_clone().unwrap()),
+                ] {
+                    std::thread::spawn(move || {
+                        let _ = std::io::copy(&mut from, &mut to);
+                        let _ = to.shutdown(std::net::Shutdown::Write);
+                    });
+                }
+                relays.extend([client, target]);
+            }
+        });
+        Self { addr, relays }
+    }
+
+    /// Breaks all connections relayed so far.
+    fn break_connections(&self) {
+        for stream in self.relays.lock().unwrap().iter() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+
+    /// The number of connections relayed so far.
+    fn connections(&self) -> usize {
+        self.relays.lock().unwrap().len() / 2
+    }
+}
+
+/// Sets up a [`TcpNetwork`](mpc_engine::TcpNetwork) between two parties listening at `port` and
+/// `port + 1` with the config `config`.
+fn tcp_setup(port: u16, config: NetworkConfig) -> [mpc_engine::TcpNetwork; 2] {
+    use mpc_engine::TcpNetwork;
+
+    let addrs = [0, 1].map(|id| std::net::SocketAddr::from(([127, 0, 0, 1], port + id)));
+    let peers = addrs.map(Into::into);
+    let [n0, n1] =
+        setup(|id| TcpNetwork::networks_with_config(id, addrs[id], &peers, 1, config.clone()));
+    [n0.unwrap(), n1.unwrap()]
+}
+
+#[test]
+fn tcp_connects_through_a_socks5_proxy() {
+    use mpc_engine::Socks5Proxy;
+
+    let proxy = Proxy::start(Some(("user", "secret")));
+    let nets = tcp_setup(
+        17350,
+        NetworkConfig {
+            socks5_proxy: Some(Socks5Proxy {
+                address: proxy.addr.into(),
+                credentials: Some(("user".to_owned(), "secret".to_owned())),
+            }),
+            ..Default::default()
+        },
+    );
+    exchange(&nets);
+    // only party 0 connects, to party 1
+    assert_eq!(proxy.connections(), 1);
+}
+
+#[test]
+fn tcp_reconnects_a_broken_connection() {
+    use mpc_engine::{RecvProgress, Socks5Proxy};
+    use std::time::Duration;
+
+    let proxy = Proxy::start(None);
+    let nets = tcp_setup(
+        17360,
+        NetworkConfig {
+            socks5_proxy: Some(Socks5Proxy {
+                address: proxy.addr.into(),
+                credentials: None,
+            }),
+            reconnect_attempts: Some(5),
+            ..Default::default()
+        },
+    );
+    exchange(&nets);
+    proxy.break_connections();
+    let [n0, n1] = &nets;
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            // a message sent on the broken connection is lost, so only send after reconnecting
+            let progress = n0.recv_timeout(1, Duration::from_millis(200)).unwrap();
+            assert!(matches!(
+                progress,
+                RecvProgress::Partial { received: 0, .. }
+            ));
+            n0.send(1, b"again").unwrap();
+            assert_eq!(n0.recv(1).unwrap(), b"back");
+        });
+        assert_eq!(n1.recv(0).unwrap(), b"again");
+        n1.send(0, b"back").unwrap();
+    });
+    assert_eq!(proxy.connections(), 2);
+}
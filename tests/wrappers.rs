@@ -0,0 +1,338 @@
+use mpc_engine::{
+    AuthNetwork, LinkMatrix, LinkProps, MpcEngine, Network, NetworkError, PriorityNetwork,
+    ReplayNetwork, SequencedNetwork, TeeNetwork, TestNetwork, TranscriptNetwork,
+};
+use std::path::PathBuf;
+
+#[test]
+fn tee_mirrors_sends_and_keeps_the_context() {
+    let [p0, p1]: [TestNetwork; 2] = TestNetwork::party_networks(2).try_into().unwrap();
+    let [s0, s1]: [TestNetwork; 2] = TestNetwork::party_networks(2).try_into().unwrap();
+    let tee = TeeNetwork::new(p0, s0);
+    tee.send(1, b"hello").unwrap();
+    assert_eq!(p1.recv(0).unwrap(), b"hello");
+    assert_eq!(s1.recv(0).unwrap(), b"hello");
+    // receives are served by the primary network only
+    p1.send(0, b"reply").unwrap();
+    s1.send(0, b"ignored").unwrap();
+    assert_eq!(tee.recv(1).unwrap(), b"reply");
+
+    // the secondary network only knows a single party, so mirroring to party 1 fails
+    let [p0, _p1]: [TestNetwork; 2] = TestNetwork::party_networks(2).try_into().unwrap();
+    let tee = TeeNetwork::new(p0, TestNetwork::party_networks(1).remove(0));
+    let Err(NetworkError::Other(err)) = tee.send(1, b"hello") else {
+        panic!("mirroring to an unknown peer did not fail");
+    };
+    assert_eq!(err.to_string(), "while mirroring to secondary network");
+    assert!(matches!(
+        err.downcast_ref::<NetworkError>(),
+        Some(NetworkError::UnknownPeer(1))
+    ));
+}
+
+fn pair() -> (TestNetwork, TestNetwork) {
+    let [n0, n1]: [TestNetwork; 2] = TestNetwork::party_networks(2).try_into().unwrap();
+    (n0, n1)
+}
+
+#[test]
+fn sequenced_rejects_unknown_peers() {
+    let (n0, _n1) = pair();
+    let net = SequencedNetwork::new(n0, 4);
+    assert!(matches!(
+        net.send(2, b"x"),
+        Err(NetworkError::UnknownPeer(2))
+    ));
+    assert!(matches!(net.recv(2), Err(NetworkError::UnknownPeer(2))));
+    assert!(net.recover_peer(2).is_err());
+}
+
+#[test]
+fn sequenced_recovers_a_restarted_party() {
+    let (n0, n1) = pair();
+    let a = SequencedNetwork::new(n0, 8);
+    let b = SequencedNetwork::new(n1, 8);
+    for msg in [b"m0", b"m1"] {
+        a.send(1, msg).unwrap();
+        assert_eq!(b.recv(0).unwrap(), msg);
+    }
+    b.send(0, b"n0").unwrap();
+    assert_eq!(a.recv(1).unwrap(), b"n0");
+    let checkpoint = b.checkpoint();
+    assert_eq!(checkpoint.next_send, [1, 0]);
+    assert_eq!(checkpoint.next_recv, [2, 0]);
+
+    // b makes progress past its checkpoint and crashes, m3 is lost on the way
+    a.send(1, b"m2").unwrap();
+    a.send(1, b"m3").unwrap();
+    assert_eq!(b.recv(0).unwrap(), b"m2");
+    b.send(0, b"n1").unwrap();
+    assert_eq!(a.recv(1).unwrap(), b"n1");
+    drop(b);
+
+    // a keeps its state on the new connection, b restarts from its checkpoint
+    let (n0, n1) = pair();
+    let a = a.rewrap(n0).unwrap();
+    let b = SequencedNetwork::new(n1, 8);
+    b.restore(&checkpoint).unwrap();
+    std::thread::scope(|scope| {
+        scope.spawn(|| a.recover_peer(1).unwrap());
+        scope.spawn(|| b.recover_peer(0).unwrap());
+    });
+
+    // b receives everything after its checkpoint exactly once
+    assert_eq!(b.recv(0).unwrap(), b"m2");
+    assert_eq!(b.recv(0).unwrap(), b"m3");
+    a.send(1, b"m4").unwrap();
+    assert_eq!(b.recv(0).unwrap(), b"m4");
+    // b replays its computation, a drops the message it already received
+    b.send(0, b"n1").unwrap();
+    b.send(0, b"n2").unwrap();
+    assert_eq!(a.recv(1).unwrap(), b"n2");
+    assert_eq!(a.checkpoint().next_recv, [0, 3]);
+    assert_eq!(b.checkpoint().next_recv, [5, 0]);
+}
+
+#[test]
+fn engine_restores_sequence_checkpoints() {
+    let nets = TestNetwork::networks(2, 2);
+    let results = std::thread::scope(|scope| {
+        let handles = nets
+            .into_iter()
+            .enumerate()
+            .map(|(id, nets)| {
+                let nets = nets
+                    .into_iter()
+                    .map(|net| SequencedNetwork::new(net, 4))
+                    .collect();
+                scope.spawn(move || {
+                    let engine = MpcEngine::new(id, 2, 1, nets);
+                    let exchange = || {
+                        engine.install_net(|net| {
+                            net.send(1 - id, &[id as u8]).unwrap();
+                            net.recv(1 - id).unwrap()
+                        })
+                    };
+                    exchange();
+                    let checkpoints = engine.checkpoint_sequences();
+                    exchange();
+                    exchange();
+                    assert_ne!(engine.checkpoint_sequences(), checkpoints);
+                    engine.restore_sequences(&checkpoints).unwrap();
+                    assert_eq!(engine.checkpoint_sequences(), checkpoints);
+                    assert!(engine.restore_sequences(&checkpoints[..1]).is_err());
+                    checkpoints
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    // one message was exchanged on network 0 before the checkpoint
+    for (id, checkpoints) in results.into_iter().enumerate() {
+        assert_eq!(checkpoints[0].next_send[1 - id], 1);
+        assert_eq!(checkpoints[0].next_recv[1 - id], 1);
+        assert_eq!(checkpoints[1].next_send[1 - id], 0);
+    }
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_bounds_the_decompressed_size() {
+    use mpc_engine::CompressedNetwork;
+
+    let (n0, n1) = pair();
+    let sender = CompressedNetwork::new(n0, 3, 64);
+    let receiver = CompressedNetwork::with_max_message_size(n1, 3, 64, 1024);
+    // below the threshold the message is sent raw
+    sender.send(1, b"small").unwrap();
+    assert_eq!(receiver.recv(0).unwrap(), b"small");
+    sender.send(1, &[0; 1024]).unwrap();
+    assert_eq!(receiver.recv(0).unwrap(), [0; 1024]);
+    // a megabyte of zeros compresses to a few bytes, but expands past the limit
+    sender.send(1, &vec![0; 1 << 20]).unwrap();
+    assert!(matches!(
+        receiver.recv(0),
+        Err(NetworkError::MessageTooLarge {
+            peer: 0,
+            len: 1_048_576,
+            max: 1024
+        })
+    ));
+}
+
+#[test]
+fn priority_control_overtakes_bulk() {
+    // a link that holds a single frame, so the bulk send blocks until the receiver reads
+    let props = LinkProps {
+        capacity: Some(1),
+        ..Default::default()
+    };
+    let mut nets = TestNetwork::networks_with_link_props(2, 1, LinkMatrix::uniform(2, props));
+    let receiver = PriorityNetwork::new(nets.pop().unwrap().remove(0), 16);
+    let sender = PriorityNetwork::new(nets.pop().unwrap().remove(0), 16);
+    let bulk = (0..1 << 16).map(|i| i as u8).collect::<Vec<_>>();
+    std::thread::scope(|scope| {
+        let bulk_send = scope.spawn(|| sender.send(1, &bulk).unwrap());
+        // the bulk send fills the link before the control message is sent
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        scope.spawn(|| sender.send_control(1, b"abort").unwrap());
+        assert_eq!(receiver.recv_control(0).unwrap(), b"abort");
+        assert!(!bulk_send.is_finished());
+        assert_eq!(receiver.recv(0).unwrap(), bulk);
+    });
+}
+
+#[test]
+fn priority_rejects_oversized_messages() {
+    let (n0, n1) = pair();
+    let sender = PriorityNetwork::new(n0, 4);
+    let receiver = PriorityNetwork::with_max_message_size(n1, 4, 10);
+    sender.send(1, &[1; 10]).unwrap();
+    sender.send(1, &[2; 16]).unwrap();
+    sender.send(1, b"after").unwrap();
+    assert_eq!(receiver.recv(0).unwrap(), [1; 10]);
+    assert!(matches!(
+        receiver.recv(0),
+        Err(NetworkError::MessageTooLarge {
+            peer: 0,
+            len: 12,
+            max: 10
+        })
+    ));
+    // the rest of the oversized message is dropped
+    assert_eq!(receiver.recv(0).unwrap(), b"after");
+}
+
+/// Two authenticated frames sent from party 0 to party 1, as they appear on the wire.
+fn auth_frames(secret: &[u8]) -> Vec<Vec<u8>> {
+    let (n0, n1) = pair();
+    let sender = AuthNetwork::new(n0, secret);
+    sender.send(1, b"first").unwrap();
+    sender.send(1, b"second").unwrap();
+    vec![n1.recv(0).unwrap(), n1.recv(0).unwrap()]
+}
+
+/// Delivers `frames` to an [`AuthNetwork`] of party 1 and returns the results of its receives.
+fn auth_deliver(secret: &[u8], frames: &[Vec<u8>]) -> Vec<Result<Vec<u8>, NetworkError>> {
+    let (n0, n1) = pair();
+    let receiver = AuthNetwork::new(n1, secret);
+    frames
+        .iter()
+        .map(|frame| {
+            n0.send(1, frame).unwrap();
+            receiver.recv(0)
+        })
+        .collect()
+}
+
+#[test]
+fn auth_round_trips() {
+    let (n0, n1) = pair();
+    let (a0, a1) = (
+        AuthNetwork::new(n0, b"secret"),
+        AuthNetwork::new(n1, b"secret"),
+    );
+    a0.send(1, b"ping").unwrap();
+    assert_eq!(a1.recv(0).unwrap(), b"ping");
+    a1.send(0, b"pong").unwrap();
+    assert_eq!(a0.recv(1).unwrap(), b"pong");
+
+    let frames = auth_frames(b"secret");
+    let results = auth_deliver(b"secret", &frames);
+    assert_eq!(results[0].as_ref().unwrap(), b"first");
+    assert_eq!(results[1].as_ref().unwrap(), b"second");
+}
+
+#[test]
+fn auth_rejects_tampered_messages() {
+    let mut frames = auth_frames(b"secret");
+    frames[0][0] ^= 1;
+    let results = auth_deliver(b"secret", &frames[..1]);
+    assert!(matches!(results[0], Err(NetworkError::Corrupt(0))));
+}
+
+#[test]
+fn auth_rejects_replayed_and_reordered_messages() {
+    let frames = auth_frames(b"secret");
+    let replayed = [frames[0].clone(), frames[0].clone(), frames[1].clone()];
+    let results = auth_deliver(b"secret", &replayed);
+    assert!(results[0].is_ok());
+    let Err(NetworkError::Other(err)) = &results[1] else {
+        panic!("replay was not rejected: {:?}", results[1]);
+    };
+    assert!(err.to_string().contains("out of order"), "{err}");
+    // the replay did not advance the expected sequence number
+    assert_eq!(results[2].as_ref().unwrap(), b"second");
+
+    let reordered = [frames[1].clone()];
+    let results = auth_deliver(b"secret", &reordered);
+    assert!(matches!(results[0], Err(NetworkError::Other(_))));
+}
+
+#[test]
+fn auth_rejects_a_wrong_key() {
+    let frames = auth_frames(b"secret");
+    let results = auth_deliver(b"another secret", &frames[..1]);
+    assert!(matches!(results[0], Err(NetworkError::Corrupt(0))));
+}
+
+/// Records a transcript of party 0 talking to the parties 1 and 2 and returns its path.
+fn record_transcript(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("mpc_engine_{}_{name}", std::process::id()));
+    let [n0, n1, n2]: [TestNetwork; 3] = TestNetwork::party_networks(3).try_into().unwrap();
+    let net = TranscriptNetwork::new(n0, &path).unwrap();
+    n2.send(0, b"x").unwrap();
+    n1.send(0, b"y").unwrap();
+    net.send(1, b"a").unwrap();
+    assert_eq!(net.recv(2).unwrap(), b"x");
+    net.send(2, b"b").unwrap();
+    assert_eq!(net.recv(1).unwrap(), b"y");
+    // dropping the transcript writes the buffered entries
+    drop(net);
+    path
+}
+
+fn message(res: Result<Vec<u8>, NetworkError>) -> String {
+    match res {
+        Err(NetworkError::Other(err)) => err.to_string(),
+        res => panic!("expected a replay error, got {res:?}"),
+    }
+}
+
+#[test]
+fn replay_returns_the_recorded_messages() {
+    let path = record_transcript("replay");
+    let replay = ReplayNetwork::open(&path).unwrap();
+    assert_eq!((replay.id(), replay.num_parties()), (0, 3));
+    assert_eq!(replay.remaining(), 4);
+    // only the order per peer and direction is enforced
+    assert_eq!(replay.recv(1).unwrap(), b"y");
+    replay.send(2, b"b").unwrap();
+    replay.send(1, b"a").unwrap();
+    assert_eq!(replay.recv(2).unwrap(), b"x");
+    assert_eq!(replay.remaining(), 0);
+    assert!(message(replay.recv(1)).contains("no further message from party 1"));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn strict_replay_detects_divergence() {
+    let path = record_transcript("strict");
+    let replay = ReplayNetwork::open(&path).unwrap().strict();
+    assert!(message(replay.recv(1)).contains("expected entry 0"));
+    replay.send(1, b"a").unwrap();
+    assert_eq!(replay.recv(2).unwrap(), b"x");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn replay_detects_a_different_message() {
+    let path = record_transcript("different");
+    let replay = ReplayNetwork::open(&path).unwrap();
+    let err = message(replay.send(1, b"z").map(|()| Vec::new()));
+    assert!(err.contains("differs from entry 0"), "{err}");
+    std::fs::remove_file(path).unwrap();
+}